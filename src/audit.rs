@@ -0,0 +1,197 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use conerror::conerror;
+use log::error;
+use rustic_jsonrpc::{BoxError, Request};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{FromRow, QueryBuilder, Sqlite, SqlitePool};
+
+use crate::client::ClientInfo;
+use crate::user::User;
+use crate::util::timestamp;
+
+pub const TARGET_PASSWORD: &str = "password";
+pub const TARGET_FILE: &str = "file";
+pub const TARGET_SHARED_ENTRY: &str = "shared_entry";
+pub const TARGET_EMERGENCY_CONTACT: &str = "emergency_contact";
+
+const MAX_AUDIT_LIST: i64 = 1000;
+
+tokio::task_local! {
+    static CONTEXT: AuditContext;
+}
+
+/// The RPC call being handled, calls of a batch are handled one at a time.
+struct AuditContext {
+    manager: AuditManager,
+    user_id: Mutex<Option<i64>>,
+    affected_user_id: Mutex<Option<i64>>,
+    target: Mutex<Option<(&'static str, i64)>>,
+}
+
+/// A request to record, `record` builds one for each RPC call.
+pub struct Event<'a> {
+    pub user_id: Option<i64>,
+    /// Owner of the data the request accessed, when it isn't the user who made it.
+    pub affected_user_id: Option<i64>,
+    pub method: &'a str,
+    /// Type and id of what the request acted on.
+    pub target: Option<(&'static str, i64)>,
+    pub success: bool,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct AuditEvent {
+    id: i64,
+    user_id: Option<i64>,
+    username: Option<String>,
+    affected_user_id: Option<i64>,
+    affected_username: Option<String>,
+    method: String,
+    /// Type of what the method acted on, e.g. `password`.
+    target_type: Option<String>,
+    target_id: Option<i64>,
+    ip: Option<String>,
+    success: bool,
+    created_at: i64,
+}
+
+/// Records every RPC call with the user who made it, see `record`, and requests to
+/// other routes that access a vault, see `record_request`.
+#[derive(Clone)]
+pub struct AuditManager {
+    db: SqlitePool,
+}
+
+impl AuditManager {
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Runs `f`, which handles an RPC call, with what it reports available to `record`.
+    pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+        let context = AuditContext {
+            manager: self.clone(),
+            user_id: Mutex::new(None),
+            affected_user_id: Mutex::new(None),
+            target: Mutex::new(None),
+        };
+        CONTEXT.scope(context, f).await
+    }
+
+    /// Events of `user`, including those affecting them, or of all users if `None`,
+    /// created in `[from, to)`, newest first.
+    #[conerror]
+    pub async fn list(
+        &self,
+        user: Option<&User>,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> conerror::Result<Vec<AuditEvent>> {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "SELECT `audit_log`.`id`, `user_id`, `user`.`username`, `affected_user_id`, \
+            `affected`.`username` AS `affected_username`, `method`, `target_type`, `target_id`, \
+            `ip`, `success`, `audit_log`.`created_at` FROM `audit_log` \
+            LEFT JOIN `user` ON `user`.`id` = `audit_log`.`user_id` \
+            LEFT JOIN `user` AS `affected` ON `affected`.`id` = `audit_log`.`affected_user_id` \
+            WHERE 1",
+        );
+        if let Some(user) = user {
+            query
+                .push(" AND (`user_id` = ")
+                .push_bind(user.id())
+                .push(" OR `affected_user_id` = ")
+                .push_bind(user.id())
+                .push(")");
+        }
+        if let Some(from) = from {
+            query
+                .push(" AND `audit_log`.`created_at` >= ")
+                .push_bind(from);
+        }
+        if let Some(to) = to {
+            query.push(" AND `audit_log`.`created_at` < ").push_bind(to);
+        }
+        query
+            .push(" ORDER BY `audit_log`.`id` DESC LIMIT ")
+            .push_bind(MAX_AUDIT_LIST);
+        let list = query.build_query_as().fetch_all(&self.db).await?;
+        Ok(list)
+    }
+
+    /// Deletes events created before `created_before`, returns the number deleted.
+    #[conerror]
+    pub async fn purge(&self, created_before: i64) -> conerror::Result<u64> {
+        let result = sqlx::query("DELETE FROM `audit_log` WHERE `created_at` < ?")
+            .bind(created_before)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Records a request made by the current client.
+    pub async fn record_request(&self, event: Event<'_>) {
+        if let Err(err) = self.insert(event).await {
+            error!("error write audit log: {}", err);
+        }
+    }
+
+    #[conerror]
+    async fn insert(&self, event: Event<'_>) -> conerror::Result<()> {
+        insert!("audit_log", {
+            "user_id": event.user_id,
+            "affected_user_id": event.affected_user_id,
+            "method": event.method,
+            "target_type": event.target.map(|v| v.0),
+            "target_id": event.target.map(|v| v.1),
+            "ip": ClientInfo::current().ip,
+            "success": event.success,
+            "created_at": timestamp(),
+        })
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Sets the user the call being handled acts as, called once the user is known,
+/// e.g. when a token is checked.
+pub fn set_user(id: i64) {
+    let _ = CONTEXT.try_with(|v| *v.user_id.lock().unwrap() = Some(id));
+}
+
+/// Sets the owner of the data the call being handled accesses, when it isn't the
+/// caller, e.g. the grantor of emergency access.
+pub fn set_affected_user(id: i64) {
+    let _ = CONTEXT.try_with(|v| *v.affected_user_id.lock().unwrap() = Some(id));
+}
+
+/// Sets the type and id of what the call being handled acts on.
+pub fn set_target(target_type: &'static str, id: i64) {
+    let _ = CONTEXT.try_with(|v| *v.target.lock().unwrap() = Some((target_type, id)));
+}
+
+/// Records a call after it returned, registered with `Registry::post_call`.
+pub fn record<'a>(
+    req: &'a Request<'a>,
+    result: &'a Result<Value, BoxError>,
+) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+    let context = CONTEXT.try_with(|v| {
+        let event = Event {
+            user_id: *v.user_id.lock().unwrap(),
+            affected_user_id: *v.affected_user_id.lock().unwrap(),
+            method: req.method,
+            target: *v.target.lock().unwrap(),
+            success: result.is_ok(),
+        };
+        (v.manager.clone(), event)
+    });
+    let (manager, event) = match context {
+        Ok(v) => v,
+        Err(_) => return Box::pin(async {}),
+    };
+    Box::pin(async move { manager.record_request(event).await })
+}
@@ -0,0 +1,141 @@
+use std::fs::{read, read_to_string, write};
+use std::io::{stderr, stdin, Write};
+
+use conerror::conerror;
+use structopt::StructOpt;
+
+use crate::db::setup_db;
+use crate::encryption::{Aes256GcmEncryptor, EncryptionManager};
+use crate::importer::{self, ImportFormat};
+use crate::password::PasswordManager;
+use crate::ratelimit::LoginLimiter;
+use crate::user::{SecondFactor, User, UserManager};
+use crate::vault::{ConflictPolicy, VaultManager};
+use crate::webauthn::WebauthnManager;
+use crate::Opt;
+
+#[derive(StructOpt, Clone)]
+pub enum Command {
+    /// Export all entries of a user into an encrypted file
+    Export {
+        #[structopt(long)]
+        username: String,
+
+        #[structopt(long)]
+        output: String,
+    },
+    /// Export all entries of a user into a KeePass (KDBX 4) database
+    ExportKdbx {
+        #[structopt(long)]
+        username: String,
+
+        #[structopt(long)]
+        output: String,
+    },
+    /// Import entries from a file created by `export`
+    Import {
+        #[structopt(long)]
+        username: String,
+
+        #[structopt(long)]
+        input: String,
+
+        /// skip, overwrite or keep_both
+        #[structopt(long, default_value = "skip")]
+        conflict: ConflictPolicy,
+    },
+    /// Import entries exported from another password manager
+    ImportFrom {
+        #[structopt(long)]
+        username: String,
+
+        #[structopt(long)]
+        input: String,
+
+        /// bitwarden_json, keepass_csv, browser_csv or onepassword_1pux
+        #[structopt(long)]
+        format: ImportFormat,
+    },
+}
+
+#[conerror]
+pub async fn run(opt: &Opt, command: &Command) -> conerror::Result<()> {
+    let db = setup_db(&opt.data_dir).await?;
+    let encryption = EncryptionManager::new(vec![Box::new(Aes256GcmEncryptor)]);
+    // security keys need a browser, so only TOTP is available on the command line
+    let user_manager = UserManager::new(
+        db.clone(),
+        encryption.clone(),
+        WebauthnManager::new(db.clone(), None),
+        opt.token_lifetime(),
+        LoginLimiter::new(db.clone(), opt.login_limits()),
+    );
+    let password_manager = PasswordManager::new(db.clone(), encryption.clone());
+    let vault_manager = VaultManager::new(db, password_manager.clone(), encryption);
+
+    match command {
+        Command::Export { username, output } => {
+            let user = login(&user_manager, username).await?;
+            let passphrase = prompt("export passphrase: ")?;
+            let data = vault_manager.export(&user, &passphrase).await?;
+            write(output, data)?;
+        }
+        Command::ExportKdbx { username, output } => {
+            let user = login(&user_manager, username).await?;
+            let password = prompt("database password: ")?;
+            let data = vault_manager.export_kdbx(&user, &password).await?;
+            write(output, data)?;
+        }
+        Command::Import {
+            username,
+            input,
+            conflict,
+        } => {
+            let user = login(&user_manager, username).await?;
+            let data = read_to_string(input)?;
+            let passphrase = prompt("export passphrase: ")?;
+            let result = vault_manager
+                .import(&user, &passphrase, &data, *conflict)
+                .await?;
+            println!(
+                "created: {}, updated: {}, skipped: {}",
+                result.created, result.updated, result.skipped
+            );
+        }
+        Command::ImportFrom {
+            username,
+            input,
+            format,
+        } => {
+            let user = login(&user_manager, username).await?;
+            let data = read(input)?;
+            let report = importer::import(&password_manager, &user, *format, &data).await?;
+            println!("imported: {}", report.imported);
+            for v in report.skipped {
+                println!("skipped record {}: {}", v.record, v.reason);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[conerror]
+async fn login(user_manager: &UserManager, username: &str) -> conerror::Result<User> {
+    let password = prompt("password: ")?;
+    let code = match user_manager.two_factor_enabled(username).await? {
+        true => Some(prompt("code: ")?),
+        false => None,
+    };
+    Ok(user_manager
+        .login(username, &password, code.as_deref().map(SecondFactor::Code))
+        .await?)
+}
+
+#[conerror]
+fn prompt(message: &str) -> conerror::Result<String> {
+    eprint!("{}", message);
+    stderr().flush()?;
+    let mut line = String::new();
+    stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
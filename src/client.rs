@@ -0,0 +1,109 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::header::USER_AGENT;
+use hyper::Request;
+
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+tokio::task_local! {
+    static CLIENT: ClientInfo;
+}
+
+/// The client that sent the request being handled.
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// Reads the client from a request, the peer address is put in the
+    /// extensions of the request when the connection is accepted. If the peer is one
+    /// of `trusted_proxies`, the client is the last address in `X-Forwarded-For` that
+    /// isn't a trusted proxy, the ones before it could be made up by the client.
+    pub fn from_request<B>(req: &Request<B>, trusted_proxies: &[IpAddr]) -> Self {
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        let mut ip = req.extensions().get::<SocketAddr>().map(|v| v.ip());
+        if matches!(ip, Some(v) if trusted_proxies.contains(&v)) {
+            let forwarded = req
+                .headers()
+                .get_all(X_FORWARDED_FOR)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .collect::<Vec<_>>();
+            for addr in forwarded.into_iter().rev() {
+                match addr.trim().parse() {
+                    Ok(addr) => {
+                        ip = Some(addr);
+                        if !trusted_proxies.contains(&addr) {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+        Self {
+            ip: ip.map(|v| v.to_string()),
+            user_agent,
+        }
+    }
+
+    /// Runs `f` with `self` as the current client.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CLIENT.scope(self, f).await
+    }
+
+    /// The client of the request being handled, empty outside of a request,
+    /// e.g. on the command line.
+    pub fn current() -> Self {
+        CLIENT.try_with(Clone::clone).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_ip(peer: &str, forwarded: &[&str]) -> Option<String> {
+        let mut req = Request::builder();
+        for v in forwarded {
+            req = req.header(X_FORWARDED_FOR, *v);
+        }
+        let mut req = req.body(()).unwrap();
+        req.extensions_mut()
+            .insert(SocketAddr::new(peer.parse().unwrap(), 1234));
+        let proxies = ["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        ClientInfo::from_request(&req, &proxies).ip
+    }
+
+    #[test]
+    fn test_from_request() {
+        // not from a proxy, the header is ignored
+        assert_eq!(
+            Some("1.2.3.4"),
+            client_ip("1.2.3.4", &["5.6.7.8"]).as_deref()
+        );
+        assert_eq!(Some("10.0.0.1"), client_ip("10.0.0.1", &[]).as_deref());
+        assert_eq!(
+            Some("5.6.7.8"),
+            client_ip("10.0.0.1", &["9.9.9.9, 5.6.7.8"]).as_deref()
+        );
+        assert_eq!(
+            Some("5.6.7.8"),
+            client_ip("10.0.0.1", &["9.9.9.9", "5.6.7.8, 10.0.0.2"]).as_deref()
+        );
+        assert_eq!(
+            Some("10.0.0.2"),
+            client_ip("10.0.0.1", &["5.6.7.8, x, 10.0.0.2"]).as_deref()
+        );
+    }
+}
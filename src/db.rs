@@ -11,12 +11,49 @@ CREATE TABLE IF NOT EXISTS user (
     salt BLOB NOT NULL,
     credential BLOB NOT NULL,
     suspend INTEGER NOT NULL DEFAULT 0,
+    history_retention INTEGER NOT NULL DEFAULT 10,
+    public_key BLOB,
+    private_key BLOB,
+    recovery_salt BLOB,
+    recovery_credential BLOB,
+    totp_secret BLOB,
+    totp_enabled INTEGER NOT NULL DEFAULT 0,
+    totp_last INTEGER,
+    token_idle_timeout INTEGER,
+    token_max_age INTEGER,
+    credential_version INTEGER NOT NULL DEFAULT 0,
     created_at INTEGER NOT NULL
 );
+CREATE TABLE IF NOT EXISTS user_backup_code (
+    user_id INTEGER NOT NULL,
+    hash BLOB NOT NULL,
+    used_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS index_user_backup_code_user_id ON user_backup_code(user_id);
+CREATE TABLE IF NOT EXISTS webauthn_credential (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    credential_id BLOB NOT NULL UNIQUE,
+    public_key BLOB NOT NULL,
+    sign_count INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS index_webauthn_credential_user_id ON webauthn_credential(user_id);
+CREATE TABLE IF NOT EXISTS webauthn_challenge (
+    user_id INTEGER NOT NULL,
+    challenge BLOB NOT NULL,
+    type TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_webauthn_challenge_user_id ON webauthn_challenge(user_id);
 CREATE TABLE IF NOT EXISTS token (
     id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER NOT NULL,
     credential BLOB NOT NULL,
+    ip TEXT,
+    user_agent TEXT,
     last_active INTEGER NOT NULL,
     created_at INTEGER NOT NULL
 );
@@ -25,15 +62,214 @@ CREATE TABLE IF NOT EXISTS password (
     id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER NOT NULL,
     name TEXT NOT NULL,
+    type TEXT NOT NULL DEFAULT 'login',
     username BLOB NOT NULL,
     password BLOB NOT NULL,
     attachment BLOB,
+    totp BLOB,
+    payload BLOB,
     updated_at INTEGER NOT NULL,
-    created_at INTEGER NOT NULL
+    created_at INTEGER NOT NULL,
+    deleted_at INTEGER,
+    folder_id INTEGER
 );
 CREATE INDEX IF NOT EXISTS index_password_user_id ON password(user_id);
+CREATE TABLE IF NOT EXISTS password_history (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    password_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    type TEXT NOT NULL DEFAULT 'login',
+    username BLOB NOT NULL,
+    password BLOB NOT NULL,
+    attachment BLOB,
+    totp BLOB,
+    payload BLOB,
+    updated_at INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_password_history_password_id ON password_history(password_id);
+CREATE TABLE IF NOT EXISTS password_field (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    password_id INTEGER NOT NULL,
+    history_id INTEGER,
+    user_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    name BLOB NOT NULL,
+    value BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_password_field_password_id ON password_field(password_id);
+CREATE TABLE IF NOT EXISTS password_url (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    password_id INTEGER NOT NULL,
+    history_id INTEGER,
+    user_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    url BLOB NOT NULL,
+    domain_hash BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_password_url_password_id ON password_url(password_id);
+CREATE INDEX IF NOT EXISTS index_password_url_domain_hash ON password_url(user_id, domain_hash);
+CREATE TABLE IF NOT EXISTS password_file (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    password_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    name BLOB NOT NULL,
+    size INTEGER NOT NULL,
+    chunks INTEGER NOT NULL,
+    blob TEXT NOT NULL UNIQUE,
+    salt BLOB NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_password_file_password_id ON password_file(password_id);
+CREATE TABLE IF NOT EXISTS folder (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    parent_id INTEGER,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_folder_user_id ON folder(user_id);
+CREATE TABLE IF NOT EXISTS tag (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    UNIQUE(user_id, name)
+);
+CREATE TABLE IF NOT EXISTS password_tag (
+    password_id INTEGER NOT NULL,
+    tag_id INTEGER NOT NULL,
+    PRIMARY KEY(password_id, tag_id)
+);
+CREATE INDEX IF NOT EXISTS index_password_tag_tag_id ON password_tag(tag_id);
+CREATE TABLE IF NOT EXISTS password_index (
+    password_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    field TEXT NOT NULL,
+    hash BLOB NOT NULL,
+    PRIMARY KEY (password_id, field)
+);
+CREATE INDEX IF NOT EXISTS index_password_index_hash ON password_index(user_id, hash);
+CREATE TABLE IF NOT EXISTS shared_vault (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name BLOB NOT NULL,
+    key_version INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS shared_vault_member (
+    vault_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    key BLOB,
+    key_version INTEGER NOT NULL,
+    invite BLOB,
+    sealed_key BLOB,
+    ephemeral_key BLOB,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (vault_id, user_id)
+);
+CREATE INDEX IF NOT EXISTS index_shared_vault_member_user_id ON shared_vault_member(user_id);
+CREATE TABLE IF NOT EXISTS shared_vault_entry (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    vault_id INTEGER NOT NULL,
+    name BLOB NOT NULL,
+    username BLOB NOT NULL,
+    password BLOB NOT NULL,
+    attachment BLOB,
+    created_by INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_shared_vault_entry_vault_id ON shared_vault_entry(vault_id);
+CREATE TABLE IF NOT EXISTS password_share (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    sender_id INTEGER NOT NULL,
+    recipient_id INTEGER NOT NULL,
+    ephemeral_key BLOB NOT NULL,
+    data BLOB NOT NULL,
+    accepted_at INTEGER,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_password_share_sender_id ON password_share(sender_id);
+CREATE INDEX IF NOT EXISTS index_password_share_recipient_id ON password_share(recipient_id);
+CREATE TABLE IF NOT EXISTS share_link (
+    id TEXT NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    password_id INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    views_left INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_share_link_password_id ON share_link(password_id);
+CREATE TABLE IF NOT EXISTS emergency_contact (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    grantor_id INTEGER NOT NULL,
+    grantee_id INTEGER NOT NULL,
+    wait_days INTEGER NOT NULL,
+    ephemeral_key BLOB NOT NULL,
+    credential BLOB NOT NULL,
+    status TEXT NOT NULL,
+    requested_at INTEGER,
+    granted_at INTEGER,
+    created_at INTEGER NOT NULL,
+    UNIQUE (grantor_id, grantee_id)
+);
+CREATE INDEX IF NOT EXISTS index_emergency_contact_grantee_id ON emergency_contact(grantee_id);
+CREATE TABLE IF NOT EXISTS login_lockout (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    key TEXT NOT NULL,
+    ip TEXT,
+    locked_until INTEGER NOT NULL,
+    unlocked_by INTEGER,
+    unlocked_at INTEGER,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_login_lockout_key ON login_lockout(key);
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER,
+    affected_user_id INTEGER,
+    method TEXT NOT NULL,
+    target_type TEXT,
+    target_id INTEGER,
+    ip TEXT,
+    success INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_audit_log_user_id ON audit_log(user_id);
+CREATE INDEX IF NOT EXISTS index_audit_log_created_at ON audit_log(created_at);
 "#;
 
+/// Columns added after the tables were first created, applied to existing databases on startup.
+const COLUMNS: &[(&str, &str, &str)] = &[
+    ("password", "totp", "BLOB"),
+    ("user", "history_retention", "INTEGER NOT NULL DEFAULT 10"),
+    ("password", "deleted_at", "INTEGER"),
+    ("password", "folder_id", "INTEGER"),
+    ("password", "type", "TEXT NOT NULL DEFAULT 'login'"),
+    ("password", "payload", "BLOB"),
+    ("password_history", "type", "TEXT NOT NULL DEFAULT 'login'"),
+    ("password_history", "payload", "BLOB"),
+    ("user", "public_key", "BLOB"),
+    ("user", "private_key", "BLOB"),
+    ("user", "recovery_salt", "BLOB"),
+    ("user", "recovery_credential", "BLOB"),
+    ("user", "totp_secret", "BLOB"),
+    ("user", "totp_enabled", "INTEGER NOT NULL DEFAULT 0"),
+    ("user", "totp_last", "INTEGER"),
+    ("user", "token_idle_timeout", "INTEGER"),
+    ("user", "token_max_age", "INTEGER"),
+    ("token", "ip", "TEXT"),
+    ("token", "user_agent", "TEXT"),
+    ("user", "credential_version", "INTEGER NOT NULL DEFAULT 0"),
+    ("audit_log", "affected_user_id", "INTEGER"),
+    ("audit_log", "target_type", "TEXT"),
+];
+
 #[conerror]
 pub async fn setup_db(data_dir: &str) -> conerror::Result<SqlitePool> {
     let path = Path::new(data_dir);
@@ -44,5 +280,31 @@ pub async fn setup_db(data_dir: &str) -> conerror::Result<SqlitePool> {
     path.push("database");
     let db = SqlitePool::connect(&format!("sqlite://{}?mode=rwc", path.to_str().unwrap())).await?;
     sqlx::query(DDL).execute(&db).await?;
+    for (table, column, definition) in COLUMNS {
+        add_column(&db, table, column, definition).await?;
+    }
     Ok(db)
 }
+
+#[conerror]
+async fn add_column(
+    db: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> conerror::Result<()> {
+    let (count,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(db)
+            .await?;
+    if count == 0 {
+        let sql = format!(
+            "ALTER TABLE `{}` ADD COLUMN `{}` {}",
+            table, column, definition
+        );
+        sqlx::query(&sql).execute(db).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,308 @@
+use std::str::FromStr;
+
+use conerror::{conerror, Error};
+use serde::Serialize;
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
+use x25519_dalek::PublicKey;
+
+use crate::audit;
+use crate::encryption::EncryptionManager;
+use crate::error::msg;
+use crate::password::{Password, PasswordManager};
+use crate::user::{lock_credential, User, UserManager};
+use crate::util::timestamp;
+
+const MAX_WAIT_DAYS: i64 = 90;
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmergencyStatus {
+    /// The contact hasn't asked for access.
+    Idle,
+    /// Access was requested and is granted once the waiting period has passed.
+    Requested,
+    Granted,
+}
+
+impl EmergencyStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EmergencyStatus::Idle => "idle",
+            EmergencyStatus::Requested => "requested",
+            EmergencyStatus::Granted => "granted",
+        }
+    }
+}
+
+impl FromStr for EmergencyStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(Self::Idle),
+            "requested" => Ok(Self::Requested),
+            "granted" => Ok(Self::Granted),
+            _ => Err(format!("unknown emergency status: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EmergencyContact {
+    id: i64,
+    grantor: String,
+    grantee: String,
+    wait_days: i64,
+    status: EmergencyStatus,
+    requested_at: Option<i64>,
+    granted_at: Option<i64>,
+}
+
+#[derive(FromRow)]
+struct EmergencyContactRow {
+    id: i64,
+    grantor: String,
+    grantee: String,
+    wait_days: i64,
+    status: String,
+    requested_at: Option<i64>,
+    granted_at: Option<i64>,
+}
+
+/// Lets a user name contacts who can read their vault when they are unavailable.
+///
+/// The user's credential is wrapped to the contact's public key when the contact is added.
+/// The contact can request access, which is granted by `grant_due_requests` after the
+/// waiting period unless the user rejects the request first.
+#[derive(Clone)]
+pub struct EmergencyManager {
+    db: SqlitePool,
+    encryption: EncryptionManager,
+    user_manager: UserManager,
+    password_manager: PasswordManager,
+}
+
+impl EmergencyManager {
+    pub fn new(
+        db: SqlitePool,
+        encryption: EncryptionManager,
+        user_manager: UserManager,
+        password_manager: PasswordManager,
+    ) -> Self {
+        Self {
+            db,
+            encryption,
+            user_manager,
+            password_manager,
+        }
+    }
+
+    #[conerror]
+    pub async fn add_contact(
+        &self,
+        user: &User,
+        username: &str,
+        wait_days: i64,
+    ) -> conerror::Result<i64> {
+        if !(1..=MAX_WAIT_DAYS).contains(&wait_days) {
+            return Err(msg("参数错误"));
+        }
+        let (grantee_id, public_key) = match self.user_manager.public_key(username).await? {
+            Some(v) => v,
+            None => return Err(msg("用户不存在")),
+        };
+        if grantee_id == user.id() {
+            return Err(msg("参数错误"));
+        }
+        let (ephemeral_key, credential) = self
+            .encryption
+            .seal(user.credential().as_bytes(), &public_key)?;
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
+        let result = insert_ignore!("emergency_contact", {
+            "grantor_id": user.id(),
+            "grantee_id": grantee_id,
+            "wait_days": wait_days,
+            "ephemeral_key": ephemeral_key.as_slice(),
+            "credential": &credential,
+            "status": EmergencyStatus::Idle.as_str(),
+            "created_at": timestamp(),
+        })
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("已是紧急联系人"));
+        }
+        tx.commit().await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Contacts the user added and users who added the user as a contact.
+    #[conerror]
+    pub async fn list_contact(&self, user: &User) -> conerror::Result<Vec<EmergencyContact>> {
+        let rows: Vec<EmergencyContactRow> = sqlx::query_as(
+            "SELECT `emergency_contact`.`id`, `grantor`.`username` AS `grantor`, `grantee`.`username` AS `grantee`, \
+            `emergency_contact`.`wait_days`, `emergency_contact`.`status`, `emergency_contact`.`requested_at`, \
+            `emergency_contact`.`granted_at` FROM `emergency_contact` \
+            JOIN `user` AS `grantor` ON `grantor`.`id` = `emergency_contact`.`grantor_id` \
+            JOIN `user` AS `grantee` ON `grantee`.`id` = `emergency_contact`.`grantee_id` \
+            WHERE `emergency_contact`.`grantor_id` = ? OR `emergency_contact`.`grantee_id` = ? \
+            ORDER BY `emergency_contact`.`id`",
+        )
+        .bind(user.id())
+        .bind(user.id())
+        .fetch_all(&self.db)
+        .await?;
+        let mut list = Vec::with_capacity(rows.len());
+        for row in rows {
+            list.push(EmergencyContact {
+                id: row.id,
+                grantor: row.grantor,
+                grantee: row.grantee,
+                wait_days: row.wait_days,
+                status: EmergencyStatus::from_str(&row.status).map_err(Error::plain)?,
+                requested_at: row.requested_at,
+                granted_at: row.granted_at,
+            });
+        }
+        Ok(list)
+    }
+
+    /// Removes a contact, either side can remove it.
+    #[conerror]
+    pub async fn remove_contact(&self, user: &User, id: i64) -> conerror::Result<()> {
+        sqlx::query(
+            "DELETE FROM `emergency_contact` WHERE `id` = ? AND (`grantor_id` = ? OR `grantee_id` = ?)",
+        )
+        .bind(id)
+        .bind(user.id())
+        .bind(user.id())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn request_access(&self, user: &User, id: i64) -> conerror::Result<()> {
+        let result = update!(
+            "emergency_contact",
+            {"status": EmergencyStatus::Requested.as_str(), "requested_at": timestamp()},
+            {"id" = id, "grantee_id" = user.id(), "status" = EmergencyStatus::Idle.as_str()}
+        )
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("紧急联系人不存在"));
+        }
+        Ok(())
+    }
+
+    /// Grants a pending request without waiting.
+    #[conerror]
+    pub async fn approve_access(&self, user: &User, id: i64) -> conerror::Result<()> {
+        let result = update!(
+            "emergency_contact",
+            {"status": EmergencyStatus::Granted.as_str(), "granted_at": timestamp()},
+            {"id" = id, "grantor_id" = user.id(), "status" = EmergencyStatus::Requested.as_str()}
+        )
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("紧急联系人不存在"));
+        }
+        Ok(())
+    }
+
+    /// Rejects a pending request, or withdraws access that was already granted.
+    #[conerror]
+    pub async fn reject_access(&self, user: &User, id: i64) -> conerror::Result<()> {
+        let result = update!(
+            "emergency_contact",
+            {
+                "status": EmergencyStatus::Idle.as_str(),
+                "requested_at": None::<i64>,
+                "granted_at": None::<i64>,
+            },
+            {"id" = id, "grantor_id" = user.id()}
+        )
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("紧急联系人不存在"));
+        }
+        Ok(())
+    }
+
+    /// Grants the requests whose waiting period has passed.
+    #[conerror]
+    pub async fn grant_due_requests(&self) -> conerror::Result<u64> {
+        let now = timestamp();
+        let result = sqlx::query(
+            "UPDATE `emergency_contact` SET `status` = ?, `granted_at` = ? \
+            WHERE `status` = ? AND `requested_at` + `wait_days` * 86400 <= ?",
+        )
+        .bind(EmergencyStatus::Granted.as_str())
+        .bind(now)
+        .bind(EmergencyStatus::Requested.as_str())
+        .bind(now)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Seals the new credential of a user who rotated it to each of their contacts.
+    #[conerror]
+    pub async fn rekey(&self, tx: &mut Transaction<'_, Sqlite>, to: &User) -> conerror::Result<()> {
+        let rows: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+            "SELECT `emergency_contact`.`id`, `user`.`public_key` FROM `emergency_contact` \
+            JOIN `user` ON `user`.`id` = `emergency_contact`.`grantee_id` \
+            WHERE `emergency_contact`.`grantor_id` = ? AND `user`.`public_key` IS NOT NULL",
+        )
+        .bind(to.id())
+        .fetch_all(&mut **tx)
+        .await?;
+        for (id, public_key) in rows {
+            let public_key = PublicKey::from(<[u8; 32]>::try_from(public_key.as_slice())?);
+            let (ephemeral_key, credential) = self
+                .encryption
+                .seal(to.credential().as_bytes(), &public_key)?;
+            update!(
+                "emergency_contact",
+                {"ephemeral_key": ephemeral_key.as_slice(), "credential": &credential},
+                {"id" = id}
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the entries of the user who granted access.
+    #[conerror]
+    pub async fn view_vault(&self, user: &User, id: i64) -> conerror::Result<Vec<Password>> {
+        let row: Option<(i64, i64, Vec<u8>, Vec<u8>)> = sqlx::query_as(
+            "SELECT `emergency_contact`.`grantor_id`, `user`.`credential_version`, \
+            `emergency_contact`.`ephemeral_key`, `emergency_contact`.`credential` FROM `emergency_contact` \
+            JOIN `user` ON `user`.`id` = `emergency_contact`.`grantor_id` \
+            WHERE `emergency_contact`.`id` = ? AND `emergency_contact`.`grantee_id` = ? \
+            AND `emergency_contact`.`status` = ?",
+        )
+        .bind(id)
+        .bind(user.id())
+        .bind(EmergencyStatus::Granted.as_str())
+        .fetch_optional(&self.db)
+        .await?;
+        let (grantor_id, credential_version, ephemeral_key, credential) = match row {
+            Some(v) => v,
+            None => return Err(msg("无权限")),
+        };
+        audit::set_affected_user(grantor_id);
+        let private_key = self.user_manager.private_key(user).await?;
+        let credential = self
+            .encryption
+            .open(&credential, &ephemeral_key, &private_key)?;
+        let grantor = User::with_credential(grantor_id, credential_version, credential);
+        let list = self.password_manager.all_password(&grantor).await?;
+        Ok(list)
+    }
+}
@@ -6,6 +6,9 @@ use aes_gcm::aes::cipher::InvalidLength;
 use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce};
 use argon2::Argon2;
 use conerror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::util::fill_bytes;
 
 #[derive(Clone)]
 pub struct EncryptionManager {
@@ -60,6 +63,56 @@ impl EncryptionManager {
         }
     }
 
+    /// Derives the key of `encrypt_chunk` and `decrypt_chunk`, so data encrypted in many
+    /// parts goes through the key derivation only once.
+    pub fn derive_chunk_key(&self, password: &[u8], salt: &[u8]) -> conerror::Result<Vec<u8>> {
+        self.derive_key(password, salt, Aes256GcmEncryptor::KEY_SIZE)
+    }
+
+    /// Encrypts part `index` of some data with a key from `derive_chunk_key`. The index is
+    /// the nonce, so parts can't be reordered, and a key must not encrypt an index twice.
+    pub fn encrypt_chunk(&self, data: &[u8], key: &[u8], index: u64) -> conerror::Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(Error::plain)?;
+        cipher
+            .encrypt(&chunk_nonce(index), data)
+            .map_err(Error::plain)
+    }
+
+    pub fn decrypt_chunk(&self, data: &[u8], key: &[u8], index: u64) -> conerror::Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(Error::plain)?;
+        cipher
+            .decrypt(&chunk_nonce(index), data)
+            .map_err(Error::plain)
+    }
+
+    /// Encrypts `data` for the owner of `public_key` with a key agreed with a fresh X25519 key,
+    /// returns the public part of the fresh key and the ciphertext.
+    pub fn seal(
+        &self,
+        data: &[u8],
+        public_key: &PublicKey,
+    ) -> conerror::Result<([u8; 32], Vec<u8>)> {
+        let mut secret = [0u8; 32];
+        fill_bytes(&mut secret);
+        let secret = StaticSecret::from(secret);
+        let ephemeral_key = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(public_key);
+        let data = self.encrypt(data, shared.as_bytes(), ephemeral_key.as_bytes())?;
+        Ok((ephemeral_key.to_bytes(), data))
+    }
+
+    /// Decrypts data encrypted by `seal`.
+    pub fn open(
+        &self,
+        data: &[u8],
+        ephemeral_key: &[u8],
+        private_key: &StaticSecret,
+    ) -> conerror::Result<Vec<u8>> {
+        let ephemeral_key: [u8; 32] = ephemeral_key.try_into().map_err(Error::plain)?;
+        let shared = private_key.diffie_hellman(&PublicKey::from(ephemeral_key));
+        self.decrypt(data, shared.as_bytes(), &ephemeral_key)
+    }
+
     fn find_encryptor(&self, data: &[u8]) -> Option<&(dyn Encryptor + Send + Sync)> {
         if data.len() < ENCRYPTOR_ID_SIZE {
             return None;
@@ -73,6 +126,12 @@ impl EncryptionManager {
 
 const ENCRYPTOR_ID_SIZE: usize = 4;
 
+fn chunk_nonce(index: u64) -> Nonce<<Aes256Gcm as AeadCore>::NonceSize> {
+    let mut nonce = Nonce::default();
+    nonce[..8].copy_from_slice(&index.to_le_bytes());
+    nonce
+}
+
 pub trait Encryptor {
     fn id(&self) -> u32;
 
@@ -149,4 +208,16 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn test_encrypt_chunk() {
+        let manager = EncryptionManager::new(vec![Box::new(Aes256GcmEncryptor)]);
+        let key = manager.derive_chunk_key(b"12345678", b"87654321").unwrap();
+        let ciphertext = manager.encrypt_chunk(b"foobar", &key, 1).unwrap();
+        assert_eq!(
+            b"foobar".as_slice(),
+            manager.decrypt_chunk(&ciphertext, &key, 1).unwrap()
+        );
+        assert!(manager.decrypt_chunk(&ciphertext, &key, 0).is_err());
+    }
 }
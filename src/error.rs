@@ -4,6 +4,11 @@ pub fn invalid_token() -> conerror::Error {
     error(-2, "登录已过期")
 }
 
+/// Login needs a TOTP or backup code in addition to the password.
+pub fn two_factor_required() -> conerror::Error {
+    error(-3, "需要两步验证码")
+}
+
 pub fn msg(msg: impl ToString) -> conerror::Error {
     error(-1, msg)
 }
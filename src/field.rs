@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use conerror::conerror;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::error::msg;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    Text,
+    /// A secret value, e.g. a PIN or a security answer, masked by clients.
+    Hidden,
+    Url,
+    Email,
+    /// A calendar date in `YYYY-MM-DD` format.
+    Date,
+    Multiline,
+}
+
+impl FieldKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FieldKind::Text => "text",
+            FieldKind::Hidden => "hidden",
+            FieldKind::Url => "url",
+            FieldKind::Email => "email",
+            FieldKind::Date => "date",
+            FieldKind::Multiline => "multiline",
+        }
+    }
+}
+
+impl FromStr for FieldKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "hidden" => Ok(Self::Hidden),
+            "url" => Ok(Self::Url),
+            "email" => Ok(Self::Email),
+            "date" => Ok(Self::Date),
+            "multiline" => Ok(Self::Multiline),
+            _ => Err(format!("unknown field kind: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CustomField {
+    pub name: String,
+    pub kind: FieldKind,
+    pub value: String,
+}
+
+impl CustomField {
+    #[conerror]
+    pub fn validate(&self) -> conerror::Result<()> {
+        let valid = !self.name.trim().is_empty()
+            && match self.kind {
+                FieldKind::Text | FieldKind::Hidden => !self.value.contains('\n'),
+                FieldKind::Url => Url::parse(&self.value).is_ok(),
+                FieldKind::Email => match self.value.split_once('@') {
+                    Some((local, domain)) => !local.is_empty() && domain.contains('.'),
+                    None => false,
+                },
+                FieldKind::Date => is_date(&self.value),
+                FieldKind::Multiline => true,
+            };
+        if !valid {
+            return Err(msg("字段格式错误"));
+        }
+        Ok(())
+    }
+}
+
+pub fn is_date(s: &str) -> bool {
+    let parts: Vec<&str> = s.split('-').collect();
+    if parts.len() != 3
+        || parts[0].len() != 4
+        || parts[1].len() != 2
+        || parts[2].len() != 2
+        || !parts.iter().all(|v| v.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return false;
+    }
+    let year: u32 = parts[0].parse().unwrap_or(0);
+    let month: u32 = parts[1].parse().unwrap_or(0);
+    let day: u32 = parts[2].parse().unwrap_or(0);
+    let leap = (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days).contains(&day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::field::{is_date, CustomField, FieldKind};
+
+    #[test]
+    fn test_is_date() {
+        let cases = [
+            ("2024-01-31", true),
+            ("2024-02-29", true),
+            ("2000-02-29", true),
+            ("2023-02-29", false),
+            ("1900-02-29", false),
+            ("2023-02-28", true),
+            ("2023-04-30", true),
+            ("2023-04-31", false),
+            ("2023-13-01", false),
+            ("2023-00-10", false),
+            ("2023-01-00", false),
+            ("2023-1-01", false),
+            ("23-01-01", false),
+            ("2023/01/01", false),
+            ("2023-01-01T00:00", false),
+            ("", false),
+        ];
+        for (date, valid) in cases {
+            assert_eq!(valid, is_date(date), "{}", date);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let cases = [
+            ("PIN", FieldKind::Hidden, "1234", true),
+            ("PIN", FieldKind::Hidden, "12\n34", false),
+            ("Note", FieldKind::Text, "a\nb", false),
+            ("Note", FieldKind::Multiline, "a\nb", true),
+            ("Site", FieldKind::Url, "https://example.com", true),
+            ("Site", FieldKind::Url, "example.com", false),
+            ("Email", FieldKind::Email, "alice@example.com", true),
+            ("Email", FieldKind::Email, "@example.com", false),
+            ("Email", FieldKind::Email, "alice@localhost", false),
+            ("Birthday", FieldKind::Date, "2000-02-29", true),
+            ("Birthday", FieldKind::Date, "2001-02-29", false),
+            (" ", FieldKind::Text, "foo", false),
+        ];
+        for (name, kind, value, valid) in cases {
+            let field = CustomField {
+                name: name.to_string(),
+                kind,
+                value: value.to_string(),
+            };
+            assert_eq!(valid, field.validate().is_ok(), "{} {}", name, value);
+        }
+    }
+}
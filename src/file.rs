@@ -0,0 +1,517 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use conerror::{conerror, Error};
+use log::error;
+use serde::Serialize;
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::encryption::EncryptionManager;
+use crate::error::msg;
+use crate::user::User;
+use crate::util::{fill_bytes, timestamp};
+
+/// Size of the plaintext chunks a file is split into before encryption.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Unfinished uploads older than this are removed by `collect_garbage`.
+const PARTIAL_FILE_LIFETIME: Duration = Duration::from_secs(86400);
+
+#[derive(Serialize)]
+pub struct FileInfo {
+    id: i64,
+    name: String,
+    size: i64,
+    created_at: i64,
+}
+
+impl FileInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+}
+
+#[derive(FromRow)]
+struct FileRow {
+    id: i64,
+    name: Vec<u8>,
+    size: i64,
+    chunks: i64,
+    blob: String,
+    salt: Vec<u8>,
+    created_at: i64,
+}
+
+/// Stores files attached to entries under `<data_dir>/files`. Each file is split into
+/// chunks that are encrypted separately with a key derived from the user credential and
+/// the salt of the file, the nonce of a chunk is its index so chunks can't be reordered.
+#[derive(Clone)]
+pub struct FileManager {
+    db: SqlitePool,
+    encryption: EncryptionManager,
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl FileManager {
+    #[conerror]
+    pub async fn new(
+        db: SqlitePool,
+        encryption: EncryptionManager,
+        data_dir: &str,
+        max_size: u64,
+    ) -> conerror::Result<Self> {
+        let mut dir = PathBuf::from(data_dir);
+        dir.push("files");
+        fs::create_dir_all(&dir).await?;
+        Ok(Self {
+            db,
+            encryption,
+            dir,
+            max_size,
+        })
+    }
+
+    #[conerror]
+    pub async fn list_file(
+        &self,
+        user: &User,
+        password_id: i64,
+    ) -> conerror::Result<Vec<FileInfo>> {
+        let rows: Vec<FileRow> = select!(
+            "password_file",
+            ["id", "name", "size", "chunks", "blob", "salt", "created_at"],
+            {"password_id" = password_id, "user_id" = user.id()},
+            "ORDER BY id"
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let mut list = Vec::with_capacity(rows.len());
+        for row in rows {
+            list.push(self.decrypt_info(user, &row)?);
+        }
+        Ok(list)
+    }
+
+    /// Starts an upload, the file is stored once `FileWriter::finish` succeeds.
+    #[conerror]
+    pub async fn create_file(
+        &self,
+        user: &User,
+        password_id: i64,
+        name: &str,
+    ) -> conerror::Result<FileWriter> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        let exists: Option<(i64,)> = sqlx::query_as(
+            "SELECT `id` FROM `password` WHERE `id` = ? AND `user_id` = ? AND `deleted_at` IS NULL",
+        )
+        .bind(password_id)
+        .bind(user.id())
+        .fetch_optional(&self.db)
+        .await?;
+        if exists.is_none() {
+            return Err(msg("密码不存在"));
+        }
+
+        let (blob, salt) = new_blob();
+        let key = self
+            .encryption
+            .derive_chunk_key(user.credential().password(), &salt)?;
+        let path = self.dir.join(format!("{}.part", blob));
+        let file = File::create(&path).await?;
+        Ok(FileWriter {
+            manager: self.clone(),
+            user: user.clone(),
+            password_id,
+            name: name.to_string(),
+            blob,
+            salt,
+            key,
+            path,
+            file: BufWriter::new(file),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            size: 0,
+            chunks: 0,
+        })
+    }
+
+    #[conerror]
+    pub async fn open_file(
+        &self,
+        user: &User,
+        id: i64,
+    ) -> conerror::Result<Option<(FileInfo, FileReader)>> {
+        // files of entries in the trash can't be downloaded until the entry is restored
+        let row: Option<FileRow> = sqlx::query_as(
+            "SELECT `password_file`.`id`, `password_file`.`name`, `size`, `chunks`, `blob`, `salt`, \
+            `password_file`.`created_at` FROM `password_file` \
+            JOIN `password` ON `password`.`id` = `password_file`.`password_id` \
+            WHERE `password_file`.`id` = ? AND `password_file`.`user_id` = ? AND `password`.`deleted_at` IS NULL",
+        )
+        .bind(id)
+        .bind(user.id())
+        .fetch_optional(&self.db)
+        .await?;
+        let row = match row {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let info = self.decrypt_info(user, &row)?;
+        let reader = self.reader(user, &row).await?;
+        Ok(Some((info, reader)))
+    }
+
+    #[conerror]
+    pub async fn delete_file(&self, user: &User, id: i64) -> conerror::Result<()> {
+        let blob: Option<(String,)> =
+            select!("password_file", ["blob"], {"id" = id, "user_id" = user.id()})
+                .fetch_optional(&self.db)
+                .await?;
+        if let Some((blob,)) = blob {
+            delete!("password_file", {"id" = id, "user_id" = user.id()})
+                .execute(&self.db)
+                .await?;
+            self.remove_blob(&blob).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes stored files that are no longer referenced, e.g. after their entry
+    /// was purged from the trash, and abandoned uploads.
+    #[conerror]
+    pub async fn collect_garbage(&self) -> conerror::Result<usize> {
+        let mut removed = 0;
+        let mut dir = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let remove = match name.strip_suffix(".part") {
+                Some(_) => {
+                    let modified = entry.metadata().await?.modified()?;
+                    SystemTime::now()
+                        .duration_since(modified)
+                        .unwrap_or_default()
+                        > PARTIAL_FILE_LIFETIME
+                }
+                None => {
+                    let exists: Option<(i64,)> =
+                        select!("password_file", ["id"], { "blob" = &name })
+                            .fetch_optional(&self.db)
+                            .await?;
+                    exists.is_none()
+                }
+            };
+            if remove {
+                fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Re-encrypts the files of `from` for `to` with a new credential. The new copies are
+    /// written next to the old ones and the rows point to them once `tx` is committed,
+    /// after which `RekeyedFiles::finish` has to be called to put them in place.
+    #[conerror]
+    pub async fn rekey(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        from: &User,
+        to: &User,
+    ) -> conerror::Result<RekeyedFiles> {
+        let rows: Vec<FileRow> = select!(
+            "password_file",
+            ["id", "name", "size", "chunks", "blob", "salt", "created_at"],
+            { "user_id" = from.id() }
+        )
+        .fetch_all(&mut **tx)
+        .await?;
+        let mut files = RekeyedFiles {
+            manager: self.clone(),
+            blobs: Vec::with_capacity(rows.len()),
+        };
+        for row in rows {
+            let (blob, salt) = new_blob();
+            files.blobs.push((row.blob.clone(), blob.clone()));
+            if let Err(err) = self.rekey_file(tx, from, to, &row, &blob, &salt).await {
+                files.abort().await;
+                return Err(err);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Writes a re-encrypted copy of a file to `<blob>.part` and points its row to `blob`.
+    #[conerror]
+    async fn rekey_file(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        from: &User,
+        to: &User,
+        row: &FileRow,
+        blob: &str,
+        salt: &[u8],
+    ) -> conerror::Result<()> {
+        let mut reader = self.reader(from, row).await?;
+        let key = self
+            .encryption
+            .derive_chunk_key(to.credential().password(), salt)?;
+        let mut file = BufWriter::new(File::create(self.dir.join(format!("{}.part", blob))).await?);
+        let mut index = 0;
+        while let Some(data) = reader.next_chunk().await? {
+            let data = self.encryption.encrypt_chunk(&data, &key, index)?;
+            write_chunk(&mut file, &data).await?;
+            index += 1;
+        }
+        file.flush().await?;
+        file.get_ref().sync_all().await?;
+
+        let name = self.encryption.decrypt(
+            &row.name,
+            from.credential().password(),
+            from.credential().salt(),
+        )?;
+        let name =
+            self.encryption
+                .encrypt(&name, to.credential().password(), to.credential().salt())?;
+        update!(
+            "password_file",
+            {"name": &name, "blob": blob, "salt": salt},
+            {"id" = row.id}
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    #[conerror]
+    async fn reader(&self, user: &User, row: &FileRow) -> conerror::Result<FileReader> {
+        let key = self
+            .encryption
+            .derive_chunk_key(user.credential().password(), &row.salt)?;
+        let file = File::open(self.dir.join(&row.blob)).await?;
+        Ok(FileReader {
+            encryption: self.encryption.clone(),
+            key,
+            file: BufReader::new(file),
+            index: 0,
+            chunks: row.chunks,
+        })
+    }
+
+    #[conerror]
+    async fn remove_blob(&self, blob: &str) -> conerror::Result<()> {
+        match fs::remove_file(self.dir.join(blob)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(Error::plain(err)),
+            _ => Ok(()),
+        }
+    }
+
+    #[conerror]
+    fn decrypt_info(&self, user: &User, row: &FileRow) -> conerror::Result<FileInfo> {
+        let name = self.encryption.decrypt(
+            &row.name,
+            user.credential().password(),
+            user.credential().salt(),
+        )?;
+        Ok(FileInfo {
+            id: row.id,
+            name: String::from_utf8(name)?,
+            size: row.size,
+            created_at: row.created_at,
+        })
+    }
+}
+
+/// A random name and salt for a stored file.
+fn new_blob() -> (String, Vec<u8>) {
+    let mut id = [0u8; 16];
+    fill_bytes(&mut id);
+    let blob = id.iter().map(|v| format!("{:02x}", v)).collect();
+    let mut salt = vec![0u8; 32];
+    fill_bytes(&mut salt);
+    (blob, salt)
+}
+
+/// Writes an encrypted chunk prefixed with its length.
+#[conerror]
+async fn write_chunk(file: &mut BufWriter<File>, data: &[u8]) -> conerror::Result<()> {
+    file.write_all(&(data.len() as u32).to_le_bytes()).await?;
+    file.write_all(data).await?;
+    Ok(())
+}
+
+/// Files re-encrypted by `FileManager::rekey`, as pairs of the old and the new blob.
+pub struct RekeyedFiles {
+    manager: FileManager,
+    blobs: Vec<(String, String)>,
+}
+
+impl RekeyedFiles {
+    /// Replaces the old files with the new ones, after the rows were committed.
+    #[conerror]
+    pub async fn finish(self) -> conerror::Result<()> {
+        let dir = &self.manager.dir;
+        for (_, blob) in &self.blobs {
+            fs::rename(dir.join(format!("{}.part", blob)), dir.join(blob)).await?;
+        }
+        for (blob, _) in &self.blobs {
+            self.manager.remove_blob(blob).await?;
+        }
+        Ok(())
+    }
+
+    /// Discards the new files, when the rows weren't committed.
+    pub async fn abort(&self) {
+        for (_, blob) in &self.blobs {
+            let path = self.manager.dir.join(format!("{}.part", blob));
+            if let Err(err) = fs::remove_file(path).await {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    error!("error remove file: {}", err);
+                }
+            }
+        }
+    }
+}
+
+pub struct FileWriter {
+    manager: FileManager,
+    user: User,
+    password_id: i64,
+    name: String,
+    blob: String,
+    salt: Vec<u8>,
+    key: Vec<u8>,
+    path: PathBuf,
+    file: BufWriter<File>,
+    buf: Vec<u8>,
+    size: u64,
+    chunks: i64,
+}
+
+impl FileWriter {
+    #[conerror]
+    pub async fn write(&mut self, mut data: &[u8]) -> conerror::Result<()> {
+        self.size += data.len() as u64;
+        if self.size > self.manager.max_size {
+            return Err(msg("文件过大"));
+        }
+        while !data.is_empty() {
+            let n = data.len().min(CHUNK_SIZE - self.buf.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() == CHUNK_SIZE {
+                self.write_chunk().await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn finish(mut self) -> conerror::Result<FileInfo> {
+        if !self.buf.is_empty() || self.chunks == 0 {
+            self.write_chunk().await?;
+        }
+        self.file.flush().await?;
+        self.file.get_ref().sync_all().await?;
+
+        let manager = &self.manager;
+        let name = manager.encryption.encrypt(
+            self.name.as_bytes(),
+            self.user.credential().password(),
+            self.user.credential().salt(),
+        )?;
+        // the row is inserted first so the file is never unreferenced for `collect_garbage`,
+        // and only if the credential wasn't rotated during the upload, which would have
+        // left the file encrypted with the old one
+        let now = timestamp();
+        let result = sqlx::query(
+            "INSERT INTO `password_file`(`password_id`,`user_id`,`name`,`size`,`chunks`,`blob`,`salt`,`created_at`) \
+            SELECT ?,?,?,?,?,?,?,? FROM `user` WHERE `id` = ? AND `credential_version` = ?",
+        )
+        .bind(self.password_id)
+        .bind(self.user.id())
+        .bind(&name)
+        .bind(self.size as i64)
+        .bind(self.chunks)
+        .bind(&self.blob)
+        .bind(&self.salt)
+        .bind(now)
+        .bind(self.user.id())
+        .bind(self.user.credential_version())
+        .execute(&manager.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            fs::remove_file(&self.path).await?;
+            return Err(msg("密钥已更换，请重新上传"));
+        }
+        let id = result.last_insert_rowid();
+        if let Err(err) = fs::rename(&self.path, manager.dir.join(&self.blob)).await {
+            delete!("password_file", { "id" = id })
+                .execute(&manager.db)
+                .await?;
+            return Err(Error::plain(err));
+        }
+        Ok(FileInfo {
+            id,
+            name: self.name,
+            size: self.size as i64,
+            created_at: now,
+        })
+    }
+
+    /// Discards the upload.
+    #[conerror]
+    pub async fn abort(self) -> conerror::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path).await?;
+        Ok(())
+    }
+
+    #[conerror]
+    async fn write_chunk(&mut self) -> conerror::Result<()> {
+        let data =
+            self.manager
+                .encryption
+                .encrypt_chunk(&self.buf, &self.key, self.chunks as u64)?;
+        write_chunk(&mut self.file, &data).await?;
+        self.buf.clear();
+        self.chunks += 1;
+        Ok(())
+    }
+}
+
+pub struct FileReader {
+    encryption: EncryptionManager,
+    key: Vec<u8>,
+    file: BufReader<File>,
+    index: i64,
+    chunks: i64,
+}
+
+impl FileReader {
+    /// Returns the next decrypted chunk, or `None` after the last one.
+    #[conerror]
+    pub async fn next_chunk(&mut self) -> conerror::Result<Option<Vec<u8>>> {
+        if self.index == self.chunks {
+            return Ok(None);
+        }
+        let mut len = [0u8; 4];
+        self.file.read_exact(&mut len).await?;
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.file.read_exact(&mut data).await?;
+        let data = self
+            .encryption
+            .decrypt_chunk(&data, &self.key, self.index as u64)?;
+        self.index += 1;
+        Ok(Some(data))
+    }
+}
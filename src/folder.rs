@@ -0,0 +1,143 @@
+use conerror::conerror;
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::error::msg;
+use crate::user::User;
+use crate::util::timestamp;
+
+#[derive(FromRow, Serialize)]
+pub struct Folder {
+    id: i64,
+    parent_id: Option<i64>,
+    name: String,
+}
+
+#[derive(Clone)]
+pub struct FolderManager {
+    db: SqlitePool,
+}
+
+impl FolderManager {
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    #[conerror]
+    pub async fn list_folder(&self, user: &User) -> conerror::Result<Vec<Folder>> {
+        let list = select!(
+            "folder",
+            ["id", "parent_id", "name"],
+            { "user_id" = user.id() },
+            "ORDER BY name"
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(list)
+    }
+
+    #[conerror]
+    pub async fn create_folder(
+        &self,
+        user: &User,
+        name: &str,
+        parent_id: Option<i64>,
+    ) -> conerror::Result<i64> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        if let Some(parent_id) = parent_id {
+            self.find_folder(user, parent_id).await?;
+        }
+        let id = insert!("folder", {
+            "user_id": user.id(),
+            "parent_id": parent_id,
+            "name": name,
+            "created_at": timestamp(),
+        })
+        .execute(&self.db)
+        .await?
+        .last_insert_rowid();
+        Ok(id)
+    }
+
+    #[conerror]
+    pub async fn rename_folder(&self, user: &User, id: i64, name: &str) -> conerror::Result<()> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        update!("folder", {"name": name}, {"id" = id, "user_id" = user.id()})
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn move_folder(
+        &self,
+        user: &User,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> conerror::Result<()> {
+        self.find_folder(user, id).await?;
+        let mut ancestor = parent_id;
+        while let Some(v) = ancestor {
+            if v == id {
+                return Err(msg("不能移动到子文件夹"));
+            }
+            ancestor = self.find_folder(user, v).await?.parent_id;
+        }
+        update!("folder", {"parent_id": parent_id}, {"id" = id, "user_id" = user.id()})
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes the folder, its subfolders and entries are moved to its parent.
+    #[conerror]
+    pub async fn delete_folder(&self, user: &User, id: i64) -> conerror::Result<()> {
+        let folder = self.find_folder(user, id).await?;
+        let mut tx = self.db.begin().await?;
+        update!("folder", {"parent_id": folder.parent_id}, {"parent_id" = id, "user_id" = user.id()})
+            .execute(&mut *tx)
+            .await?;
+        update!("password", {"folder_id": folder.parent_id}, {"folder_id" = id, "user_id" = user.id()})
+            .execute(&mut *tx)
+            .await?;
+        delete!("folder", {"id" = id, "user_id" = user.id()})
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn move_password(
+        &self,
+        user: &User,
+        password_id: i64,
+        folder_id: Option<i64>,
+    ) -> conerror::Result<()> {
+        if let Some(folder_id) = folder_id {
+            self.find_folder(user, folder_id).await?;
+        }
+        update!("password", {"folder_id": folder_id}, {"id" = password_id, "user_id" = user.id()})
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    #[conerror]
+    async fn find_folder(&self, user: &User, id: i64) -> conerror::Result<Folder> {
+        let folder =
+            select!("folder", ["id", "parent_id", "name"], {"id" = id, "user_id" = user.id()})
+                .fetch_optional(&self.db)
+                .await?;
+        match folder {
+            Some(v) => Ok(v),
+            None => Err(msg("文件夹不存在")),
+        }
+    }
+}
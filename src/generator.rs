@@ -0,0 +1,201 @@
+use conerror::conerror;
+use serde::{Deserialize, Serialize};
+
+use crate::error::msg;
+use crate::util::fill_bytes;
+
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const DIGIT: &str = "0123456789";
+const SYMBOL: &str = "!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~";
+const AMBIGUOUS: &str = "0OoIl1|`'\"";
+
+const MAX_LENGTH: usize = 1024;
+const MAX_WORDS: usize = 64;
+
+static WORDLIST: &str = include_str!("wordlist.txt");
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GenerateSpec {
+    Password {
+        #[serde(default = "default_length")]
+        length: usize,
+        #[serde(default = "default_true")]
+        uppercase: bool,
+        #[serde(default = "default_true")]
+        lowercase: bool,
+        #[serde(default = "default_true")]
+        digit: bool,
+        #[serde(default = "default_true")]
+        symbol: bool,
+        #[serde(default)]
+        exclude_ambiguous: bool,
+    },
+    Passphrase {
+        #[serde(default = "default_words")]
+        words: usize,
+        #[serde(default = "default_separator")]
+        separator: String,
+        #[serde(default)]
+        capitalize: bool,
+    },
+}
+
+#[derive(Serialize)]
+pub struct Generated {
+    password: String,
+    entropy: f64,
+}
+
+impl Generated {
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+#[conerror]
+pub fn generate(spec: &GenerateSpec) -> conerror::Result<Generated> {
+    match *spec {
+        GenerateSpec::Password {
+            length,
+            uppercase,
+            lowercase,
+            digit,
+            symbol,
+            exclude_ambiguous,
+        } => {
+            let classes: Vec<Vec<char>> = [
+                (uppercase, UPPERCASE),
+                (lowercase, LOWERCASE),
+                (digit, DIGIT),
+                (symbol, SYMBOL),
+            ]
+            .into_iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, chars)| {
+                chars
+                    .chars()
+                    .filter(|c| !exclude_ambiguous || !AMBIGUOUS.contains(*c))
+                    .collect()
+            })
+            .collect();
+            if classes.is_empty() || length < classes.len() || length > MAX_LENGTH {
+                return Err(msg("参数错误"));
+            }
+            Ok(generate_password(&classes, length))
+        }
+        GenerateSpec::Passphrase {
+            words,
+            ref separator,
+            capitalize,
+        } => {
+            if words == 0 || words > MAX_WORDS {
+                return Err(msg("参数错误"));
+            }
+            Ok(generate_passphrase(words, separator, capitalize))
+        }
+    }
+}
+
+fn generate_password(classes: &[Vec<char>], length: usize) -> Generated {
+    let pool: Vec<char> = classes.iter().flatten().copied().collect();
+    let mut password = Vec::with_capacity(length);
+    for class in classes {
+        password.push(class[random_below(class.len())]);
+    }
+    while password.len() < length {
+        password.push(pool[random_below(pool.len())]);
+    }
+    for i in (1..password.len()).rev() {
+        password.swap(i, random_below(i + 1));
+    }
+    Generated {
+        password: password.into_iter().collect(),
+        entropy: length as f64 * (pool.len() as f64).log2(),
+    }
+}
+
+fn generate_passphrase(words: usize, separator: &str, capitalize: bool) -> Generated {
+    let wordlist: Vec<&str> = WORDLIST.lines().collect();
+    let mut list = Vec::with_capacity(words);
+    for _ in 0..words {
+        let word = wordlist[random_below(wordlist.len())];
+        list.push(if capitalize {
+            word[..1].to_uppercase() + &word[1..]
+        } else {
+            word.to_string()
+        });
+    }
+    Generated {
+        password: list.join(separator),
+        entropy: words as f64 * (wordlist.len() as f64).log2(),
+    }
+}
+
+fn random_below(n: usize) -> usize {
+    let n = n as u64;
+    let zone = u64::MAX - u64::MAX % n;
+    loop {
+        let mut bytes = [0u8; 8];
+        fill_bytes(&mut bytes);
+        let v = u64::from_le_bytes(bytes);
+        if v < zone {
+            return (v % n) as usize;
+        }
+    }
+}
+
+fn default_length() -> usize {
+    16
+}
+
+fn default_words() -> usize {
+    6
+}
+
+fn default_separator() -> String {
+    "-".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generator::{generate, GenerateSpec, AMBIGUOUS, DIGIT, SYMBOL};
+
+    #[test]
+    fn test_generate_password() {
+        let spec = GenerateSpec::Password {
+            length: 4,
+            uppercase: true,
+            lowercase: true,
+            digit: true,
+            symbol: true,
+            exclude_ambiguous: true,
+        };
+        for _ in 0..100 {
+            let password = generate(&spec).unwrap().password;
+            assert_eq!(4, password.len());
+            assert!(password.chars().any(|c| c.is_ascii_uppercase()));
+            assert!(password.chars().any(|c| c.is_ascii_lowercase()));
+            assert!(password.chars().any(|c| DIGIT.contains(c)));
+            assert!(password.chars().any(|c| SYMBOL.contains(c)));
+            assert!(!password.chars().any(|c| AMBIGUOUS.contains(c)));
+        }
+    }
+
+    #[test]
+    fn test_generate_passphrase() {
+        let spec = GenerateSpec::Passphrase {
+            words: 5,
+            separator: " ".to_string(),
+            capitalize: false,
+        };
+        let generated = generate(&spec).unwrap();
+        assert_eq!(5, generated.password.split(' ').count());
+        assert_eq!(55.0, generated.entropy);
+    }
+}
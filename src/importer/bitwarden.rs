@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use conerror::conerror;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::error::msg;
+use crate::importer::{ImportRecord, ParsedImport};
+
+const TYPE_LOGIN: i64 = 1;
+const TYPE_SECURE_NOTE: i64 = 2;
+const TYPE_CARD: i64 = 3;
+const TYPE_IDENTITY: i64 = 4;
+
+#[derive(Deserialize)]
+struct Export {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    folders: Vec<Folder>,
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct Folder {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Item {
+    #[serde(rename = "type")]
+    kind: i64,
+    name: Option<String>,
+    notes: Option<String>,
+    folder_id: Option<String>,
+    #[serde(default)]
+    fields: Vec<Field>,
+    login: Option<Login>,
+    card: Option<Map<String, Value>>,
+    identity: Option<Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+struct Field {
+    name: Option<String>,
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    username: Option<String>,
+    password: Option<String>,
+    totp: Option<String>,
+    #[serde(default)]
+    uris: Vec<Uri>,
+}
+
+#[derive(Deserialize)]
+struct Uri {
+    uri: Option<String>,
+}
+
+#[conerror]
+pub(super) fn parse(data: &[u8]) -> conerror::Result<ParsedImport> {
+    let export: Export = serde_json::from_slice(data).map_err(|_| msg("文件格式错误"))?;
+    if export.encrypted {
+        return Err(msg("不支持加密的导出文件"));
+    }
+    let folders: HashMap<_, _> = export.folders.into_iter().map(|v| (v.id, v.name)).collect();
+
+    let mut parsed = ParsedImport::default();
+    for (i, item) in export.items.into_iter().enumerate() {
+        let name = match item.name.filter(|v| !v.is_empty()) {
+            Some(v) => v,
+            None => {
+                parsed.skip(i + 1, "missing name");
+                continue;
+            }
+        };
+        let mut record = ImportRecord {
+            name,
+            notes: item.notes.filter(|v| !v.is_empty()),
+            ..Default::default()
+        };
+        match (item.kind, item.login, item.card, item.identity) {
+            (TYPE_LOGIN, Some(login), _, _) => {
+                record.username = login.username.unwrap_or_default();
+                record.password = login.password.unwrap_or_default();
+                record.totp = login.totp;
+                for uri in login.uris.into_iter().filter_map(|v| v.uri) {
+                    record.add_url(&uri);
+                }
+            }
+            (TYPE_SECURE_NOTE, _, _, _) => {}
+            (TYPE_CARD, _, Some(fields), _) | (TYPE_IDENTITY, _, _, Some(fields)) => {
+                for (name, value) in fields {
+                    if let Value::String(value) = value {
+                        record.add_field(&name, &value);
+                    }
+                }
+            }
+            (kind, _, _, _) => {
+                parsed.skip(i + 1, format!("unsupported item type {}", kind));
+                continue;
+            }
+        }
+        for field in item.fields {
+            if let (Some(name), Some(value)) = (field.name, field.value) {
+                record.add_field(&name, &value);
+            }
+        }
+        if let Some(folder) = item.folder_id.and_then(|v| folders.get(&v)) {
+            record.add_field("Folder", folder);
+        }
+        parsed.push(record);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::importer::bitwarden::parse;
+
+    #[test]
+    fn test_parse() {
+        let data = br#"{
+            "encrypted": false,
+            "folders": [{"id": "f1", "name": "Work"}],
+            "items": [
+                {
+                    "type": 1,
+                    "name": "GitHub",
+                    "notes": "recovery codes in safe",
+                    "folderId": "f1",
+                    "fields": [{"name": "PIN", "value": "1234"}],
+                    "login": {
+                        "username": "alice",
+                        "password": "hunter2",
+                        "totp": "JBSWY3DPEHPK3PXP",
+                        "uris": [{"uri": "https://github.com/login"}, {"uri": "not a url"}]
+                    }
+                },
+                {"type": 3, "name": "Visa", "card": {"cardholderName": "Alice", "number": "4111", "code": null}},
+                {"type": 2, "name": "Note", "notes": "text"},
+                {"type": 5, "name": "SSH"},
+                {"type": 1, "name": "", "login": {}}
+            ]
+        }"#;
+        let parsed = parse(data).unwrap();
+        assert_eq!(3, parsed.records.len());
+
+        let login = &parsed.records[0];
+        assert_eq!("GitHub", login.name);
+        assert_eq!("alice", login.username);
+        assert_eq!("hunter2", login.password);
+        assert_eq!(
+            Some("otpauth://totp/GitHub?secret=JBSWY3DPEHPK3PXP"),
+            login.totp.as_deref()
+        );
+        assert_eq!(vec!["https://github.com/login"], login.urls);
+        assert_eq!(
+            Some("recovery codes in safe\n\nURL: not a url\nPIN: 1234\nFolder: Work"),
+            login.attachment().as_deref()
+        );
+
+        let card = &parsed.records[1];
+        assert_eq!(
+            Some("cardholderName: Alice\nnumber: 4111"),
+            card.attachment().as_deref()
+        );
+
+        let skipped: Vec<_> = parsed.skipped.iter().map(|v| v.record).collect();
+        assert_eq!(vec![4, 5], skipped);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(b"").is_err());
+        assert!(parse(b"{\"items\": [{\"name\": \"x\"}]}").is_err());
+        assert!(parse(b"{\"encrypted\": true, \"items\": []}").is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use conerror::conerror;
+
+use crate::importer::{parse_csv, ParsedImport};
+
+/// Chrome exports `name,url,username,password,note`, Firefox exports
+/// `url,username,password,httpRealm,formActionOrigin,guid,timeCreated,timeLastUsed,timePasswordChanged`.
+const COLUMNS: &[(&str, &[&str])] = &[
+    ("name", &["name", "title"]),
+    ("username", &["username", "login"]),
+    ("password", &["password"]),
+    ("url", &["url", "origin"]),
+    ("notes", &["note", "notes"]),
+    ("totp", &["totp"]),
+];
+
+const IGNORED: &[&str] = &[
+    "httpRealm",
+    "formActionOrigin",
+    "guid",
+    "timeCreated",
+    "timeLastUsed",
+    "timePasswordChanged",
+];
+
+#[conerror]
+pub(super) fn parse(data: &[u8]) -> conerror::Result<ParsedImport> {
+    parse_csv(data, COLUMNS, IGNORED)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::importer::browser::parse;
+
+    #[test]
+    fn test_parse_chrome() {
+        let data = "name,url,username,password,note\n\
+            GitHub,https://github.com/login,alice,hunter2,\n\
+            ,https://example.com/,bob,secret,\"multi\nline\"\n\
+            ,,,,\n";
+        let parsed = parse(data.as_bytes()).unwrap();
+        assert_eq!(2, parsed.records.len());
+        assert_eq!("GitHub", parsed.records[0].name);
+        assert_eq!(vec!["https://github.com/login"], parsed.records[0].urls);
+        assert_eq!(None, parsed.records[0].attachment());
+        // the name falls back to the host
+        assert_eq!("example.com", parsed.records[1].name);
+        assert_eq!(
+            Some("multi\nline"),
+            parsed.records[1].attachment().as_deref()
+        );
+        assert_eq!(1, parsed.skipped.len());
+        assert_eq!(3, parsed.skipped[0].record);
+    }
+
+    #[test]
+    fn test_parse_firefox() {
+        let data = "url,username,password,httpRealm,formActionOrigin,guid,timeCreated,timeLastUsed,timePasswordChanged\n\
+            https://example.com,alice,pw,,https://example.com,{1},1,2,3\n";
+        let parsed = parse(data.as_bytes()).unwrap();
+        assert_eq!(1, parsed.records.len());
+        assert_eq!("example.com", parsed.records[0].name);
+        assert!(parsed.records[0].fields.is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(b"\xff\xfe,\xff\n").is_err());
+        let parsed =
+            parse(b"name,url,username,password\nGitHub,https://github.com,alice\n").unwrap();
+        assert!(parsed.records.is_empty());
+        assert_eq!("expected 4 columns, found 3", parsed.skipped[0].reason);
+    }
+}
@@ -0,0 +1,59 @@
+use conerror::conerror;
+
+use crate::importer::{parse_csv, ParsedImport};
+
+/// KeePass 2.x "KeePass CSV (1.x)" export and the KeePassXC CSV export.
+const COLUMNS: &[(&str, &[&str])] = &[
+    ("name", &["Account", "Title"]),
+    ("username", &["Login Name", "Username", "User Name"]),
+    ("password", &["Password"]),
+    ("url", &["Web Site", "URL"]),
+    ("notes", &["Comments", "Notes"]),
+    ("totp", &["TOTP"]),
+];
+
+const IGNORED: &[&str] = &["Icon", "Last Modified", "Created"];
+
+#[conerror]
+pub(super) fn parse(data: &[u8]) -> conerror::Result<ParsedImport> {
+    parse_csv(data, COLUMNS, IGNORED)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::importer::keepass::parse;
+
+    #[test]
+    fn test_parse() {
+        let data = "\"Group\",\"Title\",\"Username\",\"Password\",\"URL\",\"Notes\",\"TOTP\",\"Icon\",\"Last Modified\",\"Created\"\n\
+            \"Root\",\"Mail\",\"alice\",\"pw\",\"https://mail.example.com\",\"note\",\"otpauth://totp/Mail?secret=JBSWY3DPEHPK3PXP\",\"0\",\"2024\",\"2024\"\n\
+            \"Root\",\"Bank\",\"bob\",\"pw\",\"\",\"\",\"not base32!\",\"0\",\"2024\",\"2024\"\n";
+        let parsed = parse(data.as_bytes()).unwrap();
+        assert_eq!(2, parsed.records.len());
+
+        let mail = &parsed.records[0];
+        assert_eq!("Mail", mail.name);
+        assert_eq!("alice", mail.username);
+        assert_eq!(
+            Some("otpauth://totp/Mail?secret=JBSWY3DPEHPK3PXP"),
+            mail.totp.as_deref()
+        );
+        assert_eq!(Some("note\n\nGroup: Root"), mail.attachment().as_deref());
+
+        // an unusable TOTP secret is kept as a field
+        let bank = &parsed.records[1];
+        assert_eq!(None, bank.totp);
+        assert_eq!(
+            Some("Group: Root\nTOTP: not base32!"),
+            bank.attachment().as_deref()
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(b"\xff\n").is_err());
+        let parsed = parse(b"Title,Username,Password\n,alice,pw\n").unwrap();
+        assert!(parsed.records.is_empty());
+        assert_eq!("missing name", parsed.skipped[0].reason);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use conerror::conerror;
+use serde::{Deserialize, Serialize};
+
+use crate::error::msg;
+use crate::password::{PasswordCreate, PasswordManager};
+use crate::site::parse_url;
+use crate::totp::Totp;
+use crate::user::User;
+
+mod bitwarden;
+mod browser;
+mod keepass;
+mod onepassword;
+
+#[derive(Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    BitwardenJson,
+    KeepassCsv,
+    BrowserCsv,
+    Onepassword1pux,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bitwarden_json" => Ok(Self::BitwardenJson),
+            "keepass_csv" => Ok(Self::KeepassCsv),
+            "browser_csv" => Ok(Self::BrowserCsv),
+            "onepassword_1pux" => Ok(Self::Onepassword1pux),
+            _ => Err(format!("unknown import format: {}", s)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ImportRecord {
+    name: String,
+    username: String,
+    password: String,
+    notes: Option<String>,
+    totp: Option<String>,
+    fields: Vec<(String, String)>,
+    urls: Vec<String>,
+}
+
+impl ImportRecord {
+    fn add_field(&mut self, name: &str, value: &str) {
+        if !value.is_empty() {
+            self.fields.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    /// Adds a URL of the entry, values that aren't valid URLs are kept as extra fields.
+    fn add_url(&mut self, url: &str) {
+        if parse_url(url).is_some() {
+            self.urls.push(url.trim().to_string());
+        } else {
+            self.add_field("URL", url);
+        }
+    }
+
+    /// Normalizes the TOTP secret to an `otpauth://` URI, or keeps it as an extra field when it can't be used.
+    fn finish(mut self) -> Self {
+        if let Some(totp) = self.totp.take().filter(|v| !v.is_empty()) {
+            let uri = if totp.starts_with("otpauth://") {
+                totp.clone()
+            } else {
+                format!(
+                    "otpauth://totp/{}?secret={}",
+                    url::form_urlencoded::byte_serialize(self.name.as_bytes()).collect::<String>(),
+                    totp.replace(' ', "")
+                )
+            };
+            match Totp::from_uri(&uri) {
+                Ok(_) => self.totp = Some(uri),
+                Err(_) => self.add_field("TOTP", &totp),
+            }
+        }
+        self
+    }
+
+    fn attachment(&self) -> Option<String> {
+        let mut attachment = self.notes.clone().unwrap_or_default();
+        if !self.fields.is_empty() {
+            if !attachment.is_empty() {
+                attachment.push_str("\n\n");
+            }
+            for (name, value) in &self.fields {
+                attachment.push_str(&format!("{}: {}\n", name, value));
+            }
+        }
+        let attachment = attachment.trim_end();
+        if attachment.is_empty() {
+            None
+        } else {
+            Some(attachment.to_string())
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SkippedRecord {
+    pub record: usize,
+    pub reason: String,
+}
+
+#[derive(Default)]
+struct ParsedImport {
+    records: Vec<ImportRecord>,
+    skipped: Vec<SkippedRecord>,
+}
+
+impl ParsedImport {
+    fn push(&mut self, record: ImportRecord) {
+        self.records.push(record.finish());
+    }
+
+    fn skip(&mut self, record: usize, reason: impl ToString) {
+        self.skipped.push(SkippedRecord {
+            record,
+            reason: reason.to_string(),
+        });
+    }
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub skipped: Vec<SkippedRecord>,
+}
+
+#[conerror]
+pub async fn import(
+    password_manager: &PasswordManager,
+    user: &User,
+    format: ImportFormat,
+    data: &[u8],
+) -> conerror::Result<ImportReport> {
+    let parsed = match format {
+        ImportFormat::BitwardenJson => bitwarden::parse(data)?,
+        ImportFormat::KeepassCsv => keepass::parse(data)?,
+        ImportFormat::BrowserCsv => browser::parse(data)?,
+        ImportFormat::Onepassword1pux => onepassword::parse(data)?,
+    };
+
+    for record in &parsed.records {
+        let attachment = record.attachment();
+        let create = PasswordCreate {
+            name: &record.name,
+            username: &record.username,
+            password: &record.password,
+            attachment: attachment.as_deref(),
+            totp: record.totp.as_deref(),
+            fields: &[],
+            payload: None,
+            urls: &record.urls,
+        };
+        password_manager.create_password(user, create).await?;
+    }
+    Ok(ImportReport {
+        imported: parsed.records.len(),
+        skipped: parsed.skipped,
+    })
+}
+
+/// Parses a CSV export with a header row. `columns` maps the known fields
+/// (name, username, password, url, notes, totp) to the header names used by the format,
+/// other columns except `ignored` are kept as extra fields.
+#[conerror]
+fn parse_csv(
+    data: &[u8],
+    columns: &[(&str, &[&str])],
+    ignored: &[&str],
+) -> conerror::Result<ParsedImport> {
+    let mut parsed = ParsedImport::default();
+    for (i, row) in read_csv(data, columns)?.into_iter().enumerate() {
+        let mut row = match row {
+            Ok(v) => v,
+            Err(err) => {
+                parsed.skip(i + 1, err);
+                continue;
+            }
+        };
+        let url = row.remove("url").unwrap_or_default();
+        let mut record = ImportRecord {
+            name: match row.remove("name").filter(|v| !v.is_empty()) {
+                Some(v) => v,
+                None => match host(&url) {
+                    Some(v) => v,
+                    None => {
+                        parsed.skip(i + 1, "missing name");
+                        continue;
+                    }
+                },
+            },
+            username: row.remove("username").unwrap_or_default(),
+            password: row.remove("password").unwrap_or_default(),
+            notes: row.remove("notes").filter(|v| !v.is_empty()),
+            totp: row.remove("totp"),
+            fields: Vec::new(),
+            urls: Vec::new(),
+        };
+        if record.username.is_empty() && record.password.is_empty() && record.notes.is_none() {
+            parsed.skip(i + 1, "empty entry");
+            continue;
+        }
+        record.add_url(&url);
+        let mut extra: Vec<_> = row
+            .into_iter()
+            .filter(|(k, _)| !ignored.iter().any(|v| v.eq_ignore_ascii_case(k)))
+            .collect();
+        extra.sort();
+        for (name, value) in extra {
+            record.add_field(&name, &value);
+        }
+        parsed.push(record);
+    }
+    Ok(parsed)
+}
+
+#[conerror]
+fn read_csv(
+    data: &[u8],
+    columns: &[(&str, &[&str])],
+) -> conerror::Result<Vec<Result<HashMap<String, String>, String>>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data);
+    let headers: Vec<String> = reader
+        .headers()
+        .map_err(|_| msg("文件格式错误"))?
+        .iter()
+        .map(|header| {
+            columns
+                .iter()
+                .find(|(_, aliases)| {
+                    aliases
+                        .iter()
+                        .any(|v| v.eq_ignore_ascii_case(header.trim()))
+                })
+                .map(|(field, _)| field.to_string())
+                .unwrap_or_else(|| header.trim().to_string())
+        })
+        .collect();
+
+    let mut rows = Vec::new();
+    for row in reader.records() {
+        rows.push(match row {
+            Ok(row) if row.len() == headers.len() => Ok(headers
+                .iter()
+                .cloned()
+                .zip(row.iter().map(|v| v.to_string()))
+                .collect()),
+            Ok(row) => Err(format!(
+                "expected {} columns, found {}",
+                headers.len(),
+                row.len()
+            )),
+            Err(err) => Err(err.to_string()),
+        });
+    }
+    Ok(rows)
+}
+
+fn host(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|v| v.host_str().map(|v| v.to_string()))
+}
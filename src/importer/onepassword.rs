@@ -0,0 +1,244 @@
+use std::io::{Cursor, Read};
+
+use conerror::conerror;
+use serde::Deserialize;
+use serde_json::Value;
+use zip::ZipArchive;
+
+use crate::error::msg;
+use crate::importer::{ImportRecord, ParsedImport};
+
+#[derive(Deserialize)]
+struct Export {
+    #[serde(default)]
+    accounts: Vec<Account>,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    #[serde(default)]
+    vaults: Vec<Vault>,
+}
+
+#[derive(Deserialize)]
+struct Vault {
+    attrs: VaultAttrs,
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct VaultAttrs {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    #[serde(default)]
+    state: String,
+    overview: Overview,
+    details: Details,
+}
+
+#[derive(Deserialize)]
+struct Overview {
+    title: Option<String>,
+    url: Option<String>,
+    #[serde(default)]
+    urls: Vec<Url>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Url {
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Details {
+    #[serde(default)]
+    login_fields: Vec<LoginField>,
+    notes_plain: Option<String>,
+    #[serde(default)]
+    sections: Vec<Section>,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LoginField {
+    #[serde(default)]
+    value: String,
+    #[serde(default)]
+    name: String,
+    designation: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Section {
+    #[serde(default)]
+    fields: Vec<SectionField>,
+}
+
+#[derive(Deserialize)]
+struct SectionField {
+    #[serde(default)]
+    title: String,
+    value: Value,
+}
+
+#[conerror]
+pub(super) fn parse(data: &[u8]) -> conerror::Result<ParsedImport> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|_| msg("文件格式错误"))?;
+    let mut export = Vec::new();
+    archive
+        .by_name("export.data")
+        .map_err(|_| msg("文件格式错误"))?
+        .read_to_end(&mut export)?;
+    let export: Export = serde_json::from_slice(&export).map_err(|_| msg("文件格式错误"))?;
+
+    let mut parsed = ParsedImport::default();
+    let items = export
+        .accounts
+        .into_iter()
+        .flat_map(|v| v.vaults)
+        .flat_map(|v| {
+            let vault = v.attrs.name;
+            v.items.into_iter().map(move |item| (vault.clone(), item))
+        });
+    for (i, (vault, item)) in items.enumerate() {
+        if item.state == "archived" {
+            parsed.skip(i + 1, "archived");
+            continue;
+        }
+        let name = match item.overview.title.filter(|v| !v.is_empty()) {
+            Some(v) => v,
+            None => {
+                parsed.skip(i + 1, "missing name");
+                continue;
+            }
+        };
+        let mut record = ImportRecord {
+            name,
+            password: item.details.password.unwrap_or_default(),
+            notes: item.details.notes_plain.filter(|v| !v.is_empty()),
+            ..Default::default()
+        };
+        for field in item.details.login_fields {
+            match field.designation.as_deref() {
+                Some("username") => record.username = field.value,
+                Some("password") => record.password = field.value,
+                _ => record.add_field(&field.name, &field.value),
+            }
+        }
+        for field in item.details.sections.into_iter().flat_map(|v| v.fields) {
+            if let Value::Object(value) = field.value {
+                match value.into_iter().next() {
+                    Some((kind, Value::String(value)))
+                        if kind == "totp" && record.totp.is_none() =>
+                    {
+                        record.totp = Some(value)
+                    }
+                    Some((_, Value::String(value))) => record.add_field(&field.title, &value),
+                    Some((_, Value::Number(value))) => {
+                        record.add_field(&field.title, &value.to_string())
+                    }
+                    Some((_, Value::Object(value))) => {
+                        if let Some(Value::String(value)) = value.get("email_address") {
+                            record.add_field(&field.title, value);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut urls: Vec<String> = item.overview.urls.into_iter().map(|v| v.url).collect();
+        if let Some(url) = item.overview.url.filter(|v| !urls.contains(v)) {
+            urls.insert(0, url);
+        }
+        for url in urls {
+            record.add_url(&url);
+        }
+        record.add_field("Tags", &item.overview.tags.join(", "));
+        if let Some(vault) = vault {
+            record.add_field("Vault", &vault);
+        }
+        parsed.push(record);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use crate::importer::onepassword::parse;
+
+    fn archive(name: &str, data: &str) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(name, FileOptions::default()).unwrap();
+        zip.write_all(data.as_bytes()).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = r#"{"accounts": [{"vaults": [{"attrs": {"name": "Personal"}, "items": [
+            {
+                "state": "active",
+                "overview": {"title": "GitHub", "url": "https://github.com", "urls": [{"url": "https://gist.github.com"}], "tags": ["dev"]},
+                "details": {
+                    "loginFields": [
+                        {"value": "alice", "name": "username", "designation": "username"},
+                        {"value": "hunter2", "name": "password", "designation": "password"},
+                        {"value": "on", "name": "remember"}
+                    ],
+                    "notesPlain": "note",
+                    "sections": [{"fields": [
+                        {"title": "one-time password", "value": {"totp": "otpauth://totp/GitHub?secret=JBSWY3DPEHPK3PXP"}},
+                        {"title": "PIN", "value": {"string": "1234"}},
+                        {"title": "email", "value": {"email": {"email_address": "a@example.com"}}}
+                    ]}]
+                }
+            },
+            {"state": "archived", "overview": {"title": "Old"}, "details": {}},
+            {"overview": {}, "details": {}}
+        ]}]}]}"#;
+        let parsed = parse(&archive("export.data", data)).unwrap();
+        assert_eq!(1, parsed.records.len());
+
+        let record = &parsed.records[0];
+        assert_eq!("GitHub", record.name);
+        assert_eq!("alice", record.username);
+        assert_eq!("hunter2", record.password);
+        assert_eq!(
+            Some("otpauth://totp/GitHub?secret=JBSWY3DPEHPK3PXP"),
+            record.totp.as_deref()
+        );
+        assert_eq!(
+            vec!["https://github.com", "https://gist.github.com"],
+            record.urls
+        );
+        assert_eq!(
+            Some(
+                "note\n\nremember: on\nPIN: 1234\nemail: a@example.com\nTags: dev\nVault: Personal"
+            ),
+            record.attachment().as_deref()
+        );
+
+        let skipped: Vec<_> = parsed.skipped.iter().map(|v| v.reason.as_str()).collect();
+        assert_eq!(vec!["archived", "missing name"], skipped);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(b"not a zip").is_err());
+        assert!(parse(&archive("other.data", "{}")).is_err());
+        assert!(parse(&archive("export.data", "{\"accounts\": 1}")).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncryptMut, KeyIvInit, StreamCipher};
+use aes::Aes256;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use chacha20::ChaCha20;
+use conerror::{conerror, Error};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::field::FieldKind;
+use crate::password::Password;
+use crate::util::{fill_bytes, timestamp};
+
+const SIGNATURE1: u32 = 0x9AA2D903;
+const SIGNATURE2: u32 = 0xB54BFB67;
+const VERSION: u32 = 0x00040000;
+
+const CIPHER_AES256: [u8; 16] = [
+    0x31, 0xc1, 0xf2, 0xe6, 0xbf, 0x71, 0x43, 0x50, 0xbe, 0x58, 0x05, 0x21, 0x6a, 0xfc, 0x5a, 0xff,
+];
+const KDF_ARGON2ID: [u8; 16] = [
+    0x9e, 0x29, 0x8b, 0x19, 0x56, 0xdb, 0x47, 0x73, 0xb2, 0x3d, 0xfc, 0x3e, 0xc6, 0xf0, 0xa1, 0xe6,
+];
+
+const HEADER_END: u8 = 0;
+const HEADER_CIPHER_ID: u8 = 2;
+const HEADER_COMPRESSION_FLAGS: u8 = 3;
+const HEADER_MASTER_SEED: u8 = 4;
+const HEADER_ENCRYPTION_IV: u8 = 7;
+const HEADER_KDF_PARAMETERS: u8 = 11;
+
+const INNER_HEADER_END: u8 = 0;
+const INNER_HEADER_RANDOM_STREAM_ID: u8 = 1;
+const INNER_HEADER_RANDOM_STREAM_KEY: u8 = 2;
+const RANDOM_STREAM_CHACHA20: u32 = 3;
+
+const ARGON2_MEMORY: u64 = 64 * 1024 * 1024;
+const ARGON2_ITERATIONS: u64 = 3;
+const ARGON2_PARALLELISM: u32 = 2;
+
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Seconds from 0001-01-01T00:00:00Z, the epoch of KDBX 4 timestamps, to the unix epoch.
+const UNIX_EPOCH_OFFSET: i64 = 62135596800;
+
+/// Serializes the entries into a KDBX 4 database protected by `password`.
+#[conerror]
+pub fn write_kdbx(entries: &[Password], password: &str) -> conerror::Result<Vec<u8>> {
+    let mut master_seed = [0u8; 32];
+    let mut iv = [0u8; 16];
+    let mut kdf_salt = [0u8; 32];
+    let mut stream_key = [0u8; 64];
+    fill_bytes(&mut master_seed);
+    fill_bytes(&mut iv);
+    fill_bytes(&mut kdf_salt);
+    fill_bytes(&mut stream_key);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&SIGNATURE1.to_le_bytes());
+    header.extend_from_slice(&SIGNATURE2.to_le_bytes());
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    write_field(&mut header, HEADER_CIPHER_ID, &CIPHER_AES256);
+    write_field(&mut header, HEADER_COMPRESSION_FLAGS, &0u32.to_le_bytes());
+    write_field(&mut header, HEADER_MASTER_SEED, &master_seed);
+    write_field(&mut header, HEADER_ENCRYPTION_IV, &iv);
+    write_field(
+        &mut header,
+        HEADER_KDF_PARAMETERS,
+        &kdf_parameters(&kdf_salt),
+    );
+    write_field(&mut header, HEADER_END, b"\r\n\r\n");
+
+    let transformed_key = transform_key(password, &kdf_salt)?;
+    let cipher_key = Sha256::new()
+        .chain_update(master_seed)
+        .chain_update(&transformed_key)
+        .finalize();
+    let hmac_key = Sha512::new()
+        .chain_update(master_seed)
+        .chain_update(&transformed_key)
+        .chain_update([1])
+        .finalize();
+
+    let mut payload = Vec::new();
+    write_field(
+        &mut payload,
+        INNER_HEADER_RANDOM_STREAM_ID,
+        &RANDOM_STREAM_CHACHA20.to_le_bytes(),
+    );
+    write_field(&mut payload, INNER_HEADER_RANDOM_STREAM_KEY, &stream_key);
+    write_field(&mut payload, INNER_HEADER_END, &[]);
+    payload.extend_from_slice(xml(entries, &stream_key).as_bytes());
+    let payload = cbc::Encryptor::<Aes256>::new(&cipher_key, (&iv).into())
+        .encrypt_padded_vec_mut::<Pkcs7>(&payload);
+
+    let mut file = header.clone();
+    file.extend_from_slice(&Sha256::digest(&header));
+    file.extend_from_slice(&block_hmac(&hmac_key, u64::MAX, &[&header])?);
+    for (i, block) in payload
+        .chunks(BLOCK_SIZE)
+        .chain(std::iter::once(&[][..]))
+        .enumerate()
+    {
+        let size = (block.len() as i32).to_le_bytes();
+        let index = (i as u64).to_le_bytes();
+        file.extend_from_slice(&block_hmac(&hmac_key, i as u64, &[&index, &size, block])?);
+        file.extend_from_slice(&size);
+        file.extend_from_slice(block);
+    }
+    Ok(file)
+}
+
+fn write_field(out: &mut Vec<u8>, id: u8, data: &[u8]) {
+    out.push(id);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+/// KDF parameters serialized as a KDBX variant dictionary.
+fn kdf_parameters(salt: &[u8]) -> Vec<u8> {
+    fn item(out: &mut Vec<u8>, kind: u8, name: &str, value: &[u8]) {
+        out.push(kind);
+        out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        out.extend_from_slice(value);
+    }
+
+    let mut out = 0x0100u16.to_le_bytes().to_vec();
+    item(&mut out, 0x42, "$UUID", &KDF_ARGON2ID);
+    item(&mut out, 0x42, "S", salt);
+    item(&mut out, 0x04, "P", &ARGON2_PARALLELISM.to_le_bytes());
+    item(&mut out, 0x05, "M", &ARGON2_MEMORY.to_le_bytes());
+    item(&mut out, 0x05, "I", &ARGON2_ITERATIONS.to_le_bytes());
+    item(&mut out, 0x04, "V", &0x13u32.to_le_bytes());
+    out.push(0);
+    out
+}
+
+#[conerror]
+fn transform_key(password: &str, salt: &[u8]) -> conerror::Result<Vec<u8>> {
+    let composite_key = Sha256::digest(Sha256::digest(password.as_bytes()));
+    let params = Params::new(
+        (ARGON2_MEMORY / 1024) as u32,
+        ARGON2_ITERATIONS as u32,
+        ARGON2_PARALLELISM,
+        Some(32),
+    )
+    .map_err(Error::plain)?;
+    let mut key = vec![0; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(&composite_key, salt, &mut key)
+        .map_err(Error::plain)?;
+    Ok(key)
+}
+
+#[conerror]
+fn block_hmac(hmac_key: &[u8], index: u64, data: &[&[u8]]) -> conerror::Result<Vec<u8>> {
+    let key = Sha512::new()
+        .chain_update(index.to_le_bytes())
+        .chain_update(hmac_key)
+        .finalize();
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key)?;
+    for v in data {
+        mac.update(v);
+    }
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn xml(entries: &[Password], stream_key: &[u8]) -> String {
+    let hash = Sha512::digest(stream_key);
+    let mut stream = ChaCha20::new(
+        GenericArray::from_slice(&hash[..32]),
+        GenericArray::from_slice(&hash[32..44]),
+    );
+    let mut protect = |value: &str| {
+        let mut value = value.as_bytes().to_vec();
+        stream.apply_keystream(&mut value);
+        BASE64_STANDARD.encode(value)
+    };
+
+    let now = timestamp();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n");
+    xml.push_str("<KeePassFile><Meta><Generator>passman</Generator><DatabaseName>passman</DatabaseName></Meta><Root><Group>");
+    xml.push_str(&format!(
+        "<UUID>{}</UUID><Name>passman</Name>{}<IsExpanded>True</IsExpanded>",
+        uuid(),
+        times(now, now)
+    ));
+    for entry in entries {
+        xml.push_str(&format!(
+            "<Entry><UUID>{}</UUID>{}",
+            uuid(),
+            times(entry.created_at, entry.updated_at)
+        ));
+        xml.push_str(&string("Title", &escape(&entry.name)));
+        xml.push_str(&string("UserName", &escape(&entry.username)));
+        xml.push_str(&protected_string("Password", &protect(&entry.password)));
+        xml.push_str(&string(
+            "Notes",
+            &escape(entry.attachment.as_deref().unwrap_or_default()),
+        ));
+        if let Some(totp) = &entry.totp {
+            xml.push_str(&protected_string("otp", &protect(totp)));
+        }
+        let mut keys = vec!["Title", "UserName", "Password", "Notes", "URL", "otp"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        // additional URLs use the convention of KeePass2Android and KeePassXC
+        for (i, url) in entry.urls.iter().enumerate() {
+            let key = match i {
+                0 => String::from("URL"),
+                _ => format!("KP2A_URL_{}", i),
+            };
+            xml.push_str(&string(&key, &escape(url)));
+            keys.push(key);
+        }
+        let payload = entry
+            .payload
+            .as_ref()
+            .map(|v| v.fields())
+            .unwrap_or_default();
+        let fields = entry
+            .fields
+            .iter()
+            .map(|v| {
+                (
+                    v.name.as_str(),
+                    v.value.clone(),
+                    v.kind == FieldKind::Hidden,
+                )
+            })
+            .chain(payload);
+        for (name, value, secret) in fields {
+            // keys must be unique within an entry
+            let mut key = name.to_string();
+            let mut n = 1;
+            while keys.contains(&key) {
+                n += 1;
+                key = format!("{} ({})", name, n);
+            }
+            if secret {
+                xml.push_str(&protected_string(&escape(&key), &protect(&value)));
+            } else {
+                xml.push_str(&string(&escape(&key), &escape(&value)));
+            }
+            keys.push(key);
+        }
+        xml.push_str("</Entry>");
+    }
+    xml.push_str("</Group></Root></KeePassFile>");
+    xml
+}
+
+fn string(key: &str, value: &str) -> String {
+    format!(
+        "<String><Key>{}</Key><Value>{}</Value></String>",
+        key, value
+    )
+}
+
+fn protected_string(key: &str, value: &str) -> String {
+    format!(
+        "<String><Key>{}</Key><Value Protected=\"True\">{}</Value></String>",
+        key, value
+    )
+}
+
+fn times(created_at: i64, updated_at: i64) -> String {
+    format!(
+        "<Times><CreationTime>{}</CreationTime><LastModificationTime>{}</LastModificationTime>\
+        <LastAccessTime>{}</LastAccessTime><ExpiryTime>{}</ExpiryTime><Expires>False</Expires>\
+        <UsageCount>0</UsageCount><LocationChanged>{}</LocationChanged></Times>",
+        time(created_at),
+        time(updated_at),
+        time(updated_at),
+        time(updated_at),
+        time(updated_at)
+    )
+}
+
+fn time(timestamp: i64) -> String {
+    BASE64_STANDARD.encode((timestamp + UNIX_EPOCH_OFFSET).to_le_bytes())
+}
+
+fn uuid() -> String {
+    let mut uuid = [0u8; 16];
+    fill_bytes(&mut uuid);
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    BASE64_STANDARD.encode(uuid)
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::block_padding::Pkcs7;
+    use aes::cipher::generic_array::GenericArray;
+    use aes::cipher::{BlockDecryptMut, KeyIvInit, StreamCipher};
+    use aes::Aes256;
+    use base64::prelude::BASE64_STANDARD;
+    use base64::Engine;
+    use chacha20::ChaCha20;
+    use sha2::{Digest, Sha256, Sha512};
+
+    use crate::entry::EntryType;
+    use crate::field::{CustomField, FieldKind};
+    use crate::kdbx::*;
+    use crate::password::Password;
+
+    fn read_u32(data: &[u8], pos: usize) -> usize {
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize
+    }
+
+    /// Reads the header fields, returns them with the length of the header.
+    fn read_fields(data: &[u8], mut pos: usize) -> (Vec<(u8, Vec<u8>)>, usize) {
+        let mut fields = Vec::new();
+        loop {
+            let id = data[pos];
+            let len = read_u32(data, pos + 1);
+            fields.push((id, data[pos + 5..pos + 5 + len].to_vec()));
+            pos += 5 + len;
+            if id == HEADER_END {
+                return (fields, pos);
+            }
+        }
+    }
+
+    fn field(fields: &[(u8, Vec<u8>)], id: u8) -> &[u8] {
+        &fields.iter().find(|v| v.0 == id).unwrap().1
+    }
+
+    /// Verifies the file like a KDBX 4 reader and returns the decrypted XML with
+    /// protected values in plain text, `None` if the password is wrong.
+    fn read_kdbx(data: &[u8], password: &str) -> Option<String> {
+        assert_eq!(SIGNATURE1, read_u32(data, 0) as u32);
+        assert_eq!(SIGNATURE2, read_u32(data, 4) as u32);
+        assert_eq!(VERSION, read_u32(data, 8) as u32);
+        let (fields, end) = read_fields(data, 12);
+        assert_eq!(CIPHER_AES256, field(&fields, HEADER_CIPHER_ID));
+        assert_eq!(&Sha256::digest(&data[..end])[..], &data[end..end + 32]);
+
+        let kdf = field(&fields, HEADER_KDF_PARAMETERS);
+        let salt = kdf
+            .windows(42)
+            .find(|v| v[..6] == [0x42, 1, 0, 0, 0, b'S'])
+            .unwrap();
+        let transformed_key = transform_key(password, &salt[10..42]).unwrap();
+        let master_seed = field(&fields, HEADER_MASTER_SEED);
+        let hmac_key = Sha512::new()
+            .chain_update(master_seed)
+            .chain_update(&transformed_key)
+            .chain_update([1])
+            .finalize();
+        if block_hmac(&hmac_key, u64::MAX, &[&data[..end]]).unwrap() != data[end + 32..end + 64] {
+            return None;
+        }
+
+        let mut payload = Vec::new();
+        let mut pos = end + 64;
+        for i in 0u64.. {
+            let size = read_u32(data, pos + 32);
+            let block = &data[pos + 36..pos + 36 + size];
+            let hmac = block_hmac(
+                &hmac_key,
+                i,
+                &[&i.to_le_bytes(), &data[pos + 32..pos + 36], block],
+            )
+            .unwrap();
+            assert_eq!(hmac, data[pos..pos + 32]);
+            pos += 36 + size;
+            if size == 0 {
+                break;
+            }
+            payload.extend_from_slice(block);
+        }
+        assert_eq!(data.len(), pos);
+
+        let cipher_key = Sha256::new()
+            .chain_update(master_seed)
+            .chain_update(&transformed_key)
+            .finalize();
+        let iv = field(&fields, HEADER_ENCRYPTION_IV);
+        let payload = cbc::Decryptor::<Aes256>::new(&cipher_key, iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&payload)
+            .unwrap();
+        let (inner, end) = read_fields(&payload, 0);
+        assert_eq!(
+            RANDOM_STREAM_CHACHA20.to_le_bytes(),
+            field(&inner, INNER_HEADER_RANDOM_STREAM_ID)
+        );
+        let hash = Sha512::digest(field(&inner, INNER_HEADER_RANDOM_STREAM_KEY));
+        let mut stream = ChaCha20::new(
+            GenericArray::from_slice(&hash[..32]),
+            GenericArray::from_slice(&hash[32..44]),
+        );
+
+        // protected values are decrypted in document order
+        let xml = String::from_utf8(payload[end..].to_vec()).unwrap();
+        let mut parts = xml.split("<Value Protected=\"True\">");
+        let mut out = parts.next().unwrap().to_string();
+        for part in parts {
+            let (value, rest) = part.split_once('<').unwrap();
+            let mut value = BASE64_STANDARD.decode(value).unwrap();
+            stream.apply_keystream(&mut value);
+            out.push_str("<Value>");
+            out.push_str(&String::from_utf8(value).unwrap());
+            out.push('<');
+            out.push_str(rest);
+        }
+        Some(out)
+    }
+
+    #[test]
+    fn test_write_kdbx() {
+        let entry = Password {
+            id: 1,
+            name: "GitHub <work>".to_string(),
+            kind: EntryType::Login,
+            username: "alice".to_string(),
+            password: "hunter2".to_string(),
+            attachment: Some("note".to_string()),
+            totp: Some("otpauth://totp/GitHub?secret=JBSWY3DPEHPK3PXP".to_string()),
+            fields: vec![
+                CustomField {
+                    name: "PIN".to_string(),
+                    kind: FieldKind::Hidden,
+                    value: "1234".to_string(),
+                },
+                CustomField {
+                    name: "URL".to_string(),
+                    kind: FieldKind::Text,
+                    value: "x".to_string(),
+                },
+            ],
+            payload: None,
+            urls: vec![
+                "https://github.com".to_string(),
+                "https://gist.github.com".to_string(),
+            ],
+            updated_at: 0,
+            created_at: 0,
+        };
+        let data = write_kdbx(&[entry], "secret").unwrap();
+        assert!(read_kdbx(&data, "wrong").is_none());
+
+        let xml = read_kdbx(&data, "secret").unwrap();
+        for s in [
+            "<Key>Title</Key><Value>GitHub &lt;work&gt;</Value>",
+            "<Key>UserName</Key><Value>alice</Value>",
+            "<Key>Password</Key><Value>hunter2</Value>",
+            "<Key>Notes</Key><Value>note</Value>",
+            "<Key>otp</Key><Value>otpauth://totp/GitHub?secret=JBSWY3DPEHPK3PXP</Value>",
+            "<Key>URL</Key><Value>https://github.com</Value>",
+            "<Key>KP2A_URL_1</Key><Value>https://gist.github.com</Value>",
+            "<Key>PIN</Key><Value>1234</Value>",
+            "<Key>URL (2)</Key><Value>x</Value>",
+        ] {
+            assert!(xml.contains(s), "{}", s);
+        }
+    }
+}
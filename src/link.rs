@@ -0,0 +1,148 @@
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use conerror::conerror;
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::encryption::{Aes256GcmEncryptor, Encryptor};
+use crate::error::msg;
+use crate::password::PasswordManager;
+use crate::user::User;
+use crate::util::{fill_bytes, timestamp};
+
+const MAX_LINK_LIFETIME: i64 = 30 * 86400;
+
+const MAX_LINK_VIEWS: i64 = 100;
+
+/// Content of a share link, encrypted as JSON.
+#[derive(Serialize)]
+struct LinkSecret {
+    name: String,
+    username: String,
+    password: String,
+    urls: Vec<String>,
+}
+
+/// A link that was opened, see `ShareLinkManager::open_link`.
+pub struct OpenedLink {
+    pub user_id: i64,
+    pub password_id: i64,
+    pub data: Vec<u8>,
+}
+
+pub struct ShareLink {
+    pub id: String,
+    /// Base64 encoded key, only given to the creator and never stored.
+    pub key: String,
+}
+
+/// Links that reveal a single entry to anyone holding them, a limited number of times.
+///
+/// The entry is encrypted with AES-256-GCM under a random key that is put in the fragment
+/// of the link, so the server only ever stores and serves ciphertext. The ciphertext is
+/// followed by the 12 byte nonce.
+#[derive(Clone)]
+pub struct ShareLinkManager {
+    db: SqlitePool,
+    password_manager: PasswordManager,
+}
+
+impl ShareLinkManager {
+    pub fn new(db: SqlitePool, password_manager: PasswordManager) -> Self {
+        Self {
+            db,
+            password_manager,
+        }
+    }
+
+    #[conerror]
+    pub async fn create_link(
+        &self,
+        user: &User,
+        password_id: i64,
+        expires_in: i64,
+        max_views: i64,
+    ) -> conerror::Result<ShareLink> {
+        if !(1..=MAX_LINK_LIFETIME).contains(&expires_in)
+            || !(1..=MAX_LINK_VIEWS).contains(&max_views)
+        {
+            return Err(msg("参数错误"));
+        }
+        let password = match self
+            .password_manager
+            .view_password(user, password_id)
+            .await?
+        {
+            Some(v) => v,
+            None => return Err(msg("密码不存在")),
+        };
+        let secret = LinkSecret {
+            name: password.name,
+            username: password.username,
+            password: password.password,
+            urls: password.urls,
+        };
+
+        let mut key = [0u8; 32];
+        fill_bytes(&mut key);
+        let data = Aes256GcmEncryptor.encrypt(&serde_json::to_vec(&secret)?, &key)?;
+        let mut id = [0u8; 16];
+        fill_bytes(&mut id);
+        let id: String = id.iter().map(|v| format!("{:02x}", v)).collect();
+        let now = timestamp();
+        insert!("share_link", {
+            "id": &id,
+            "user_id": user.id(),
+            "password_id": password_id,
+            "data": &data,
+            "views_left": max_views,
+            "expires_at": now + expires_in,
+            "created_at": now,
+        })
+        .execute(&self.db)
+        .await?;
+        Ok(ShareLink {
+            id,
+            key: BASE64_URL_SAFE_NO_PAD.encode(key),
+        })
+    }
+
+    /// Returns the ciphertext of a link and counts the view, the link is deleted
+    /// after its last view.
+    #[conerror]
+    pub async fn open_link(&self, id: &str) -> conerror::Result<Option<OpenedLink>> {
+        let row: Option<(i64, i64, Vec<u8>, i64)> = sqlx::query_as(
+            "UPDATE `share_link` SET `views_left` = `views_left` - 1 \
+            WHERE `id` = ? AND `views_left` > 0 AND `expires_at` > ? \
+            RETURNING `user_id`, `password_id`, `data`, `views_left`",
+        )
+        .bind(id)
+        .bind(timestamp())
+        .fetch_optional(&self.db)
+        .await?;
+        let (user_id, password_id, data, views_left) = match row {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if views_left == 0 {
+            delete!("share_link", { "id" = id })
+                .execute(&self.db)
+                .await?;
+        }
+        Ok(Some(OpenedLink {
+            user_id,
+            password_id,
+            data,
+        }))
+    }
+
+    #[conerror]
+    pub async fn delete_expired(&self) -> conerror::Result<u64> {
+        let result =
+            sqlx::query("DELETE FROM `share_link` WHERE `expires_at` <= ? OR `views_left` <= 0")
+                .bind(timestamp())
+                .execute(&self.db)
+                .await?;
+        Ok(result.rows_affected())
+    }
+}
@@ -1,12 +1,19 @@
+use std::collections::HashMap;
 use std::future::Future;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::Duration;
 
 use conerror::conerror;
-use http_body_util::{BodyExt, Full};
-use hyper::body::{Bytes, Incoming};
-use hyper::header::CONTENT_TYPE;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
+use hyper::header::{
+    AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_SECURITY_POLICY,
+    CONTENT_TYPE, REFERRER_POLICY,
+};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
@@ -17,21 +24,59 @@ use serde_json::{to_string, Value};
 use structopt::StructOpt;
 use tokio::net::TcpListener;
 
+use crate::audit::{AuditManager, Event, TARGET_FILE, TARGET_PASSWORD};
+use crate::cli::Command;
+use crate::client::ClientInfo;
 use crate::db::setup_db;
+use crate::emergency::EmergencyManager;
 use crate::encryption::{Aes256GcmEncryptor, EncryptionManager};
+use crate::file::{FileManager, FileReader};
+use crate::folder::FolderManager;
+use crate::link::ShareLinkManager;
 use crate::password::PasswordManager;
+use crate::ratelimit::{LoginLimiter, LoginLimits};
+use crate::rekey::KeyRotationManager;
 use crate::service::methods;
-use crate::user::UserManager;
+use crate::share::ShareManager;
+use crate::shared::SharedVaultManager;
+use crate::tag::TagManager;
+use crate::user::{TokenLifetime, User, UserManager};
+use crate::util::timestamp;
+use crate::vault::VaultManager;
+use crate::webauthn::{RelyingParty, WebauthnManager};
 
 #[macro_use]
 mod query;
+mod audit;
+mod cli;
+mod client;
 mod db;
+mod emergency;
 mod encryption;
+mod entry;
 mod error;
+mod field;
+mod file;
+mod folder;
+mod generator;
+mod importer;
+mod kdbx;
+mod link;
 mod password;
+mod ratelimit;
+mod rekey;
 mod service;
+mod share;
+mod shared;
+mod site;
+mod tag;
+mod totp;
 mod user;
 mod util;
+mod vault;
+mod webauthn;
+
+static SHARE_LINK_PAGE: &str = include_str!("link.html");
 
 #[cfg(not(debug_assertions))]
 #[derive(rust_embed::RustEmbed)]
@@ -41,20 +86,100 @@ struct Asset;
 #[derive(StructOpt, Clone)]
 struct Opt {
     #[structopt(long)]
-    bind: String,
+    bind: Option<String>,
 
     #[structopt(long)]
     data_dir: String,
 
     #[structopt(long)]
     allow_create_user: bool,
+
+    /// Days after which entries in the trash are permanently deleted
+    #[structopt(long, default_value = "30")]
+    trash_retention_days: i64,
+
+    /// Days after which audit log events are deleted
+    #[structopt(long, default_value = "365")]
+    audit_retention_days: i64,
+
+    /// Maximum size in MiB of a file attached to an entry
+    #[structopt(long, default_value = "20")]
+    max_file_size: u64,
+
+    /// Seconds after which an unused token expires
+    #[structopt(long, default_value = "300")]
+    token_idle_timeout: i64,
+
+    /// Seconds after login after which a token expires, even if it's in use or refreshed
+    #[structopt(long, default_value = "604800")]
+    token_max_age: i64,
+
+    /// Failed logins allowed in a row from one IP address before it's locked out
+    #[structopt(long, default_value = "20")]
+    login_ip_limit: i64,
+
+    /// Failed logins allowed in a row for one username before it's locked out
+    #[structopt(long, default_value = "5")]
+    login_username_limit: i64,
+
+    /// Seconds after which one more failed login is allowed
+    #[structopt(long, default_value = "60")]
+    login_refill_interval: i64,
+
+    /// Seconds of the first lockout, doubled for each following one
+    #[structopt(long, default_value = "300")]
+    login_lockout: i64,
+
+    /// Maximum seconds of a lockout
+    #[structopt(long, default_value = "86400")]
+    login_max_lockout: i64,
+
+    /// Users who can manage lockouts, can be given more than once
+    #[structopt(long, number_of_values = 1)]
+    admin: Vec<String>,
+
+    /// Address of a reverse proxy whose X-Forwarded-For header is trusted to
+    /// tell the client address, can be given more than once
+    #[structopt(long, number_of_values = 1)]
+    trusted_proxy: Vec<IpAddr>,
+
+    /// URL the server is reachable at, used to build share links and
+    /// as the relying party of security keys
+    #[structopt(long)]
+    public_url: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+impl Opt {
+    fn token_lifetime(&self) -> TokenLifetime {
+        TokenLifetime {
+            idle_timeout: self.token_idle_timeout,
+            max_age: self.token_max_age,
+        }
+    }
+
+    fn login_limits(&self) -> LoginLimits {
+        LoginLimits {
+            ip_capacity: self.login_ip_limit,
+            username_capacity: self.login_username_limit,
+            refill_interval: self.login_refill_interval,
+            lockout: self.login_lockout,
+            max_lockout: self.login_max_lockout,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
     init_logger();
     let opt = Opt::from_args();
-    if let Err(err) = run(&opt).await {
+    let result = match &opt.command {
+        Some(command) => cli::run(&opt, command).await,
+        None => run(&opt).await,
+    };
+    if let Err(err) = result {
         error!("{}", err);
         return ExitCode::FAILURE;
     }
@@ -63,37 +188,201 @@ async fn main() -> ExitCode {
 
 #[conerror]
 async fn run(opt: &Opt) -> conerror::Result<()> {
+    let bind = match &opt.bind {
+        Some(v) => v,
+        None => return Err(conerror::Error::plain("--bind is required")),
+    };
     let db = setup_db(&opt.data_dir).await?;
     let encryption = EncryptionManager::new(vec![Box::new(Aes256GcmEncryptor)]);
 
+    let webauthn_manager = WebauthnManager::new(
+        db.clone(),
+        opt.public_url.as_deref().and_then(RelyingParty::from_url),
+    );
+    let login_limiter = LoginLimiter::new(db.clone(), opt.login_limits());
+    login_limiter.restore().await?;
+    let user_manager = UserManager::new(
+        db.clone(),
+        encryption.clone(),
+        webauthn_manager.clone(),
+        opt.token_lifetime(),
+        login_limiter.clone(),
+    );
+    let file_manager = FileManager::new(
+        db.clone(),
+        encryption.clone(),
+        &opt.data_dir,
+        opt.max_file_size * 1024 * 1024,
+    )
+    .await?;
+
     let mut registry = Registry::new();
     registry.provide(db.clone());
     registry.provide(opt.clone());
-    registry.provide(UserManager::new(db.clone(), encryption.clone()));
-    registry.provide(PasswordManager::new(db, encryption));
+    registry.provide(user_manager.clone());
+    spawn_token_janitor(user_manager.clone());
+    registry.provide(webauthn_manager);
+    registry.provide(login_limiter);
+    registry.provide(file_manager.clone());
+    registry.provide(FolderManager::new(db.clone()));
+    registry.provide(TagManager::new(db.clone()));
+    let shared_vault_manager =
+        SharedVaultManager::new(db.clone(), encryption.clone(), user_manager.clone());
+    registry.provide(shared_vault_manager.clone());
+    let password_manager = PasswordManager::new(db.clone(), encryption.clone());
+    registry.provide(ShareManager::new(
+        db.clone(),
+        encryption.clone(),
+        user_manager.clone(),
+        password_manager.clone(),
+    ));
+    let emergency_manager = EmergencyManager::new(
+        db.clone(),
+        encryption.clone(),
+        user_manager.clone(),
+        password_manager.clone(),
+    );
+    registry.provide(emergency_manager.clone());
+    registry.provide(KeyRotationManager::new(
+        db.clone(),
+        user_manager.clone(),
+        password_manager.clone(),
+        file_manager.clone(),
+        shared_vault_manager,
+        emergency_manager.clone(),
+    ));
+    spawn_emergency_timer(emergency_manager);
+    registry.provide(VaultManager::new(
+        db.clone(),
+        password_manager.clone(),
+        encryption,
+    ));
+    let share_link_manager = ShareLinkManager::new(db.clone(), password_manager.clone());
+    registry.provide(share_link_manager.clone());
+    let audit_manager = AuditManager::new(db.clone());
+    registry.provide(audit_manager.clone());
+    spawn_trash_purger(
+        password_manager.clone(),
+        file_manager.clone(),
+        share_link_manager.clone(),
+        audit_manager.clone(),
+        opt.trash_retention_days,
+        opt.audit_retention_days,
+    );
+    registry.provide(password_manager);
     registry.register(methods());
-    registry.post_call(log_error);
+    registry.post_call(post_call);
 
     let registry = Arc::new(registry);
-    serve_http(&opt.bind, move |req| {
+    let trusted_proxies: Arc<[IpAddr]> = opt.trusted_proxy.clone().into();
+    serve_http(bind, move |req| {
         let registry = registry.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        let audit_manager = audit_manager.clone();
+        let user_manager = user_manager.clone();
+        let file_manager = file_manager.clone();
+        let share_link_manager = share_link_manager.clone();
         async move {
-            match (req.method(), req.uri().path()) {
-                (&Method::POST, "/rpc") => handle_rpc(&registry, req).await,
-                #[cfg(not(debug_assertions))]
-                (&Method::GET, path) => handle_static(path).await,
-                _ => Ok(not_found()),
-            }
+            let client = ClientInfo::from_request(&req, &trusted_proxies);
+            let response = async {
+                match (req.method(), req.uri().path()) {
+                    (&Method::POST, "/rpc") => handle_rpc(&registry, &audit_manager, req).await,
+                    (&Method::POST, "/file") => {
+                        handle_upload(&user_manager, &file_manager, req).await
+                    }
+                    (&Method::GET, "/file") => {
+                        handle_download(&user_manager, &file_manager, &audit_manager, req).await
+                    }
+                    (&Method::GET, path) if path.starts_with("/s/") => Ok(share_link_page()),
+                    (&Method::POST, path) if path.starts_with("/s/") => {
+                        handle_share_link(&share_link_manager, &audit_manager, &path[3..]).await
+                    }
+                    #[cfg(not(debug_assertions))]
+                    (&Method::GET, path) => handle_static(path).await,
+                    _ => Ok(not_found()),
+                }
+            };
+            client.scope(response).await
         }
     })
     .await?;
     Ok(())
 }
 
+fn spawn_trash_purger(
+    password_manager: PasswordManager,
+    file_manager: FileManager,
+    share_link_manager: ShareLinkManager,
+    audit_manager: AuditManager,
+    retention_days: i64,
+    audit_retention_days: i64,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let deleted_before = timestamp() - retention_days * 86400;
+            match password_manager.purge_expired_trash(deleted_before).await {
+                Ok(0) => {}
+                Ok(n) => info!("purged {} entries from trash", n),
+                Err(err) => error!("error purge trash: {}", err),
+            }
+            match file_manager.collect_garbage().await {
+                Ok(0) => {}
+                Ok(n) => info!("removed {} unreferenced files", n),
+                Err(err) => error!("error collect garbage files: {}", err),
+            }
+            match share_link_manager.delete_expired().await {
+                Ok(0) => {}
+                Ok(n) => info!("deleted {} expired share links", n),
+                Err(err) => error!("error delete expired share links: {}", err),
+            }
+            match audit_manager
+                .purge(timestamp() - audit_retention_days * 86400)
+                .await
+            {
+                Ok(0) => {}
+                Ok(n) => info!("purged {} audit log events", n),
+                Err(err) => error!("error purge audit log: {}", err),
+            }
+        }
+    });
+}
+
+/// Deletes expired tokens, which are otherwise only rejected when used.
+fn spawn_token_janitor(user_manager: UserManager) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(600));
+        loop {
+            interval.tick().await;
+            match user_manager.delete_expired_tokens().await {
+                Ok(0) => {}
+                Ok(n) => info!("deleted {} expired tokens", n),
+                Err(err) => error!("error delete expired tokens: {}", err),
+            }
+        }
+    });
+}
+
+/// Grants emergency access requests once their waiting period has passed.
+fn spawn_emergency_timer(emergency_manager: EmergencyManager) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            match emergency_manager.grant_due_requests().await {
+                Ok(0) => {}
+                Ok(n) => info!("granted {} emergency access requests", n),
+                Err(err) => error!("error grant emergency access: {}", err),
+            }
+        }
+    });
+}
+
 #[conerror]
 pub async fn serve_http<F, H>(addr: &str, handler: H) -> conerror::Result<()>
 where
-    F: Future<Output = hyper::Result<Response<Full<Bytes>>>> + Send,
+    F: Future<Output = hyper::Result<Response<Body>>> + Send,
     H: Fn(Request<Incoming>) -> F + Send + Clone + 'static,
 {
     let listener = TcpListener::bind(addr).await?;
@@ -101,14 +390,15 @@ where
     info!("server started at {}", addr);
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = listener.accept().await?;
         let io = TokioIo::new(stream);
         let handler = handler.clone();
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(peer);
+            handler(req)
+        });
         tokio::spawn(async move {
-            if let Err(err) = http1_builder()
-                .serve_connection(io, service_fn(handler))
-                .await
-            {
+            if let Err(err) = http1_builder().serve_connection(io, service).await {
                 error!("error serve connection: {}", err);
             }
         });
@@ -121,28 +411,258 @@ fn http1_builder() -> http1::Builder {
     builder
 }
 
+type Body = BoxBody<Bytes, std::io::Error>;
+
+fn full<T: Into<Bytes>>(data: T) -> Body {
+    Full::new(data.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
 async fn handle_rpc(
     registry: &Registry,
+    audit_manager: &AuditManager,
     req: Request<Incoming>,
-) -> hyper::Result<Response<Full<Bytes>>> {
-    match registry
-        .handle(&req.into_body().collect().await?.to_bytes())
-        .await
-    {
-        Some(v) => {
-            let response = to_string(&v).unwrap();
-            let mut response = Response::new(Full::new(Bytes::from(response)));
+) -> hyper::Result<Response<Body>> {
+    let body = req.into_body().collect().await?.to_bytes();
+    match handle_calls(registry, audit_manager, &body).await {
+        Some(response) => {
+            let mut response = Response::new(full(response));
             response
                 .headers_mut()
                 .insert(CONTENT_TYPE, "application/json".parse().unwrap());
             Ok(response)
         }
-        None => Ok(Response::default()),
+        None => Ok(Response::new(full(Bytes::new()))),
     }
 }
 
+/// Handles the calls of a batch one at a time, each in its own audit scope, so every
+/// call is recorded with the user it acted as. Returns the serialized response.
+async fn handle_calls(
+    registry: &Registry,
+    audit_manager: &AuditManager,
+    body: &[u8],
+) -> Option<String> {
+    let calls = match serde_json::from_slice::<Vec<Value>>(body) {
+        Ok(v) if !v.is_empty() => v,
+        // not a batch, or an empty one which the registry rejects
+        _ => {
+            let response = audit_manager.scope(registry.handle(body)).await?;
+            return Some(to_string(&response).unwrap());
+        }
+    };
+    let mut list = Vec::with_capacity(calls.len());
+    for call in calls {
+        let body = serde_json::to_vec(&call).unwrap();
+        if let Some(v) = audit_manager.scope(registry.handle(&body)).await {
+            list.push(v);
+        }
+    }
+    // a batch of notifications gets no response
+    if list.is_empty() {
+        return None;
+    }
+    Some(to_string(&list).unwrap())
+}
+
+/// Uploads the request body as a file of an entry,
+/// `POST /file?password_id=<id>&name=<file name>` with `Authorization: Bearer <token>`.
+async fn handle_upload(
+    user_manager: &UserManager,
+    file_manager: &FileManager,
+    req: Request<Incoming>,
+) -> hyper::Result<Response<Body>> {
+    let user = match authorize(user_manager, &req).await {
+        Some(v) => v,
+        None => return Ok(status(StatusCode::UNAUTHORIZED)),
+    };
+    let query = query(&req);
+    let password_id = query.get("password_id").and_then(|v| v.parse().ok());
+    let (password_id, name) = match (password_id, query.get("name")) {
+        (Some(id), Some(name)) => (id, name),
+        _ => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    let mut writer = match file_manager.create_file(&user, password_id, name).await {
+        Ok(v) => v,
+        Err(err) => return Ok(error_response(err)),
+    };
+
+    let mut body = req.into_body();
+    while let Some(frame) = body.frame().await {
+        let result = match frame {
+            Ok(frame) => match frame.into_data() {
+                Ok(data) => writer.write(&data).await,
+                Err(_) => continue,
+            },
+            Err(err) => Err(conerror::Error::plain(err)),
+        };
+        if let Err(err) = result {
+            if let Err(err) = writer.abort().await {
+                error!("error abort upload: {}", err);
+            }
+            return Ok(error_response(err));
+        }
+    }
+    match writer.finish().await {
+        Ok(info) => {
+            let mut response = Response::new(full(to_string(&info).unwrap()));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+            Ok(response)
+        }
+        Err(err) => Ok(error_response(err)),
+    }
+}
+
+/// Streams a decrypted file, `GET /file?id=<id>` with `Authorization: Bearer <token>`.
+async fn handle_download(
+    user_manager: &UserManager,
+    file_manager: &FileManager,
+    audit_manager: &AuditManager,
+    req: Request<Incoming>,
+) -> hyper::Result<Response<Body>> {
+    let user = match authorize(user_manager, &req).await {
+        Some(v) => v,
+        None => return Ok(status(StatusCode::UNAUTHORIZED)),
+    };
+    let id = match query(&req).get("id").and_then(|v| v.parse().ok()) {
+        Some(v) => v,
+        None => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    let result = file_manager.open_file(&user, id).await;
+    audit_manager
+        .record_request(Event {
+            user_id: Some(user.id()),
+            affected_user_id: None,
+            method: "file.download",
+            target: Some((TARGET_FILE, id)),
+            success: matches!(result, Ok(Some(_))),
+        })
+        .await;
+    let (info, reader) = match result {
+        Ok(Some(v)) => v,
+        Ok(None) => return Ok(not_found()),
+        Err(err) => return Ok(error_response(err)),
+    };
+
+    let stream = futures_util::stream::try_unfold(reader, |mut reader: FileReader| async move {
+        match reader.next_chunk().await {
+            Ok(Some(data)) => Ok(Some((Frame::data(Bytes::from(data)), reader))),
+            Ok(None) => Ok(None),
+            Err(err) => {
+                error!("error read file: {}", err);
+                Err(std::io::Error::other(err.to_string()))
+            }
+        }
+    });
+    let mut response = Response::new(BodyExt::boxed(StreamBody::new(stream)));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+    headers.insert(CONTENT_LENGTH, info.size().into());
+    headers.insert(
+        CONTENT_DISPOSITION,
+        format!(
+            "attachment; filename*=UTF-8''{}",
+            percent_encode(info.name())
+        )
+        .parse()
+        .unwrap(),
+    );
+    Ok(response)
+}
+
+/// Page of a share link, `GET /s/<id>`. It decrypts the entry in the browser with the key
+/// in the fragment, link previews fetching the page don't use up a view.
+fn share_link_page() -> Response<Body> {
+    let mut response = Response::new(full(SHARE_LINK_PAGE));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+    headers.insert(CACHE_CONTROL, "no-store".parse().unwrap());
+    headers.insert(
+        CONTENT_SECURITY_POLICY,
+        "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self'"
+            .parse()
+            .unwrap(),
+    );
+    headers.insert(REFERRER_POLICY, "no-referrer".parse().unwrap());
+    response
+}
+
+/// Serves the ciphertext of a share link, `POST /s/<id>`. Each request uses up a view.
+async fn handle_share_link(
+    share_link_manager: &ShareLinkManager,
+    audit_manager: &AuditManager,
+    id: &str,
+) -> hyper::Result<Response<Body>> {
+    let result = share_link_manager.open_link(id).await;
+    // the viewer is anonymous, the view shows up in the log of the owner
+    let link = result.as_ref().ok().and_then(Option::as_ref);
+    audit_manager
+        .record_request(Event {
+            user_id: None,
+            affected_user_id: link.map(|v| v.user_id),
+            method: "share_link.view",
+            target: link.map(|v| (TARGET_PASSWORD, v.password_id)),
+            success: link.is_some(),
+        })
+        .await;
+    let link = match result {
+        Ok(Some(v)) => v,
+        Ok(None) => return Ok(not_found()),
+        Err(err) => return Ok(error_response(err)),
+    };
+    let mut response = Response::new(full(link.data));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+    headers.insert(CACHE_CONTROL, "no-store".parse().unwrap());
+    Ok(response)
+}
+
+async fn authorize(user_manager: &UserManager, req: &Request<Incoming>) -> Option<User> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    user_manager.find_user(token).await.ok()
+}
+
+fn query(req: &Request<Incoming>) -> HashMap<String, String> {
+    url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Responds with the message of a user facing error, or 500 for other errors.
+fn error_response(err: conerror::Error) -> Response<Body> {
+    let err: BoxError = err.into();
+    if let Some(e) = rustic_jsonrpc::Error::cast(&*err) {
+        let mut response = Response::new(full(e.message.clone()));
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return response;
+    }
+    error!("{}", err);
+    status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
 #[cfg(not(debug_assertions))]
-async fn handle_static(path: &str) -> hyper::Result<Response<Full<Bytes>>> {
+async fn handle_static(path: &str) -> hyper::Result<Response<Body>> {
     let mut path = path.trim_start_matches('/');
     if path == "" {
         path = "index.html"
@@ -150,8 +670,8 @@ async fn handle_static(path: &str) -> hyper::Result<Response<Full<Bytes>>> {
     match Asset::get(path) {
         Some(file) => {
             let mut response = match file.data {
-                std::borrow::Cow::Borrowed(data) => Response::new(Full::new(Bytes::from(data))),
-                std::borrow::Cow::Owned(data) => Response::new(Full::new(Bytes::from(data))),
+                std::borrow::Cow::Borrowed(data) => Response::new(full(data)),
+                std::borrow::Cow::Owned(data) => Response::new(full(data)),
             };
             response
                 .headers_mut()
@@ -162,6 +682,17 @@ async fn handle_static(path: &str) -> hyper::Result<Response<Full<Bytes>>> {
     }
 }
 
+/// Logs unexpected errors and records the call in the audit log.
+fn post_call<'a>(
+    req: &'a rustic_jsonrpc::Request<'a>,
+    result: &'a Result<Value, BoxError>,
+) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+    Box::pin(async move {
+        log_error(req, result).await;
+        audit::record(req, result).await;
+    })
+}
+
 fn log_error<'a>(
     req: &'a rustic_jsonrpc::Request<'a>,
     result: &'a Result<Value, BoxError>,
@@ -174,9 +705,13 @@ fn log_error<'a>(
     Box::pin(async {})
 }
 
-fn not_found() -> Response<Full<Bytes>> {
-    let mut r = Response::default();
-    *r.status_mut() = StatusCode::NOT_FOUND;
+fn not_found() -> Response<Body> {
+    status(StatusCode::NOT_FOUND)
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut r = Response::new(full(Bytes::new()));
+    *r.status_mut() = status;
     r
 }
 
@@ -1,9 +1,23 @@
-use conerror::conerror;
-use serde::Serialize;
-use sqlx::{FromRow, SqlitePool};
+use std::collections::HashMap;
+use std::str::FromStr;
 
+use conerror::{conerror, Error};
+use futures_util::stream::BoxStream;
+use futures_util::TryStreamExt;
+use hmac::{Hmac, Mac};
+use regex::RegexBuilder;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{FromRow, QueryBuilder, Sqlite, SqlitePool, Transaction};
+
+use crate::audit::{self, TARGET_PASSWORD};
 use crate::encryption::EncryptionManager;
-use crate::user::User;
+use crate::entry::{EntryPayload, EntryType};
+use crate::error::msg;
+use crate::field::{CustomField, FieldKind};
+use crate::site::{parse_url, registrable_domain};
+use crate::totp::{Totp, TotpCode};
+use crate::user::{lock_credential, User};
 use crate::util::timestamp;
 
 pub struct PasswordCreate<'a> {
@@ -11,26 +25,216 @@ pub struct PasswordCreate<'a> {
     pub username: &'a str,
     pub password: &'a str,
     pub attachment: Option<&'a str>,
+    pub totp: Option<&'a str>,
+    pub fields: &'a [CustomField],
+    /// Content of entries other than logins.
+    pub payload: Option<&'a EntryPayload>,
+    pub urls: &'a [String],
 }
 
-pub type PasswordUpdate<'a> = PasswordCreate<'a>;
+/// Changes to an entry.
+pub struct PasswordUpdate<'a> {
+    pub name: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+    pub attachment: Option<&'a str>,
+    /// `None` keeps the current secret, an empty string removes it.
+    pub totp: Option<&'a str>,
+    /// `None` keeps the current fields.
+    pub fields: Option<&'a [CustomField]>,
+    /// `None` keeps the current content and type, `Some(None)` makes the entry a login.
+    pub payload: Option<Option<&'a EntryPayload>>,
+    /// `None` keeps the current URLs.
+    pub urls: Option<&'a [String]>,
+}
 
-#[derive(FromRow, Serialize)]
+impl<'a> From<PasswordCreate<'a>> for PasswordUpdate<'a> {
+    /// Replaces everything in the entry.
+    fn from(create: PasswordCreate<'a>) -> Self {
+        Self {
+            name: create.name,
+            username: create.username,
+            password: create.password,
+            attachment: create.attachment,
+            totp: Some(create.totp.unwrap_or_default()),
+            fields: Some(create.fields),
+            payload: Some(create.payload),
+            urls: Some(create.urls),
+        }
+    }
+}
+
+const NOT_DELETED: Option<i64> = None;
+
+/// Child tables of `password` whose rows are copied with `history_id` set when an entry
+/// is archived, with the columns that are copied besides the ids.
+pub const VERSIONED_TABLES: &[(&str, &str)] = &[
+    ("password_field", "`position`,`kind`,`name`,`value`"),
+    ("password_url", "`position`,`url`,`domain_hash`"),
+];
+
+/// Separator of the tag names aggregated by `list_password`.
+const TAG_SEPARATOR: char = '\u{1f}';
+
+#[derive(Default)]
+pub struct PasswordFilter<'a> {
+    /// Only entries in this folder or one of its subfolders.
+    pub folder_id: Option<i64>,
+    pub tag: Option<&'a str>,
+    /// Only entries whose name contains this string.
+    pub name: Option<&'a str>,
+    pub sort: PasswordSort,
+    /// Zero-based page index, ignored if `page_size` is `None`.
+    pub page: i64,
+    pub page_size: Option<i64>,
+    /// Only entries with one of these ids.
+    pub ids: Option<&'a [i64]>,
+}
+
+/// How `lookup_by_url` compares the URL of a page with the URLs of entries.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UrlMatch {
+    /// Same registrable domain, e.g. `git.example.com` matches `example.com`.
+    #[default]
+    Domain,
+    /// Same host and port.
+    Host,
+    /// Same URL after normalization.
+    Exact,
+    /// The given URL is a regular expression matched against the URLs of entries.
+    Regex,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordSort {
+    #[default]
+    UpdatedDesc,
+    UpdatedAsc,
+    NameAsc,
+    NameDesc,
+    CreatedDesc,
+    CreatedAsc,
+}
+
+impl PasswordSort {
+    fn clause(self) -> &'static str {
+        match self {
+            PasswordSort::UpdatedDesc => " ORDER BY `updated_at` DESC",
+            PasswordSort::UpdatedAsc => " ORDER BY `updated_at` ASC",
+            PasswordSort::NameAsc => " ORDER BY `name` ASC",
+            PasswordSort::NameDesc => " ORDER BY `name` DESC",
+            PasswordSort::CreatedDesc => " ORDER BY `created_at` DESC",
+            PasswordSort::CreatedAsc => " ORDER BY `created_at` ASC",
+        }
+    }
+}
+
+#[derive(Serialize)]
 pub struct PasswordListItem {
     id: i64,
     name: String,
+    #[serde(rename = "type")]
+    kind: EntryType,
+    folder_id: Option<i64>,
+    tags: Vec<String>,
+    updated_at: i64,
+}
+
+#[derive(FromRow)]
+struct PasswordListRow {
+    id: i64,
+    name: String,
+    #[sqlx(rename = "type")]
+    kind: String,
+    folder_id: Option<i64>,
+    tags: Option<String>,
     updated_at: i64,
 }
 
 #[derive(Serialize)]
 pub struct Password {
+    pub id: i32,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: EntryType,
+    pub username: String,
+    pub password: String,
+    pub attachment: Option<String>,
+    pub totp: Option<String>,
+    pub fields: Vec<CustomField>,
+    pub payload: Option<EntryPayload>,
+    pub urls: Vec<String>,
+    pub updated_at: i64,
+    pub created_at: i64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct TrashItem {
+    id: i64,
+    name: String,
+    deleted_at: i64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct PasswordVersion {
+    id: i64,
+    name: String,
+    updated_at: i64,
+    created_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    id: i64,
+    name: String,
+    /// Fields that matched the query.
+    fields: Vec<&'static str>,
+}
+
+#[derive(FromRow)]
+struct SearchRow {
+    id: i64,
+    name: String,
+    username: Vec<u8>,
+    attachment: Option<Vec<u8>>,
+}
+
+#[derive(FromRow)]
+struct FieldRow {
+    password_id: i64,
+    kind: String,
+    name: Vec<u8>,
+    value: Vec<u8>,
+}
+
+#[derive(FromRow)]
+struct RekeyRow {
+    id: i64,
+    username: Vec<u8>,
+    password: Vec<u8>,
+    attachment: Option<Vec<u8>>,
+    totp: Option<Vec<u8>>,
+    payload: Option<Vec<u8>>,
+}
+
+#[derive(FromRow)]
+struct PasswordRow {
     id: i32,
     name: String,
-    username: String,
-    password: String,
-    attachment: Option<String>,
+    #[sqlx(rename = "type")]
+    kind: String,
+    username: Vec<u8>,
+    password: Vec<u8>,
+    attachment: Option<Vec<u8>>,
+    totp: Option<Vec<u8>>,
+    payload: Option<Vec<u8>>,
+    updated_at: i64,
+    created_at: i64,
 }
 
+#[derive(Clone)]
 pub struct PasswordManager {
     db: SqlitePool,
     encryption: EncryptionManager,
@@ -42,75 +246,320 @@ impl PasswordManager {
     }
 
     #[conerror]
-    pub async fn list_password(&self, user: &User) -> conerror::Result<Vec<PasswordListItem>> {
-        let list = select!(
-            "password",
-            ["id", "name", "updated_at"],
-            { "user_id" = user.id() },
-            "ORDER BY updated_at DESC"
-        )
-        .fetch_all(&self.db)
-        .await?;
-        Ok(list)
+    pub async fn list_password(
+        &self,
+        user: &User,
+        filter: &PasswordFilter<'_>,
+    ) -> conerror::Result<Vec<PasswordListItem>> {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "SELECT `id`, `name`, `type`, `folder_id`, `updated_at`, \
+            (SELECT GROUP_CONCAT(`tag`.`name`, char(31)) FROM `password_tag` \
+            JOIN `tag` ON `tag`.`id` = `password_tag`.`tag_id` WHERE `password_tag`.`password_id` = `password`.`id`) AS `tags` \
+            FROM `password` WHERE `deleted_at` IS NULL AND `user_id` = ",
+        );
+        query.push_bind(user.id());
+        if let Some(folder_id) = filter.folder_id {
+            query
+                .push(
+                    " AND `folder_id` IN (WITH RECURSIVE `sub`(`id`) AS (SELECT `id` FROM `folder` WHERE `id` = ",
+                )
+                .push_bind(folder_id)
+                .push(" AND `user_id` = ")
+                .push_bind(user.id())
+                .push(
+                    " UNION ALL SELECT `folder`.`id` FROM `folder` JOIN `sub` ON `folder`.`parent_id` = `sub`.`id`) \
+                    SELECT `id` FROM `sub`)",
+                );
+        }
+        if let Some(tag) = filter.tag {
+            query
+                .push(
+                    " AND EXISTS (SELECT 1 FROM `password_tag` JOIN `tag` ON `tag`.`id` = `password_tag`.`tag_id` \
+                    WHERE `password_tag`.`password_id` = `password`.`id` AND `tag`.`name` = ",
+                )
+                .push_bind(tag)
+                .push(")");
+        }
+        if let Some(name) = filter.name.filter(|v| !v.is_empty()) {
+            query
+                .push(" AND INSTR(LOWER(`name`), LOWER(")
+                .push_bind(name)
+                .push(")) > 0");
+        }
+        if let Some(ids) = filter.ids {
+            if ids.is_empty() {
+                return Ok(Vec::new());
+            }
+            let mut separated = query.push(" AND `id` IN (").separated(", ");
+            for id in ids {
+                separated.push_bind(*id);
+            }
+            separated.push_unseparated(")");
+        }
+        query.push(filter.sort.clause());
+        if let Some(page_size) = filter.page_size {
+            if page_size <= 0 || filter.page < 0 {
+                return Err(msg("参数错误"));
+            }
+            query
+                .push(" LIMIT ")
+                .push_bind(page_size)
+                .push(" OFFSET ")
+                .push_bind(filter.page * page_size);
+        }
+
+        let rows: Vec<PasswordListRow> = query.build_query_as().fetch_all(&self.db).await?;
+        Ok(rows
+            .into_iter()
+            .map(|v| PasswordListItem {
+                id: v.id,
+                name: v.name,
+                kind: EntryType::from_str(&v.kind).unwrap_or_default(),
+                folder_id: v.folder_id,
+                tags: match v.tags {
+                    Some(tags) => tags.split(TAG_SEPARATOR).map(String::from).collect(),
+                    None => Vec::new(),
+                },
+                updated_at: v.updated_at,
+            })
+            .collect())
     }
 
+    /// Finds entries with a URL matching `url`. Except for `UrlMatch::Regex`, candidates are
+    /// selected by the keyed hash of the registrable domain so only those are decrypted.
     #[conerror]
-    pub async fn view_password(&self, user: &User, id: i64) -> conerror::Result<Option<Password>> {
-        #[derive(FromRow)]
-        struct Row {
-            id: i32,
-            name: String,
-            username: Vec<u8>,
-            password: Vec<u8>,
-            attachment: Option<Vec<u8>>,
+    pub async fn lookup_by_url(
+        &self,
+        user: &User,
+        url: &str,
+        mode: UrlMatch,
+    ) -> conerror::Result<Vec<PasswordListItem>> {
+        let target = match mode {
+            UrlMatch::Regex => None,
+            _ => match parse_url(url) {
+                Some(v) => Some(v),
+                None => return Err(msg("无效的链接")),
+            },
+        };
+        let rows: Vec<(i64, Vec<u8>)> = match &target {
+            None => {
+                sqlx::query_as(
+                    "SELECT `password_id`, `url` FROM `password_url` WHERE `user_id` = ? AND `history_id` IS NULL",
+                )
+                .bind(user.id())
+                .fetch_all(&self.db)
+                .await?
+            }
+            Some(target) => {
+                sqlx::query_as(
+                    "SELECT `password_id`, `url` FROM `password_url` \
+                    WHERE `user_id` = ? AND `domain_hash` = ? AND `history_id` IS NULL",
+                )
+                .bind(user.id())
+                .bind(blind_index(user, "domain", &registrable_domain(target))?)
+                .fetch_all(&self.db)
+                .await?
+            }
+        };
+
+        let mut ids = Vec::new();
+        match (mode, &target) {
+            (UrlMatch::Domain, _) => ids.extend(rows.into_iter().map(|(id, _)| id)),
+            (UrlMatch::Host | UrlMatch::Exact, Some(target)) => {
+                for (id, value) in rows {
+                    let value = String::from_utf8(self.decrypt(user, &value)?)?;
+                    let matched = match parse_url(&value) {
+                        Some(v) if matches!(mode, UrlMatch::Host) => {
+                            v.host_str() == target.host_str()
+                                && v.port_or_known_default() == target.port_or_known_default()
+                        }
+                        Some(v) => v == *target,
+                        None => false,
+                    };
+                    if matched {
+                        ids.push(id);
+                    }
+                }
+            }
+            _ => {
+                let re = RegexBuilder::new(url)
+                    .size_limit(1 << 20)
+                    .build()
+                    .map_err(|_| msg("无效的正则表达式"))?;
+                for (id, value) in rows {
+                    let value = String::from_utf8(self.decrypt(user, &value)?)?;
+                    if re.is_match(&value) {
+                        ids.push(id);
+                    }
+                }
+            }
         }
-        let password: Option<Row> = select!(
+        ids.sort_unstable();
+        ids.dedup();
+
+        let filter = PasswordFilter {
+            ids: Some(&ids),
+            ..Default::default()
+        };
+        self.list_password(user, &filter).await
+    }
+
+    #[conerror]
+    pub async fn view_password(&self, user: &User, id: i64) -> conerror::Result<Option<Password>> {
+        let password: Option<PasswordRow> = select!(
             "password",
-            ["id", "name", "username", "password", "attachment"],
-            {"id" = id, "user_id" = user.id()}
+            ["id", "name", "type", "username", "password", "attachment", "totp", "payload", "updated_at", "created_at"],
+            {"id" = id, "user_id" = user.id(), "deleted_at" is NOT_DELETED}
         )
         .fetch_optional(&self.db)
         .await?;
 
         match password {
-            Some(password) => Ok(Some(Password {
-                id: password.id,
-                name: password.name,
-                username: String::from_utf8(self.decrypt(user, &password.username)?)?,
-                password: String::from_utf8(self.decrypt(user, &password.password)?)?,
-                attachment: match password.attachment {
-                    Some(v) => Some(String::from_utf8(self.decrypt(user, &v)?)?),
-                    None => None,
-                },
-            })),
+            Some(password) => {
+                let rows: Vec<FieldRow> = sqlx::query_as(
+                    "SELECT `password_id`, `kind`, `name`, `value` FROM `password_field` \
+                    WHERE `password_id` = ? AND `history_id` IS NULL ORDER BY `position`",
+                )
+                .bind(id)
+                .fetch_all(&self.db)
+                .await?;
+                let fields = self.decrypt_fields(user, rows)?.remove(&id);
+                let rows: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+                    "SELECT `password_id`, `url` FROM `password_url` \
+                    WHERE `password_id` = ? AND `history_id` IS NULL ORDER BY `position`",
+                )
+                .bind(id)
+                .fetch_all(&self.db)
+                .await?;
+                let urls = self.decrypt_urls(user, rows)?.remove(&id);
+                Ok(Some(self.decrypt_row(user, password, fields, urls)?))
+            }
             None => Ok(None),
         }
     }
 
+    #[conerror]
+    pub async fn all_password(&self, user: &User) -> conerror::Result<Vec<Password>> {
+        let rows: Vec<PasswordRow> = select!(
+            "password",
+            [
+                "id",
+                "name",
+                "type",
+                "username",
+                "password",
+                "attachment",
+                "totp",
+                "payload",
+                "updated_at",
+                "created_at"
+            ],
+            {"user_id" = user.id(), "deleted_at" is NOT_DELETED},
+            "ORDER BY id"
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let fields: Vec<FieldRow> = sqlx::query_as(
+            "SELECT `password_id`, `kind`, `name`, `value` FROM `password_field` \
+            WHERE `user_id` = ? AND `history_id` IS NULL ORDER BY `password_id`, `position`",
+        )
+        .bind(user.id())
+        .fetch_all(&self.db)
+        .await?;
+        let mut fields = self.decrypt_fields(user, fields)?;
+        let urls: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+            "SELECT `password_id`, `url` FROM `password_url` \
+            WHERE `user_id` = ? AND `history_id` IS NULL ORDER BY `password_id`, `position`",
+        )
+        .bind(user.id())
+        .fetch_all(&self.db)
+        .await?;
+        let mut urls = self.decrypt_urls(user, urls)?;
+
+        let mut list = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id = row.id as i64;
+            list.push(self.decrypt_row(user, row, fields.remove(&id), urls.remove(&id))?);
+        }
+        Ok(list)
+    }
+
+    #[conerror]
+    pub async fn find_password_id(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user: &User,
+        name: &str,
+    ) -> conerror::Result<Option<i64>> {
+        let row: Option<(i64,)> = select!(
+            "password",
+            ["id"],
+            {"user_id" = user.id(), "name" = name, "deleted_at" is NOT_DELETED},
+            "ORDER BY id LIMIT 1"
+        )
+        .fetch_optional(&mut **tx)
+        .await?;
+        Ok(row.map(|v| v.0))
+    }
+
     #[conerror]
     pub async fn create_password(
         &self,
         user: &User,
         create: PasswordCreate<'_>,
     ) -> conerror::Result<()> {
+        let mut tx = self.db.begin().await?;
+        self.insert_password(&mut tx, user, create).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Creates an entry as part of `tx`.
+    #[conerror]
+    pub async fn insert_password(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user: &User,
+        create: PasswordCreate<'_>,
+    ) -> conerror::Result<()> {
+        lock_credential(tx, user).await?;
         let username = self.encrypt(user, create.username.as_bytes())?;
         let password = self.encrypt(user, create.password.as_bytes())?;
         let attachment = match create.attachment {
             Some(v) => Some(self.encrypt(user, v.as_bytes())?),
             _ => None,
         };
+        let totp = match create.totp {
+            Some(v) => {
+                Totp::from_uri(v)?;
+                Some(self.encrypt(user, v.as_bytes())?)
+            }
+            _ => None,
+        };
+        let payload = self.encrypt_payload(user, create.payload)?;
+        let kind = create
+            .payload
+            .map(EntryPayload::entry_type)
+            .unwrap_or_default();
         let now = timestamp();
-        insert!("password", {
+        let id = insert!("password", {
             "user_id": user.id(),
             "name": create.name,
+            "type": kind.as_str(),
             "username": &username,
             "password": &password,
             "attachment": &attachment,
+            "totp": &totp,
+            "payload": &payload,
             "updated_at": now,
             "created_at": now,
         })
-        .execute(&self.db)
-        .await?;
+        .execute(&mut **tx)
+        .await?
+        .last_insert_rowid();
+        self.write_fields(tx, user, id, create.fields).await?;
+        self.write_urls(tx, user, id, create.urls).await?;
         Ok(())
     }
 
@@ -121,27 +570,718 @@ impl PasswordManager {
         id: i64,
         update: PasswordUpdate<'_>,
     ) -> conerror::Result<()> {
+        let mut tx = self.db.begin().await?;
+        if self.write_update(&mut tx, user, id, update).await? {
+            tx.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Updates an entry as part of `tx`, the previous version is kept in the history.
+    /// Returns false if the entry doesn't exist, in which case `tx` must be rolled back.
+    #[conerror]
+    pub async fn write_update(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user: &User,
+        id: i64,
+        update: PasswordUpdate<'_>,
+    ) -> conerror::Result<bool> {
+        lock_credential(tx, user).await?;
         let username = self.encrypt(user, update.username.as_bytes())?;
         let password = self.encrypt(user, update.password.as_bytes())?;
         let attachment = match update.attachment {
             Some(v) => Some(self.encrypt(user, v.as_bytes())?),
             _ => None,
         };
+        let totp = match update.totp {
+            Some("") => Some(None),
+            Some(v) => {
+                Totp::from_uri(v)?;
+                Some(Some(self.encrypt(user, v.as_bytes())?))
+            }
+            None => None,
+        };
+        let payload = match update.payload {
+            Some(v) => Some(self.encrypt_payload(user, v)?),
+            None => None,
+        };
         let now = timestamp();
-        update!("password",
+        self.archive_password(tx, user, id).await?;
+        delete!("password_index", { "password_id" = id })
+            .execute(&mut **tx)
+            .await?;
+        let result = update!("password",
         {"name": update.name, "username": &username, "password": &password, "attachment": &attachment, "updated_at": now},
-        {"id" = id, "user_id" = user.id()}).execute(&self.db).await?;
+        {"id" = id, "user_id" = user.id(), "deleted_at" is NOT_DELETED}).execute(&mut **tx).await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        // values the client didn't send are kept
+        if let Some(totp) = totp {
+            update!("password", {"totp": &totp}, {"id" = id})
+                .execute(&mut **tx)
+                .await?;
+        }
+        if let Some(payload) = payload {
+            let kind = update
+                .payload
+                .flatten()
+                .map(EntryPayload::entry_type)
+                .unwrap_or_default();
+            update!("password", {"type": kind.as_str(), "payload": &payload}, {"id" = id})
+                .execute(&mut **tx)
+                .await?;
+        }
+        if let Some(fields) = update.fields {
+            self.write_fields(tx, user, id, fields).await?;
+        }
+        if let Some(urls) = update.urls {
+            self.write_urls(tx, user, id, urls).await?;
+        }
+        Ok(true)
+    }
+
+    #[conerror]
+    pub async fn list_history(
+        &self,
+        user: &User,
+        id: i64,
+    ) -> conerror::Result<Vec<PasswordVersion>> {
+        let list = select!(
+            "password_history",
+            ["id", "name", "updated_at", "created_at"],
+            {"password_id" = id, "user_id" = user.id()},
+            "ORDER BY id DESC"
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(list)
+    }
+
+    #[conerror]
+    pub async fn restore_history(&self, user: &User, version_id: i64) -> conerror::Result<()> {
+        #[derive(FromRow)]
+        struct Row {
+            password_id: i64,
+            name: String,
+            #[sqlx(rename = "type")]
+            kind: String,
+            username: Vec<u8>,
+            password: Vec<u8>,
+            attachment: Option<Vec<u8>>,
+            totp: Option<Vec<u8>>,
+            payload: Option<Vec<u8>>,
+        }
+        let version: Row = match select!(
+            "password_history",
+            ["password_id", "name", "type", "username", "password", "attachment", "totp", "payload"],
+            {"id" = version_id, "user_id" = user.id()}
+        )
+        .fetch_optional(&self.db)
+        .await?
+        {
+            Some(v) => v,
+            None => return Err(msg("版本不存在")),
+        };
+        audit::set_target(TARGET_PASSWORD, version.password_id);
+
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
+        self.archive_password(&mut tx, user, version.password_id)
+            .await?;
+        delete!("password_index", { "password_id" = version.password_id })
+            .execute(&mut *tx)
+            .await?;
+        let result = update!("password",
+        {"name": &version.name, "type": &version.kind, "username": &version.username, "password": &version.password, "attachment": &version.attachment, "totp": &version.totp, "payload": &version.payload, "updated_at": timestamp()},
+        {"id" = version.password_id, "user_id" = user.id(), "deleted_at" is NOT_DELETED}).execute(&mut *tx).await?;
+        // the entry is in the trash
+        if result.rows_affected() == 0 {
+            return Err(msg("密码不存在"));
+        }
+        for (table, columns) in VERSIONED_TABLES {
+            let sql = format!(
+                "DELETE FROM `{}` WHERE `password_id` = ? AND `history_id` IS NULL",
+                table
+            );
+            sqlx::query(&sql)
+                .bind(version.password_id)
+                .execute(&mut *tx)
+                .await?;
+            let sql = format!(
+                "INSERT INTO `{0}`(`password_id`,`history_id`,`user_id`,{1}) \
+                SELECT `password_id`,NULL,`user_id`,{1} FROM `{0}` WHERE `history_id` = ?",
+                table, columns
+            );
+            sqlx::query(&sql).bind(version_id).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Copies the current version of the entry into `password_history` and drops
+    /// versions beyond the user's retention count.
+    #[conerror]
+    async fn archive_password(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user: &User,
+        id: i64,
+    ) -> conerror::Result<()> {
+        let (retention,): (i64,) = select!("user", ["history_retention"], { "id" = user.id() })
+            .fetch_one(&mut **tx)
+            .await?;
+        if retention > 0 {
+            let result = sqlx::query(
+                "INSERT INTO `password_history`(`password_id`,`user_id`,`name`,`type`,`username`,`password`,`attachment`,`totp`,`payload`,`updated_at`,`created_at`) \
+                SELECT `id`,`user_id`,`name`,`type`,`username`,`password`,`attachment`,`totp`,`payload`,`updated_at`,? FROM `password` WHERE `id` = ? AND `user_id` = ?",
+            )
+            .bind(timestamp())
+            .bind(id)
+            .bind(user.id())
+            .execute(&mut **tx)
+            .await?;
+            if result.rows_affected() > 0 {
+                for (table, columns) in VERSIONED_TABLES {
+                    let sql = format!(
+                        "INSERT INTO `{0}`(`password_id`,`history_id`,`user_id`,{1}) \
+                        SELECT `password_id`,?,`user_id`,{1} FROM `{0}` WHERE `password_id` = ? AND `history_id` IS NULL",
+                        table, columns
+                    );
+                    sqlx::query(&sql)
+                        .bind(result.last_insert_rowid())
+                        .bind(id)
+                        .execute(&mut **tx)
+                        .await?;
+                }
+            }
+        }
+        sqlx::query(
+            "DELETE FROM `password_history` WHERE `password_id` = ? AND `id` NOT IN \
+            (SELECT `id` FROM `password_history` WHERE `password_id` = ? ORDER BY `id` DESC LIMIT ?)",
+        )
+        .bind(id)
+        .bind(id)
+        .bind(retention)
+        .execute(&mut **tx)
+        .await?;
+        for (table, _) in VERSIONED_TABLES {
+            let sql = format!(
+                "DELETE FROM `{}` WHERE `password_id` = ? AND `history_id` IS NOT NULL \
+                AND `history_id` NOT IN (SELECT `id` FROM `password_history` WHERE `password_id` = ?)",
+                table
+            );
+            sqlx::query(&sql)
+                .bind(id)
+                .bind(id)
+                .execute(&mut **tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the current custom fields of an entry.
+    #[conerror]
+    async fn write_fields(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user: &User,
+        id: i64,
+        fields: &[CustomField],
+    ) -> conerror::Result<()> {
+        sqlx::query(
+            "DELETE FROM `password_field` WHERE `password_id` = ? AND `history_id` IS NULL",
+        )
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+        for (i, field) in fields.iter().enumerate() {
+            field.validate()?;
+            insert!("password_field", {
+                "password_id": id,
+                "user_id": user.id(),
+                "position": i as i64,
+                "kind": field.kind.as_str(),
+                "name": &self.encrypt(user, field.name.trim().as_bytes())?,
+                "value": &self.encrypt(user, field.value.as_bytes())?,
+            })
+            .execute(&mut **tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the current URLs of an entry, each stored encrypted together with
+    /// a keyed hash of its registrable domain for `lookup_by_url`.
+    #[conerror]
+    async fn write_urls(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user: &User,
+        id: i64,
+        urls: &[String],
+    ) -> conerror::Result<()> {
+        sqlx::query("DELETE FROM `password_url` WHERE `password_id` = ? AND `history_id` IS NULL")
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+        for (i, url) in urls.iter().enumerate() {
+            let url = match parse_url(url) {
+                Some(v) => v,
+                None => return Err(msg("无效的链接")),
+            };
+            insert!("password_url", {
+                "password_id": id,
+                "user_id": user.id(),
+                "position": i as i64,
+                "url": &self.encrypt(user, url.as_str().as_bytes())?,
+                "domain_hash": &blind_index(user, "domain", &registrable_domain(&url))?,
+            })
+            .execute(&mut **tx)
+            .await?;
+        }
         Ok(())
     }
 
+    #[conerror]
+    pub async fn totp(&self, user: &User, id: i64) -> conerror::Result<Option<TotpCode>> {
+        let totp: Option<(Option<Vec<u8>>,)> =
+            select!("password", ["totp"], {"id" = id, "user_id" = user.id(), "deleted_at" is NOT_DELETED})
+                .fetch_optional(&self.db)
+                .await?;
+        match totp {
+            Some((Some(v),)) => {
+                let totp = Totp::from_uri(&String::from_utf8(self.decrypt(user, &v)?)?)?;
+                Ok(Some(totp.code(timestamp())))
+            }
+            _ => Ok(None),
+        }
+    }
+
     #[conerror]
     pub async fn delete_password(&self, user: &User, id: i64) -> conerror::Result<()> {
-        delete!("password", {"id" = id, "user_id" = user.id()})
+        update!("password", {"deleted_at": timestamp()}, {"id" = id, "user_id" = user.id(), "deleted_at" is NOT_DELETED})
             .execute(&self.db)
             .await?;
         Ok(())
     }
 
+    #[conerror]
+    pub async fn list_trash(&self, user: &User) -> conerror::Result<Vec<TrashItem>> {
+        let list = select!(
+            "password",
+            ["id", "name", "deleted_at"],
+            {"user_id" = user.id(), "deleted_at" > 0},
+            "ORDER BY deleted_at DESC"
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(list)
+    }
+
+    #[conerror]
+    pub async fn restore_trash(&self, user: &User, id: i64) -> conerror::Result<()> {
+        update!("password", {"deleted_at": NOT_DELETED, "updated_at": timestamp()}, {"id" = id, "user_id" = user.id(), "deleted_at" > 0})
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Permanently deletes an entry in the trash, or every entry in the trash if `id` is `None`.
+    #[conerror]
+    pub async fn purge_trash(&self, user: &User, id: Option<i64>) -> conerror::Result<()> {
+        let mut tx = self.db.begin().await?;
+        for table in [
+            "password_history",
+            "password_field",
+            "password_url",
+            "password_file",
+            "password_tag",
+            "password_index",
+            "share_link",
+        ] {
+            let sql = format!(
+                "DELETE FROM `{}` WHERE `password_id` IN \
+                (SELECT `id` FROM `password` WHERE `user_id` = ? AND `deleted_at` > 0 AND (? IS NULL OR `id` = ?))",
+                table
+            );
+            sqlx::query(&sql)
+                .bind(user.id())
+                .bind(id)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(
+            "DELETE FROM `password` WHERE `user_id` = ? AND `deleted_at` > 0 AND (? IS NULL OR `id` = ?)",
+        )
+        .bind(user.id())
+        .bind(id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Permanently deletes entries of all users that were moved to the trash before `deleted_before`.
+    #[conerror]
+    pub async fn purge_expired_trash(&self, deleted_before: i64) -> conerror::Result<u64> {
+        let mut tx = self.db.begin().await?;
+        for table in [
+            "password_history",
+            "password_field",
+            "password_url",
+            "password_file",
+            "password_tag",
+            "password_index",
+            "share_link",
+        ] {
+            let sql = format!(
+                "DELETE FROM `{}` WHERE `password_id` IN \
+                (SELECT `id` FROM `password` WHERE `deleted_at` <= ?)",
+                table
+            );
+            sqlx::query(&sql)
+                .bind(deleted_before)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let count = delete!("password", { "deleted_at" <= deleted_before })
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    /// Searches usernames, attachments, URLs and custom fields of the user's entries,
+    /// hidden fields aren't searched. Substring matches decrypt every entry as it is
+    /// read from the database, `exact` lookups on usernames and URL domains go through
+    /// the blind indexes instead.
+    #[conerror]
+    pub async fn search_password(
+        &self,
+        user: &User,
+        query: &str,
+        exact: bool,
+    ) -> conerror::Result<Vec<SearchHit>> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        if exact {
+            return self.search_index(user, &query).await;
+        }
+
+        // URLs and fields are matched first, hits are listed in the order of the entries
+        let mut matched: HashMap<i64, Vec<&'static str>> = HashMap::new();
+        let mut urls: BoxStream<'_, Result<(i64, Vec<u8>), sqlx::Error>> = sqlx::query_as(
+            "SELECT `password_id`, `url` FROM `password_url` WHERE `user_id` = ? AND `history_id` IS NULL",
+        )
+        .bind(user.id())
+        .fetch(&self.db);
+        while let Some((id, url)) = urls.try_next().await? {
+            let url = String::from_utf8(self.decrypt(user, &url)?)?;
+            let fields = matched.entry(id).or_default();
+            if url.to_lowercase().contains(&query) && !fields.contains(&"urls") {
+                fields.push("urls");
+            }
+        }
+        let mut custom_fields: BoxStream<'_, Result<(i64, Vec<u8>), sqlx::Error>> = sqlx::query_as(
+            "SELECT `password_id`, `value` FROM `password_field` \
+                WHERE `user_id` = ? AND `history_id` IS NULL AND `kind` != ?",
+        )
+        .bind(user.id())
+        .bind(FieldKind::Hidden.as_str())
+        .fetch(&self.db);
+        while let Some((id, value)) = custom_fields.try_next().await? {
+            let value = String::from_utf8(self.decrypt(user, &value)?)?;
+            let fields = matched.entry(id).or_default();
+            if value.to_lowercase().contains(&query) && !fields.contains(&"fields") {
+                fields.push("fields");
+            }
+        }
+
+        let mut list = Vec::new();
+        let mut rows: BoxStream<'_, Result<SearchRow, sqlx::Error>> = select!(
+            "password",
+            ["id", "name", "username", "attachment"],
+            {"user_id" = user.id(), "deleted_at" is NOT_DELETED},
+            "ORDER BY updated_at DESC"
+        )
+        .fetch(&self.db);
+        while let Some(row) = rows.try_next().await? {
+            let mut fields = Vec::new();
+            let username = String::from_utf8(self.decrypt(user, &row.username)?)?;
+            if username.to_lowercase().contains(&query) {
+                fields.push("username");
+            }
+            if let Some(attachment) = row.attachment {
+                let attachment = String::from_utf8(self.decrypt(user, &attachment)?)?;
+                if attachment.to_lowercase().contains(&query) {
+                    fields.push("attachment");
+                }
+            }
+            fields.extend(matched.remove(&row.id).unwrap_or_default());
+            if !fields.is_empty() {
+                list.push(SearchHit {
+                    id: row.id,
+                    name: row.name,
+                    fields,
+                });
+            }
+        }
+        Ok(list)
+    }
+
+    /// Exact lookups of a username, or of the domain of a URL unless the query is an
+    /// email address.
+    #[conerror]
+    async fn search_index(&self, user: &User, query: &str) -> conerror::Result<Vec<SearchHit>> {
+        self.build_index(user).await?;
+        let domain_hash = match parse_url(query) {
+            Some(v) if !query.contains('@') => {
+                Some(blind_index(user, "domain", &registrable_domain(&v))?)
+            }
+            _ => None,
+        };
+        let rows: Vec<(i64, String, String)> = sqlx::query_as(
+            "SELECT `password`.`id`, `password`.`name`, 'username', `password`.`updated_at` FROM `password_index` \
+            JOIN `password` ON `password`.`id` = `password_index`.`password_id` \
+            WHERE `password_index`.`user_id` = ? AND `password_index`.`hash` = ? AND `password`.`deleted_at` IS NULL \
+            UNION \
+            SELECT `password`.`id`, `password`.`name`, 'urls', `password`.`updated_at` FROM `password_url` \
+            JOIN `password` ON `password`.`id` = `password_url`.`password_id` \
+            WHERE `password_url`.`user_id` = ? AND `password_url`.`domain_hash` = ? \
+            AND `password_url`.`history_id` IS NULL AND `password`.`deleted_at` IS NULL \
+            ORDER BY 4 DESC, 1 DESC, 3 DESC",
+        )
+        .bind(user.id())
+        .bind(blind_index(user, "username", query)?)
+        .bind(user.id())
+        .bind(domain_hash)
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut list: Vec<SearchHit> = Vec::new();
+        for (id, name, field) in rows {
+            let field = if field == "username" {
+                "username"
+            } else {
+                "urls"
+            };
+            match list.last_mut() {
+                Some(v) if v.id == id => v.fields.push(field),
+                _ => list.push(SearchHit {
+                    id,
+                    name,
+                    fields: vec![field],
+                }),
+            }
+        }
+        Ok(list)
+    }
+
+    /// Adds blind index entries for the entries that don't have one yet, entries
+    /// drop theirs whenever they are modified.
+    #[conerror]
+    async fn build_index(&self, user: &User) -> conerror::Result<()> {
+        let rows: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+            "SELECT `id`, `username` FROM `password` WHERE `user_id` = ? AND `deleted_at` IS NULL \
+            AND `id` NOT IN (SELECT `password_id` FROM `password_index` WHERE `user_id` = ?)",
+        )
+        .bind(user.id())
+        .bind(user.id())
+        .fetch_all(&self.db)
+        .await?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
+        for (id, username) in rows {
+            let username = String::from_utf8(self.decrypt(user, &username)?)?;
+            let hash = blind_index(user, "username", &username.trim().to_lowercase())?;
+            // a concurrent search may have indexed the entry in the meantime
+            insert_ignore!("password_index", {
+                "password_id": id,
+                "user_id": user.id(),
+                "field": "username",
+                "hash": &hash,
+            })
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Re-encrypts all entries of `from`, including the trash and history, for `to`
+    /// with a new credential. The blind index is dropped and rebuilt on the next search.
+    #[conerror]
+    pub async fn rekey(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        from: &User,
+        to: &User,
+    ) -> conerror::Result<()> {
+        let rewrap = |data: &[u8]| self.encrypt(to, &self.decrypt(from, data)?);
+        let rewrap_optional = |data: &Option<Vec<u8>>| match data {
+            Some(v) => rewrap(v).map(Some),
+            None => Ok(None),
+        };
+        for table in ["password", "password_history"] {
+            let rows: Vec<RekeyRow> = sqlx::query_as(&format!(
+                "SELECT `id`, `username`, `password`, `attachment`, `totp`, `payload` \
+                FROM `{}` WHERE `user_id` = ?",
+                table
+            ))
+            .bind(from.id())
+            .fetch_all(&mut **tx)
+            .await?;
+            let sql = format!(
+                "UPDATE `{}` SET `username` = ?, `password` = ?, `attachment` = ?, `totp` = ?, \
+                `payload` = ? WHERE `id` = ?",
+                table
+            );
+            for row in rows {
+                sqlx::query(&sql)
+                    .bind(rewrap(&row.username)?)
+                    .bind(rewrap(&row.password)?)
+                    .bind(rewrap_optional(&row.attachment)?)
+                    .bind(rewrap_optional(&row.totp)?)
+                    .bind(rewrap_optional(&row.payload)?)
+                    .bind(row.id)
+                    .execute(&mut **tx)
+                    .await?;
+            }
+        }
+
+        let rows: Vec<(i64, Vec<u8>, Vec<u8>)> =
+            select!("password_field", ["id", "name", "value"], {
+                "user_id" = from.id()
+            })
+            .fetch_all(&mut **tx)
+            .await?;
+        for (id, name, value) in rows {
+            update!(
+                "password_field",
+                {"name": &rewrap(&name)?, "value": &rewrap(&value)?},
+                {"id" = id}
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+
+        let rows: Vec<(i64, Vec<u8>)> =
+            select!("password_url", ["id", "url"], { "user_id" = from.id() })
+                .fetch_all(&mut **tx)
+                .await?;
+        for (id, url) in rows {
+            let url = String::from_utf8(self.decrypt(from, &url)?)?;
+            let domain = match parse_url(&url) {
+                Some(v) => registrable_domain(&v),
+                None => return Err(msg("无效的链接")),
+            };
+            update!("password_url", {
+                "url": &self.encrypt(to, url.as_bytes())?,
+                "domain_hash": &blind_index(to, "domain", &domain)?,
+            }, {"id" = id})
+            .execute(&mut **tx)
+            .await?;
+        }
+
+        delete!("password_index", { "user_id" = from.id() })
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    #[conerror]
+    fn decrypt_row(
+        &self,
+        user: &User,
+        row: PasswordRow,
+        fields: Option<Vec<CustomField>>,
+        urls: Option<Vec<String>>,
+    ) -> conerror::Result<Password> {
+        Ok(Password {
+            id: row.id,
+            name: row.name,
+            kind: EntryType::from_str(&row.kind).map_err(Error::plain)?,
+            username: String::from_utf8(self.decrypt(user, &row.username)?)?,
+            password: String::from_utf8(self.decrypt(user, &row.password)?)?,
+            attachment: match row.attachment {
+                Some(v) => Some(String::from_utf8(self.decrypt(user, &v)?)?),
+                None => None,
+            },
+            totp: match row.totp {
+                Some(v) => Some(String::from_utf8(self.decrypt(user, &v)?)?),
+                None => None,
+            },
+            fields: fields.unwrap_or_default(),
+            payload: match row.payload {
+                Some(v) => Some(serde_json::from_slice(&self.decrypt(user, &v)?)?),
+                None => None,
+            },
+            urls: urls.unwrap_or_default(),
+            updated_at: row.updated_at,
+            created_at: row.created_at,
+        })
+    }
+
+    #[conerror]
+    fn encrypt_payload(
+        &self,
+        user: &User,
+        payload: Option<&EntryPayload>,
+    ) -> conerror::Result<Option<Vec<u8>>> {
+        match payload {
+            Some(v) => {
+                v.validate()?;
+                Ok(Some(self.encrypt(user, &serde_json::to_vec(v)?)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Decrypts `(password_id, url)` rows and groups them by entry, keeping the order of `rows`.
+    #[conerror]
+    fn decrypt_urls(
+        &self,
+        user: &User,
+        rows: Vec<(i64, Vec<u8>)>,
+    ) -> conerror::Result<HashMap<i64, Vec<String>>> {
+        let mut urls: HashMap<i64, Vec<String>> = HashMap::new();
+        for (id, url) in rows {
+            let url = String::from_utf8(self.decrypt(user, &url)?)?;
+            urls.entry(id).or_default().push(url);
+        }
+        Ok(urls)
+    }
+
+    /// Decrypts field rows and groups them by entry, keeping the order of `rows`.
+    #[conerror]
+    fn decrypt_fields(
+        &self,
+        user: &User,
+        rows: Vec<FieldRow>,
+    ) -> conerror::Result<HashMap<i64, Vec<CustomField>>> {
+        let mut fields: HashMap<i64, Vec<CustomField>> = HashMap::new();
+        for row in rows {
+            let field = CustomField {
+                name: String::from_utf8(self.decrypt(user, &row.name)?)?,
+                kind: FieldKind::from_str(&row.kind).map_err(Error::plain)?,
+                value: String::from_utf8(self.decrypt(user, &row.value)?)?,
+            };
+            fields.entry(row.password_id).or_default().push(field);
+        }
+        Ok(fields)
+    }
+
     #[conerror]
     fn encrypt(&self, user: &User, data: &[u8]) -> conerror::Result<Vec<u8>> {
         let data = self.encryption.encrypt(
@@ -162,3 +1302,16 @@ impl PasswordManager {
         Ok(data)
     }
 }
+
+/// Keyed hash of a normalized field value, the key is derived from the user's credential
+/// so the index reveals nothing without it.
+#[conerror]
+fn blind_index(user: &User, field: &str, value: &str) -> conerror::Result<Vec<u8>> {
+    let mut key = Hmac::<Sha256>::new_from_slice(user.credential().password())?;
+    key.update(b"passman blind index");
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.finalize().into_bytes())?;
+    mac.update(field.as_bytes());
+    mac.update(&[0]);
+    mac.update(value.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
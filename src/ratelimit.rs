@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use conerror::conerror;
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::error::msg;
+use crate::user::User;
+use crate::util::timestamp;
+
+/// Buckets kept in memory, when there are more the ones that are full again are
+/// dropped and then the least recently used ones, down to half of it. Buckets of
+/// keys that are locked out are always kept.
+const MAX_BUCKETS: usize = 4096;
+
+const MAX_LOCKOUT_LIST: i64 = 200;
+
+#[derive(Clone, Copy)]
+pub struct LoginLimits {
+    /// Failed logins allowed in a row from one IP address.
+    pub ip_capacity: i64,
+    /// Failed logins allowed in a row for one username.
+    pub username_capacity: i64,
+    /// Seconds after which one more failed login is allowed.
+    pub refill_interval: i64,
+    /// Seconds of the first lockout, doubled for each following one.
+    pub lockout: i64,
+    pub max_lockout: i64,
+}
+
+impl LoginLimits {
+    fn capacity(&self, kind: LockoutKind) -> i64 {
+        match kind {
+            LockoutKind::Ip => self.ip_capacity,
+            LockoutKind::Username => self.username_capacity,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockoutKind {
+    Ip,
+    Username,
+}
+
+impl LockoutKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LockoutKind::Ip => "ip",
+            LockoutKind::Username => "username",
+        }
+    }
+}
+
+impl FromStr for LockoutKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(Self::Ip),
+            "username" => Ok(Self::Username),
+            _ => Err(format!("unknown lockout kind: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Lockout {
+    id: i64,
+    kind: LockoutKind,
+    key: String,
+    /// Address of the attempt that caused the lockout.
+    ip: Option<String>,
+    locked_until: i64,
+    /// Admin who ended the lockout early.
+    unlocked_by: Option<String>,
+    unlocked_at: Option<i64>,
+    created_at: i64,
+}
+
+#[derive(FromRow)]
+struct LockoutRow {
+    id: i64,
+    kind: String,
+    key: String,
+    ip: Option<String>,
+    locked_until: i64,
+    unlocked_by: Option<String>,
+    unlocked_at: Option<i64>,
+    created_at: i64,
+}
+
+/// Token bucket of failed logins, a key is locked out once its bucket is empty.
+/// A token is taken when a login starts and given back unless it fails, so logins
+/// running at the same time can't get past the limit.
+struct Bucket {
+    tokens: i64,
+    /// Logins that took a token and haven't finished yet.
+    pending: i64,
+    updated_at: i64,
+    /// Last time a login took a token.
+    used_at: i64,
+    locked_until: i64,
+    /// Lockouts since the bucket was last full, the next one lasts twice as long.
+    lockouts: u32,
+}
+
+impl Bucket {
+    fn new(capacity: i64, now: i64) -> Self {
+        Self {
+            tokens: capacity,
+            pending: 0,
+            updated_at: now,
+            used_at: now,
+            locked_until: 0,
+            lockouts: 0,
+        }
+    }
+
+    fn refill(&mut self, limits: &LoginLimits, capacity: i64, now: i64) {
+        if now <= self.updated_at {
+            return;
+        }
+        let interval = limits.refill_interval.max(1);
+        let n = (now - self.updated_at) / interval;
+        if self.tokens + n >= capacity {
+            self.tokens = capacity;
+            self.updated_at = now;
+            self.lockouts = 0;
+        } else {
+            self.tokens += n;
+            self.updated_at += n * interval;
+        }
+    }
+
+    /// Takes a token for a login that is starting, fails if there's none left.
+    fn take(&mut self, limits: &LoginLimits, capacity: i64, now: i64) -> bool {
+        self.refill(limits, capacity, now);
+        if self.locked_until > now || self.tokens <= 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        self.pending += 1;
+        self.used_at = now;
+        true
+    }
+
+    /// Gives back the token of a login that didn't fail.
+    fn give_back(&mut self, capacity: i64) {
+        self.tokens = (self.tokens + 1).min(capacity);
+        self.pending = (self.pending - 1).max(0);
+    }
+
+    /// Keeps the token of a failed login, returns the time the key is locked out until
+    /// if it was the last one and no other login may still give one back.
+    fn fail(&mut self, limits: &LoginLimits, now: i64) -> Option<i64> {
+        self.pending = (self.pending - 1).max(0);
+        if self.tokens > 0 || self.pending > 0 {
+            return None;
+        }
+        let duration = limits
+            .lockout
+            .saturating_mul(1 << self.lockouts.min(30))
+            .min(limits.max_lockout);
+        self.tokens = 0;
+        self.lockouts += 1;
+        self.locked_until = now + duration;
+        // refilling starts once the lockout is over, so the backoff keeps growing
+        // until the key stays quiet long enough for the bucket to be full again
+        self.updated_at = self.locked_until;
+        Some(self.locked_until)
+    }
+}
+
+/// Limits failed logins per IP address and per username. Buckets are kept in memory,
+/// lockouts are recorded in `login_lockout` and restored from there on startup.
+#[derive(Clone)]
+pub struct LoginLimiter {
+    db: SqlitePool,
+    limits: LoginLimits,
+    buckets: Arc<Mutex<HashMap<(LockoutKind, String), Bucket>>>,
+}
+
+impl LoginLimiter {
+    pub fn new(db: SqlitePool, limits: LoginLimits) -> Self {
+        Self {
+            db,
+            limits,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Loads the lockouts that are still in effect.
+    #[conerror]
+    pub async fn restore(&self) -> conerror::Result<()> {
+        let rows: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT `kind`, `key`, MAX(`locked_until`) FROM `login_lockout` \
+            WHERE `locked_until` > ? AND `unlocked_at` IS NULL GROUP BY `kind`, `key`",
+        )
+        .bind(timestamp())
+        .fetch_all(&self.db)
+        .await?;
+        let mut buckets = self.buckets.lock().unwrap();
+        for (kind, key, locked_until) in rows {
+            let kind = LockoutKind::from_str(&kind).map_err(conerror::Error::plain)?;
+            buckets.insert(
+                (kind, key),
+                Bucket {
+                    tokens: 0,
+                    pending: 0,
+                    updated_at: locked_until,
+                    used_at: 0,
+                    locked_until,
+                    lockouts: 1,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Starts a login, fails if the address or the username is locked out.
+    #[conerror]
+    pub fn begin(&self, ip: Option<&str>, username: &str) -> conerror::Result<LoginAttempt> {
+        let now = timestamp();
+        let mut buckets = self.buckets.lock().unwrap();
+        let keys = self.keys(ip, username);
+        if buckets.len() >= MAX_BUCKETS && keys.iter().any(|v| !buckets.contains_key(v)) {
+            evict(&self.limits, &mut buckets, now);
+        }
+        for (i, key) in keys.iter().enumerate() {
+            let capacity = self.limits.capacity(key.0);
+            let bucket = buckets
+                .entry(key.clone())
+                .or_insert_with(|| Bucket::new(capacity, now));
+            if !bucket.take(&self.limits, capacity, now) {
+                for key in &keys[..i] {
+                    if let Some(v) = buckets.get_mut(key) {
+                        v.give_back(self.limits.capacity(key.0));
+                    }
+                }
+                return Err(msg("请稍后再试"));
+            }
+        }
+        Ok(LoginAttempt {
+            limiter: self.clone(),
+            ip: ip.map(String::from),
+            username: username.to_string(),
+            done: false,
+        })
+    }
+
+    #[conerror]
+    async fn record_failure(&self, ip: Option<&str>, username: &str) -> conerror::Result<()> {
+        let now = timestamp();
+        let mut lockouts = Vec::new();
+        {
+            let mut buckets = self.buckets.lock().unwrap();
+            for key in self.keys(ip, username) {
+                let capacity = self.limits.capacity(key.0);
+                // the bucket is gone if another login of the username succeeded meanwhile
+                let bucket = buckets.entry(key.clone()).or_insert_with(|| {
+                    let mut v = Bucket::new(capacity, now);
+                    v.tokens -= 1;
+                    v.pending += 1;
+                    v
+                });
+                if let Some(locked_until) = bucket.fail(&self.limits, now) {
+                    lockouts.push((key, locked_until));
+                }
+            }
+        }
+
+        for ((kind, key), locked_until) in lockouts {
+            insert!("login_lockout", {
+                "kind": kind.as_str(),
+                "key": &key,
+                "ip": ip,
+                "locked_until": locked_until,
+                "created_at": now,
+            })
+            .execute(&self.db)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the failed logins of the username, the address still counts them.
+    fn record_success(&self, ip: Option<&str>, username: &str) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.remove(&(LockoutKind::Username, username.to_string()));
+        if let Some(ip) = ip {
+            if let Some(v) = buckets.get_mut(&(LockoutKind::Ip, ip.to_string())) {
+                v.give_back(self.limits.ip_capacity);
+            }
+        }
+    }
+
+    fn release(&self, ip: Option<&str>, username: &str) {
+        let mut buckets = self.buckets.lock().unwrap();
+        for key in self.keys(ip, username) {
+            if let Some(v) = buckets.get_mut(&key) {
+                v.give_back(self.limits.capacity(key.0));
+            }
+        }
+    }
+
+    /// Ends the lockout of a username and forgets its failed logins.
+    #[conerror]
+    pub async fn unlock_user(&self, admin: &User, username: &str) -> conerror::Result<()> {
+        self.buckets
+            .lock()
+            .unwrap()
+            .remove(&(LockoutKind::Username, username.to_string()));
+        let now = timestamp();
+        sqlx::query(
+            "UPDATE `login_lockout` SET `unlocked_by` = ?, `unlocked_at` = ? \
+            WHERE `kind` = ? AND `key` = ? AND `locked_until` > ? AND `unlocked_at` IS NULL",
+        )
+        .bind(admin.id())
+        .bind(now)
+        .bind(LockoutKind::Username.as_str())
+        .bind(username)
+        .bind(now)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// The most recent lockouts, newest first.
+    #[conerror]
+    pub async fn list_lockout(&self) -> conerror::Result<Vec<Lockout>> {
+        let rows: Vec<LockoutRow> = sqlx::query_as(
+            "SELECT `login_lockout`.`id`, `kind`, `key`, `ip`, `locked_until`, \
+            `user`.`username` AS `unlocked_by`, `unlocked_at`, `login_lockout`.`created_at` \
+            FROM `login_lockout` LEFT JOIN `user` ON `user`.`id` = `login_lockout`.`unlocked_by` \
+            ORDER BY `login_lockout`.`id` DESC LIMIT ?",
+        )
+        .bind(MAX_LOCKOUT_LIST)
+        .fetch_all(&self.db)
+        .await?;
+        let mut list = Vec::with_capacity(rows.len());
+        for row in rows {
+            list.push(Lockout {
+                id: row.id,
+                kind: LockoutKind::from_str(&row.kind).map_err(conerror::Error::plain)?,
+                key: row.key,
+                ip: row.ip,
+                locked_until: row.locked_until,
+                unlocked_by: row.unlocked_by,
+                unlocked_at: row.unlocked_at,
+                created_at: row.created_at,
+            });
+        }
+        Ok(list)
+    }
+
+    fn keys(&self, ip: Option<&str>, username: &str) -> Vec<(LockoutKind, String)> {
+        let mut keys = vec![(LockoutKind::Username, username.to_string())];
+        if let Some(ip) = ip {
+            keys.push((LockoutKind::Ip, ip.to_string()));
+        }
+        keys
+    }
+}
+
+/// Drops buckets down to half of `MAX_BUCKETS`, so it runs at most once in
+/// `MAX_BUCKETS / 2` new keys. Locked out keys are never dropped, dropping one
+/// would end its lockout.
+fn evict(limits: &LoginLimits, buckets: &mut HashMap<(LockoutKind, String), Bucket>, now: i64) {
+    buckets.retain(|(kind, _), v| {
+        v.refill(limits, limits.capacity(*kind), now);
+        v.locked_until > now || v.tokens < limits.capacity(*kind)
+    });
+    if buckets.len() <= MAX_BUCKETS / 2 {
+        return;
+    }
+    let mut list: Vec<_> = buckets
+        .iter()
+        .filter(|(_, v)| v.locked_until <= now)
+        .map(|(k, v)| (v.used_at, k.clone()))
+        .collect();
+    list.sort_unstable_by_key(|v| v.0);
+    let n = buckets.len() - MAX_BUCKETS / 2;
+    for (_, key) in list.into_iter().take(n) {
+        buckets.remove(&key);
+    }
+}
+
+/// A login that holds a token of the address and of the username, given back when
+/// it's dropped unless the login failed.
+pub struct LoginAttempt {
+    limiter: LoginLimiter,
+    ip: Option<String>,
+    username: String,
+    done: bool,
+}
+
+impl LoginAttempt {
+    /// The password or the second factor was wrong.
+    #[conerror]
+    pub async fn fail(mut self) -> conerror::Result<()> {
+        self.done = true;
+        self.limiter
+            .record_failure(self.ip.as_deref(), &self.username)
+            .await?;
+        Ok(())
+    }
+
+    pub fn succeed(mut self) {
+        self.done = true;
+        self.limiter
+            .record_success(self.ip.as_deref(), &self.username);
+    }
+}
+
+impl Drop for LoginAttempt {
+    fn drop(&mut self) {
+        if !self.done {
+            self.limiter.release(self.ip.as_deref(), &self.username);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: LoginLimits = LoginLimits {
+        ip_capacity: 10,
+        username_capacity: 3,
+        refill_interval: 60,
+        lockout: 300,
+        max_lockout: 1000,
+    };
+
+    fn take_and_fail(bucket: &mut Bucket, now: i64) -> Option<i64> {
+        assert!(bucket.take(&LIMITS, 3, now));
+        bucket.fail(&LIMITS, now)
+    }
+
+    #[test]
+    fn test_take() {
+        let mut bucket = Bucket::new(3, 0);
+        assert_eq!(None, take_and_fail(&mut bucket, 0));
+        assert_eq!(None, take_and_fail(&mut bucket, 0));
+        assert_eq!(Some(300), take_and_fail(&mut bucket, 0));
+        assert!(!bucket.take(&LIMITS, 3, 299));
+
+        // refills only after the lockout, twice as long the next time
+        assert_eq!(Some(360 + 600), take_and_fail(&mut bucket, 360));
+        assert_eq!(Some(1020 + 1000), take_and_fail(&mut bucket, 1020));
+    }
+
+    #[test]
+    fn test_take_concurrent() {
+        let mut bucket = Bucket::new(3, 0);
+        assert!(bucket.take(&LIMITS, 3, 0));
+        assert!(bucket.take(&LIMITS, 3, 0));
+        assert!(bucket.take(&LIMITS, 3, 0));
+        assert!(!bucket.take(&LIMITS, 3, 0));
+
+        bucket.give_back(3);
+        assert!(bucket.take(&LIMITS, 3, 0));
+        // locked out once the last running login fails
+        assert_eq!(None, bucket.fail(&LIMITS, 0));
+        assert_eq!(None, bucket.fail(&LIMITS, 0));
+        assert_eq!(Some(300), bucket.fail(&LIMITS, 0));
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut buckets = HashMap::new();
+        for i in 0..MAX_BUCKETS as i64 {
+            let mut bucket = Bucket::new(3, 0);
+            bucket.take(&LIMITS, 3, 0);
+            bucket.used_at = i;
+            if i == 0 {
+                bucket.locked_until = i64::MAX;
+            }
+            buckets.insert((LockoutKind::Username, i.to_string()), bucket);
+        }
+        buckets.insert(
+            (LockoutKind::Username, "full".to_string()),
+            Bucket::new(3, 0),
+        );
+        // refilled during the lockout
+        let mut bucket = Bucket::new(3, 0);
+        bucket.locked_until = i64::MAX;
+        buckets.insert((LockoutKind::Username, "locked".to_string()), bucket);
+        evict(&LIMITS, &mut buckets, 0);
+        assert_eq!(MAX_BUCKETS / 2, buckets.len());
+        assert!(buckets.contains_key(&(LockoutKind::Username, "0".to_string())));
+        assert!(buckets.contains_key(&(LockoutKind::Username, "locked".to_string())));
+        assert!(buckets.contains_key(&(LockoutKind::Username, (MAX_BUCKETS - 1).to_string())));
+        assert!(!buckets.contains_key(&(LockoutKind::Username, "1".to_string())));
+        assert!(!buckets.contains_key(&(LockoutKind::Username, "full".to_string())));
+    }
+
+    #[test]
+    fn test_evict_locked() {
+        let mut buckets = HashMap::new();
+        for i in 0..MAX_BUCKETS as i64 {
+            let mut bucket = Bucket::new(3, 0);
+            bucket.locked_until = 1;
+            buckets.insert((LockoutKind::Username, i.to_string()), bucket);
+        }
+        evict(&LIMITS, &mut buckets, 0);
+        assert_eq!(MAX_BUCKETS, buckets.len());
+        evict(&LIMITS, &mut buckets, 1);
+        assert!(buckets.is_empty());
+    }
+
+    #[test]
+    fn test_refill() {
+        let mut bucket = Bucket::new(3, 0);
+        take_and_fail(&mut bucket, 0);
+        take_and_fail(&mut bucket, 0);
+        take_and_fail(&mut bucket, 0);
+        bucket.refill(&LIMITS, 3, 300 + 120);
+        assert_eq!(2, bucket.tokens);
+        assert_eq!(1, bucket.lockouts);
+        bucket.refill(&LIMITS, 3, 300 + 180);
+        assert_eq!(3, bucket.tokens);
+        assert_eq!(0, bucket.lockouts);
+    }
+}
@@ -0,0 +1,87 @@
+use conerror::conerror;
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::client::ClientInfo;
+use crate::emergency::EmergencyManager;
+use crate::file::FileManager;
+use crate::password::PasswordManager;
+use crate::shared::SharedVaultManager;
+use crate::user::{User, UserManager};
+
+/// Replaces the credential of a user, re-encrypting everything that was encrypted with it.
+#[derive(Clone)]
+pub struct KeyRotationManager {
+    db: SqlitePool,
+    user_manager: UserManager,
+    password_manager: PasswordManager,
+    file_manager: FileManager,
+    shared_vault_manager: SharedVaultManager,
+    emergency_manager: EmergencyManager,
+}
+
+#[derive(Serialize)]
+pub struct KeyRotation {
+    /// Token of a new session, all existing sessions are ended.
+    pub token: String,
+    /// The previous recovery key no longer works.
+    pub recovery_key: String,
+    /// The previous backup codes no longer work, none if two-factor login isn't enabled.
+    pub backup_codes: Option<Vec<String>>,
+}
+
+impl KeyRotationManager {
+    pub fn new(
+        db: SqlitePool,
+        user_manager: UserManager,
+        password_manager: PasswordManager,
+        file_manager: FileManager,
+        shared_vault_manager: SharedVaultManager,
+        emergency_manager: EmergencyManager,
+    ) -> Self {
+        Self {
+            db,
+            user_manager,
+            password_manager,
+            file_manager,
+            shared_vault_manager,
+            emergency_manager,
+        }
+    }
+
+    /// Rotates the credential in a single transaction, so either everything is
+    /// re-encrypted or nothing is.
+    #[conerror]
+    pub async fn rotate_key(
+        &self,
+        user: &User,
+        password: &str,
+        client: &ClientInfo,
+    ) -> conerror::Result<KeyRotation> {
+        let mut tx = self.db.begin().await?;
+        let (new_user, recovery_key, backup_codes) =
+            self.user_manager.rekey(&mut tx, user, password).await?;
+        self.password_manager
+            .rekey(&mut tx, user, &new_user)
+            .await?;
+        self.shared_vault_manager
+            .rekey(&mut tx, user, &new_user)
+            .await?;
+        self.emergency_manager.rekey(&mut tx, &new_user).await?;
+        // files are written last, the new blobs must be removed if anything fails afterwards
+        let files = self.file_manager.rekey(&mut tx, user, &new_user).await?;
+        let result = tx.commit().await;
+        if result.is_err() {
+            files.abort().await;
+        }
+        result?;
+        files.finish().await?;
+
+        let token = self.user_manager.create_token(&new_user, client).await?;
+        Ok(KeyRotation {
+            token,
+            recovery_key,
+            backup_codes,
+        })
+    }
+}
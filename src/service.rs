@@ -1,67 +1,940 @@
 use std::borrow::Cow;
 
-use crate::error::msg;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+
+use crate::audit::{
+    self, AuditEvent, AuditManager, TARGET_EMERGENCY_CONTACT, TARGET_FILE, TARGET_PASSWORD,
+    TARGET_SHARED_ENTRY,
+};
+use crate::client::ClientInfo;
+use crate::emergency::{EmergencyContact, EmergencyManager};
+use crate::entry::{EntryPayload, PayloadUpdate};
+use crate::error::{msg, two_factor_required};
+use crate::field::CustomField;
+use crate::file::{FileInfo, FileManager};
+use crate::folder::{Folder, FolderManager};
+use crate::generator::{self, GenerateSpec, Generated};
+use crate::importer::{self, ImportFormat, ImportReport};
+use crate::link::ShareLinkManager;
 use conerror::conerror;
 use rustic_jsonrpc::{method, methods, Method};
 
-use crate::password::{
-    Password, PasswordCreate, PasswordListItem, PasswordManager, PasswordUpdate,
-};
-use crate::user::UserManager;
-use crate::Opt;
+use crate::password::{
+    Password, PasswordCreate, PasswordFilter, PasswordListItem, PasswordManager, PasswordSort,
+    PasswordUpdate, PasswordVersion, SearchHit, TrashItem, UrlMatch,
+};
+use crate::ratelimit::{Lockout, LoginLimiter};
+use crate::rekey::{KeyRotation, KeyRotationManager};
+use crate::share::{IncomingShare, OutgoingShare, ShareManager};
+use crate::shared::{
+    MemberInvite, SharedEntry, SharedEntryCreate, SharedEntryListItem, SharedVault,
+    SharedVaultManager, SharedVaultMember,
+};
+use crate::tag::{Tag, TagManager};
+use crate::totp::TotpCode;
+use crate::user::{
+    SecondFactor, Session, TwoFactorEnrollment, User, UserManager, UserSettings,
+    UserSettingsUpdate, WebauthnRegistration,
+};
+use crate::vault::{ConflictPolicy, ImportResult, VaultManager};
+use crate::webauthn::{
+    AssertionOptions, AssertionResponse, RegistrationOptions, RegistrationResponse,
+    WebauthnCredential, WebauthnManager,
+};
+use crate::Opt;
+
+#[conerror]
+#[method(name = "user.login")]
+async fn login<'a>(
+    #[inject] user_manager: &UserManager,
+    username: Cow<'a, str>,
+    password: Cow<'a, str>,
+    code: Option<Cow<'a, str>>,
+    webauthn: Option<AssertionResponse>,
+) -> conerror::Result<String> {
+    let second_factor = match (&code, &webauthn) {
+        (_, Some(response)) => Some(SecondFactor::Webauthn(response)),
+        (Some(code), None) => Some(SecondFactor::Code(code)),
+        (None, None) => None,
+    };
+    let user = user_manager
+        .login(&username, &password, second_factor)
+        .await?;
+    let token = user_manager
+        .create_token(&user, &ClientInfo::current())
+        .await?;
+    Ok(token)
+}
+
+#[conerror]
+#[method(name = "user.create")]
+async fn create_user<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] opt: &Opt,
+    username: Cow<'a, str>,
+    password: Cow<'a, str>,
+) -> conerror::Result<String> {
+    if !opt.allow_create_user {
+        return Err(msg("create user not available"));
+    }
+    let (_, recovery_key) = user_manager.create_user(&username, &password).await?;
+    Ok(recovery_key)
+}
+
+#[conerror]
+#[method(name = "user.recover")]
+async fn recover_user<'a>(
+    #[inject] user_manager: &UserManager,
+    username: Cow<'a, str>,
+    recovery_key: Cow<'a, str>,
+    new_password: Cow<'a, str>,
+) -> conerror::Result<()> {
+    user_manager
+        .recover(&username, &recovery_key, &new_password)
+        .await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "user.regenerate_recovery_key")]
+async fn regenerate_recovery_key<'a>(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    password: Cow<'a, str>,
+) -> conerror::Result<String> {
+    let user = user_manager.find_user(token).await?;
+    let recovery_key = user_manager
+        .regenerate_recovery_key(&user, &password)
+        .await?;
+    Ok(recovery_key)
+}
+
+#[conerror]
+#[method(name = "user.logout")]
+async fn logout(#[inject] user_manager: &UserManager, token: &str) -> conerror::Result<()> {
+    user_manager.logout(token).await?;
+    Ok(())
+}
+
+/// Returns a new token that replaces the given one.
+#[conerror]
+#[method(name = "user.refresh_token")]
+async fn refresh_token(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+) -> conerror::Result<String> {
+    let token = user_manager
+        .refresh_token(token, &ClientInfo::current())
+        .await?;
+    Ok(token)
+}
+
+#[conerror]
+#[method(name = "user.sessions.list")]
+async fn list_session(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+) -> conerror::Result<Vec<Session>> {
+    let user = user_manager.find_user(token).await?;
+    let list = user_manager.list_session(&user, token).await?;
+    Ok(list)
+}
+
+#[conerror]
+#[method(name = "user.sessions.revoke")]
+async fn revoke_session(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    user_manager.revoke_session(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "user.2fa.enroll")]
+async fn enroll_two_factor(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+) -> conerror::Result<TwoFactorEnrollment> {
+    let user = user_manager.find_user(token).await?;
+    let enrollment = user_manager.enroll_two_factor(&user).await?;
+    Ok(enrollment)
+}
+
+#[conerror]
+#[method(name = "user.2fa.confirm")]
+async fn confirm_two_factor<'a>(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    code: Cow<'a, str>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    user_manager.confirm_two_factor(&user, &code).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "user.2fa.disable")]
+async fn disable_two_factor<'a>(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    code: Cow<'a, str>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    user_manager.disable_two_factor(&user, &code).await?;
+    Ok(())
+}
+
+/// Called with the password before `user.login` when a security key is used.
+#[conerror]
+#[method(name = "user.webauthn.login_options")]
+async fn webauthn_login_options<'a>(
+    #[inject] user_manager: &UserManager,
+    username: Cow<'a, str>,
+    password: Cow<'a, str>,
+) -> conerror::Result<AssertionOptions> {
+    let options = user_manager
+        .webauthn_login_options(&username, &password)
+        .await?;
+    Ok(options)
+}
+
+#[conerror]
+#[method(name = "user.webauthn.register_options")]
+async fn webauthn_register_options(
+    #[inject] user_manager: &UserManager,
+    #[inject] webauthn_manager: &WebauthnManager,
+    token: &str,
+) -> conerror::Result<RegistrationOptions> {
+    let user = user_manager.find_user(token).await?;
+    let options = webauthn_manager.registration_options(&user).await?;
+    Ok(options)
+}
+
+#[conerror]
+#[method(name = "user.webauthn.register")]
+async fn webauthn_register<'a>(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    name: Cow<'a, str>,
+    response: RegistrationResponse,
+) -> conerror::Result<WebauthnRegistration> {
+    let user = user_manager.find_user(token).await?;
+    let registration = user_manager
+        .register_webauthn(&user, &name, &response)
+        .await?;
+    Ok(registration)
+}
+
+#[conerror]
+#[method(name = "user.webauthn.list")]
+async fn list_webauthn_credential(
+    #[inject] user_manager: &UserManager,
+    #[inject] webauthn_manager: &WebauthnManager,
+    token: &str,
+) -> conerror::Result<Vec<WebauthnCredential>> {
+    let user = user_manager.find_user(token).await?;
+    let list = webauthn_manager.list_credential(&user).await?;
+    Ok(list)
+}
+
+/// Options for the assertion `user.webauthn.remove` can be confirmed with.
+#[conerror]
+#[method(name = "user.webauthn.assertion_options")]
+async fn webauthn_assertion_options(
+    #[inject] user_manager: &UserManager,
+    #[inject] webauthn_manager: &WebauthnManager,
+    token: &str,
+) -> conerror::Result<AssertionOptions> {
+    let user = user_manager.find_user(token).await?;
+    let options = webauthn_manager.assertion_options(user.id()).await?;
+    Ok(options)
+}
+
+/// Needs a TOTP or backup `code`, or an assertion with one of the user's security keys.
+#[conerror]
+#[method(name = "user.webauthn.remove")]
+async fn remove_webauthn_credential<'a>(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    id: i64,
+    code: Option<Cow<'a, str>>,
+    webauthn: Option<AssertionResponse>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    let second_factor = match (&code, &webauthn) {
+        (_, Some(response)) => SecondFactor::Webauthn(response),
+        (Some(code), None) => SecondFactor::Code(code),
+        (None, None) => return Err(two_factor_required()),
+    };
+    user_manager
+        .remove_webauthn(&user, id, second_factor)
+        .await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "user.change_password")]
+async fn change_user_password<'a>(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    old_password: Cow<'a, str>,
+    new_password: Cow<'a, str>,
+    logout_other_sessions: Option<bool>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    let current_token = logout_other_sessions.unwrap_or(false).then_some(token);
+    user_manager
+        .change_password(&user, &old_password, &new_password, current_token)
+        .await?;
+    Ok(())
+}
+
+/// Replaces the credential and re-encrypts all data of the user, which ends every session.
+#[conerror]
+#[method(name = "user.rotate_key")]
+async fn rotate_key<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] key_rotation_manager: &KeyRotationManager,
+    token: &str,
+    password: Cow<'a, str>,
+) -> conerror::Result<KeyRotation> {
+    let user = user_manager.find_user(token).await?;
+    let rotation = key_rotation_manager
+        .rotate_key(&user, &password, &ClientInfo::current())
+        .await?;
+    Ok(rotation)
+}
+
+#[conerror]
+#[method(name = "user.settings")]
+async fn user_settings(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+) -> conerror::Result<UserSettings> {
+    let user = user_manager.find_user(token).await?;
+    Ok(user_manager.settings(&user).await?)
+}
+
+#[conerror]
+#[method(name = "user.update_settings")]
+async fn update_user_settings(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    history_retention: Option<i64>,
+    token_idle_timeout: Option<i64>,
+    token_max_age: Option<i64>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    let update = UserSettingsUpdate {
+        history_retention,
+        token_idle_timeout,
+        token_max_age,
+    };
+    user_manager.update_settings(&user, update).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "admin.lockouts.list")]
+async fn list_lockout(
+    #[inject] user_manager: &UserManager,
+    #[inject] login_limiter: &LoginLimiter,
+    #[inject] opt: &Opt,
+    token: &str,
+) -> conerror::Result<Vec<Lockout>> {
+    find_admin(user_manager, opt, token).await?;
+    let list = login_limiter.list_lockout().await?;
+    Ok(list)
+}
+
+/// Ends the lockout of a username after too many failed logins.
+#[conerror]
+#[method(name = "admin.unlock_user")]
+async fn unlock_user(
+    #[inject] user_manager: &UserManager,
+    #[inject] login_limiter: &LoginLimiter,
+    #[inject] opt: &Opt,
+    token: &str,
+    username: &str,
+) -> conerror::Result<()> {
+    let admin = find_admin(user_manager, opt, token).await?;
+    login_limiter.unlock_user(&admin, username).await?;
+    Ok(())
+}
+
+/// Lists the events of the user in a date range, or those of all users for admins.
+#[conerror]
+#[method(name = "audit.list")]
+async fn list_audit(
+    #[inject] user_manager: &UserManager,
+    #[inject] audit_manager: &AuditManager,
+    #[inject] opt: &Opt,
+    token: &str,
+    from: Option<i64>,
+    to: Option<i64>,
+    all: Option<bool>,
+) -> conerror::Result<Vec<AuditEvent>> {
+    let user = user_manager.find_user(token).await?;
+    let all = all.unwrap_or(false);
+    if all && !is_admin(user_manager, opt, &user).await? {
+        return Err(msg("无权限"));
+    }
+    let list = audit_manager
+        .list((!all).then_some(&user), from, to)
+        .await?;
+    Ok(list)
+}
+
+/// Finds the user of the token, who has to be one of the `--admin` users.
+#[conerror]
+async fn find_admin(user_manager: &UserManager, opt: &Opt, token: &str) -> conerror::Result<User> {
+    let user = user_manager.find_user(token).await?;
+    if !is_admin(user_manager, opt, &user).await? {
+        return Err(msg("无权限"));
+    }
+    Ok(user)
+}
+
+#[conerror]
+async fn is_admin(user_manager: &UserManager, opt: &Opt, user: &User) -> conerror::Result<bool> {
+    let username = user_manager.username(user).await?;
+    Ok(opt.admin.contains(&username))
+}
+
+#[conerror]
+#[method(name = "password.list")]
+async fn list_password<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    folder_id: Option<i64>,
+    tag: Option<Cow<'a, str>>,
+    name: Option<Cow<'a, str>>,
+    sort: Option<PasswordSort>,
+    page: Option<i64>,
+    page_size: Option<i64>,
+) -> conerror::Result<Vec<PasswordListItem>> {
+    let user = user_manager.find_user(token).await?;
+    let filter = PasswordFilter {
+        folder_id,
+        tag: tag.as_deref(),
+        name: name.as_deref(),
+        sort: sort.unwrap_or_default(),
+        page: page.unwrap_or(0),
+        page_size,
+        ids: None,
+    };
+    let list = password_manager.list_password(&user, &filter).await?;
+    Ok(list)
+}
+
+#[conerror]
+#[method(name = "password.search")]
+async fn search_password<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    query: Cow<'a, str>,
+    exact: Option<bool>,
+) -> conerror::Result<Vec<SearchHit>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(password_manager
+        .search_password(&user, &query, exact.unwrap_or(false))
+        .await?)
+}
+
+#[conerror]
+#[method(name = "password.lookup_by_url")]
+async fn lookup_password_by_url<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    url: Cow<'a, str>,
+    mode: Option<UrlMatch>,
+) -> conerror::Result<Vec<PasswordListItem>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(password_manager
+        .lookup_by_url(&user, &url, mode.unwrap_or_default())
+        .await?)
+}
+
+#[conerror]
+#[method(name = "file.list")]
+async fn list_file(
+    #[inject] user_manager: &UserManager,
+    #[inject] file_manager: &FileManager,
+    token: &str,
+    password_id: i64,
+) -> conerror::Result<Vec<FileInfo>> {
+    audit::set_target(TARGET_PASSWORD, password_id);
+    let user = user_manager.find_user(token).await?;
+    Ok(file_manager.list_file(&user, password_id).await?)
+}
+
+#[conerror]
+#[method(name = "file.delete")]
+async fn delete_file(
+    #[inject] user_manager: &UserManager,
+    #[inject] file_manager: &FileManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    audit::set_target(TARGET_FILE, id);
+    let user = user_manager.find_user(token).await?;
+    file_manager.delete_file(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "password.set_folder")]
+async fn set_password_folder(
+    #[inject] user_manager: &UserManager,
+    #[inject] folder_manager: &FolderManager,
+    token: &str,
+    id: i64,
+    folder_id: Option<i64>,
+) -> conerror::Result<()> {
+    audit::set_target(TARGET_PASSWORD, id);
+    let user = user_manager.find_user(token).await?;
+    folder_manager.move_password(&user, id, folder_id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "password.set_tags")]
+async fn set_password_tags(
+    #[inject] user_manager: &UserManager,
+    #[inject] tag_manager: &TagManager,
+    token: &str,
+    id: i64,
+    tags: Vec<String>,
+) -> conerror::Result<()> {
+    audit::set_target(TARGET_PASSWORD, id);
+    let user = user_manager.find_user(token).await?;
+    tag_manager.set_password_tags(&user, id, &tags).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "folder.list")]
+async fn list_folder(
+    #[inject] user_manager: &UserManager,
+    #[inject] folder_manager: &FolderManager,
+    token: &str,
+) -> conerror::Result<Vec<Folder>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(folder_manager.list_folder(&user).await?)
+}
+
+#[conerror]
+#[method(name = "folder.create")]
+async fn create_folder<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] folder_manager: &FolderManager,
+    token: &str,
+    name: Cow<'a, str>,
+    parent_id: Option<i64>,
+) -> conerror::Result<i64> {
+    let user = user_manager.find_user(token).await?;
+    Ok(folder_manager
+        .create_folder(&user, &name, parent_id)
+        .await?)
+}
+
+#[conerror]
+#[method(name = "folder.rename")]
+async fn rename_folder<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] folder_manager: &FolderManager,
+    token: &str,
+    id: i64,
+    name: Cow<'a, str>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    folder_manager.rename_folder(&user, id, &name).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "folder.move")]
+async fn move_folder(
+    #[inject] user_manager: &UserManager,
+    #[inject] folder_manager: &FolderManager,
+    token: &str,
+    id: i64,
+    parent_id: Option<i64>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    folder_manager.move_folder(&user, id, parent_id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "folder.delete")]
+async fn delete_folder(
+    #[inject] user_manager: &UserManager,
+    #[inject] folder_manager: &FolderManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    folder_manager.delete_folder(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "tag.list")]
+async fn list_tag(
+    #[inject] user_manager: &UserManager,
+    #[inject] tag_manager: &TagManager,
+    token: &str,
+) -> conerror::Result<Vec<Tag>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(tag_manager.list_tag(&user).await?)
+}
+
+#[conerror]
+#[method(name = "tag.rename")]
+async fn rename_tag<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] tag_manager: &TagManager,
+    token: &str,
+    id: i64,
+    name: Cow<'a, str>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    tag_manager.rename_tag(&user, id, &name).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "tag.delete")]
+async fn delete_tag(
+    #[inject] user_manager: &UserManager,
+    #[inject] tag_manager: &TagManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    tag_manager.delete_tag(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "password.share")]
+async fn share_password<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] share_manager: &ShareManager,
+    token: &str,
+    id: i64,
+    username: Cow<'a, str>,
+) -> conerror::Result<i64> {
+    audit::set_target(TARGET_PASSWORD, id);
+    let user = user_manager.find_user(token).await?;
+    Ok(share_manager.share_password(&user, id, &username).await?)
+}
+
+#[conerror]
+#[method(name = "password.share.incoming")]
+async fn list_incoming_share(
+    #[inject] user_manager: &UserManager,
+    #[inject] share_manager: &ShareManager,
+    token: &str,
+) -> conerror::Result<Vec<IncomingShare>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(share_manager.list_incoming(&user).await?)
+}
 
 #[conerror]
-#[method(name = "user.login")]
-async fn login<'a>(
+#[method(name = "password.share.outgoing")]
+async fn list_outgoing_share(
     #[inject] user_manager: &UserManager,
-    username: Cow<'a, str>,
-    password: Cow<'a, str>,
-) -> conerror::Result<String> {
-    let user = user_manager.login(&username, &password).await?;
-    let token = user_manager.create_token(&user).await?;
-    Ok(token)
+    #[inject] share_manager: &ShareManager,
+    token: &str,
+) -> conerror::Result<Vec<OutgoingShare>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(share_manager.list_outgoing(&user).await?)
 }
 
 #[conerror]
-#[method(name = "user.create")]
-async fn create_user<'a>(
+#[method(name = "password.share.accept")]
+async fn accept_share(
+    #[inject] user_manager: &UserManager,
+    #[inject] share_manager: &ShareManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    share_manager.accept_share(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "password.share.revoke")]
+async fn revoke_share(
+    #[inject] user_manager: &UserManager,
+    #[inject] share_manager: &ShareManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    share_manager.revoke_share(&user, id).await?;
+    Ok(())
+}
+
+/// Returns the share link, the key is in the fragment so it's never sent to the server.
+#[conerror]
+#[method(name = "share_link.create")]
+async fn create_share_link(
     #[inject] user_manager: &UserManager,
+    #[inject] share_link_manager: &ShareLinkManager,
     #[inject] opt: &Opt,
+    token: &str,
+    id: i64,
+    expires_in: i64,
+    max_views: Option<i64>,
+) -> conerror::Result<String> {
+    audit::set_target(TARGET_PASSWORD, id);
+    let user = user_manager.find_user(token).await?;
+    let link = share_link_manager
+        .create_link(&user, id, expires_in, max_views.unwrap_or(1))
+        .await?;
+    let base = opt.public_url.as_deref().unwrap_or_default();
+    Ok(format!(
+        "{}/s/{}#{}",
+        base.trim_end_matches('/'),
+        link.id,
+        link.key
+    ))
+}
+
+#[conerror]
+#[method(name = "emergency.list")]
+async fn list_emergency_contact(
+    #[inject] user_manager: &UserManager,
+    #[inject] emergency_manager: &EmergencyManager,
+    token: &str,
+) -> conerror::Result<Vec<EmergencyContact>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(emergency_manager.list_contact(&user).await?)
+}
+
+#[conerror]
+#[method(name = "emergency.add")]
+async fn add_emergency_contact<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] emergency_manager: &EmergencyManager,
+    token: &str,
     username: Cow<'a, str>,
-    password: Cow<'a, str>,
+    wait_days: i64,
+) -> conerror::Result<i64> {
+    let user = user_manager.find_user(token).await?;
+    Ok(emergency_manager
+        .add_contact(&user, &username, wait_days)
+        .await?)
+}
+
+#[conerror]
+#[method(name = "emergency.remove")]
+async fn remove_emergency_contact(
+    #[inject] user_manager: &UserManager,
+    #[inject] emergency_manager: &EmergencyManager,
+    token: &str,
+    id: i64,
 ) -> conerror::Result<()> {
-    if !opt.allow_create_user {
-        return Err(msg("create user not available"));
-    }
-    user_manager.create_user(&username, &password).await?;
+    let user = user_manager.find_user(token).await?;
+    emergency_manager.remove_contact(&user, id).await?;
     Ok(())
 }
 
 #[conerror]
-#[method(name = "user.change_password")]
-async fn change_user_password<'a>(
+#[method(name = "emergency.request")]
+async fn request_emergency_access(
     #[inject] user_manager: &UserManager,
+    #[inject] emergency_manager: &EmergencyManager,
     token: &str,
-    old_password: Cow<'a, str>,
-    new_password: Cow<'a, str>,
+    id: i64,
 ) -> conerror::Result<()> {
     let user = user_manager.find_user(token).await?;
-    user_manager
-        .change_password(&user, &old_password, &new_password)
+    emergency_manager.request_access(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "emergency.approve")]
+async fn approve_emergency_access(
+    #[inject] user_manager: &UserManager,
+    #[inject] emergency_manager: &EmergencyManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    emergency_manager.approve_access(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "emergency.reject")]
+async fn reject_emergency_access(
+    #[inject] user_manager: &UserManager,
+    #[inject] emergency_manager: &EmergencyManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    emergency_manager.reject_access(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "emergency.view")]
+async fn view_emergency_vault(
+    #[inject] user_manager: &UserManager,
+    #[inject] emergency_manager: &EmergencyManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<Vec<Password>> {
+    audit::set_target(TARGET_EMERGENCY_CONTACT, id);
+    let user = user_manager.find_user(token).await?;
+    Ok(emergency_manager.view_vault(&user, id).await?)
+}
+
+#[conerror]
+#[method(name = "shared.list")]
+async fn list_shared_vault(
+    #[inject] user_manager: &UserManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
+    token: &str,
+) -> conerror::Result<Vec<SharedVault>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(shared_vault_manager.list_vault(&user).await?)
+}
+
+#[conerror]
+#[method(name = "shared.create")]
+async fn create_shared_vault<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
+    token: &str,
+    name: Cow<'a, str>,
+) -> conerror::Result<i64> {
+    let user = user_manager.find_user(token).await?;
+    Ok(shared_vault_manager.create_vault(&user, &name).await?)
+}
+
+#[conerror]
+#[method(name = "shared.invite")]
+async fn invite_shared_vault_member<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
+    token: &str,
+    vault_id: i64,
+    username: Cow<'a, str>,
+) -> conerror::Result<String> {
+    let user = user_manager.find_user(token).await?;
+    Ok(shared_vault_manager
+        .invite_member(&user, vault_id, &username)
+        .await?)
+}
+
+#[conerror]
+#[method(name = "shared.accept")]
+async fn accept_shared_vault_invite<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
+    token: &str,
+    vault_id: i64,
+    code: Cow<'a, str>,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    shared_vault_manager
+        .accept_invite(&user, vault_id, &code)
         .await?;
     Ok(())
 }
 
 #[conerror]
-#[method(name = "password.list")]
-async fn list_password(
+#[method(name = "shared.members")]
+async fn list_shared_vault_member(
     #[inject] user_manager: &UserManager,
-    #[inject] password_manager: &PasswordManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
     token: &str,
-) -> conerror::Result<Vec<PasswordListItem>> {
+    vault_id: i64,
+) -> conerror::Result<Vec<SharedVaultMember>> {
     let user = user_manager.find_user(token).await?;
-    let list = password_manager.list_password(&user).await?;
-    Ok(list)
+    Ok(shared_vault_manager.list_member(&user, vault_id).await?)
+}
+
+#[conerror]
+#[method(name = "shared.revoke")]
+async fn revoke_shared_vault_member(
+    #[inject] user_manager: &UserManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
+    token: &str,
+    vault_id: i64,
+    user_id: i64,
+) -> conerror::Result<Vec<MemberInvite>> {
+    let user = user_manager.find_user(token).await?;
+    let invites = shared_vault_manager
+        .revoke_member(&user, vault_id, user_id)
+        .await?;
+    Ok(invites)
+}
+
+#[conerror]
+#[method(name = "shared.entry.list")]
+async fn list_shared_entry(
+    #[inject] user_manager: &UserManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
+    token: &str,
+    vault_id: i64,
+) -> conerror::Result<Vec<SharedEntryListItem>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(shared_vault_manager.list_entry(&user, vault_id).await?)
+}
+
+#[conerror]
+#[method(name = "shared.entry.create")]
+async fn create_shared_entry<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
+    token: &str,
+    vault_id: i64,
+    name: Cow<'a, str>,
+    username: Cow<'a, str>,
+    password: Cow<'a, str>,
+    attachment: Option<Cow<'a, str>>,
+) -> conerror::Result<i64> {
+    let user = user_manager.find_user(token).await?;
+    let create = SharedEntryCreate {
+        name: &name,
+        username: &username,
+        password: &password,
+        attachment: attachment.as_ref().map(|v| &**v),
+    };
+    let id = shared_vault_manager
+        .create_entry(&user, vault_id, create)
+        .await?;
+    audit::set_target(TARGET_SHARED_ENTRY, id);
+    Ok(id)
+}
+
+#[conerror]
+#[method(name = "shared.entry.view")]
+async fn view_shared_entry(
+    #[inject] user_manager: &UserManager,
+    #[inject] shared_vault_manager: &SharedVaultManager,
+    token: &str,
+    vault_id: i64,
+    id: i64,
+) -> conerror::Result<Option<SharedEntry>> {
+    audit::set_target(TARGET_SHARED_ENTRY, id);
+    let user = user_manager.find_user(token).await?;
+    Ok(shared_vault_manager.view_entry(&user, vault_id, id).await?)
 }
 
 #[conerror]
@@ -72,10 +945,22 @@ async fn view_password(
     token: &str,
     id: i64,
 ) -> conerror::Result<Option<Password>> {
+    audit::set_target(TARGET_PASSWORD, id);
     let user = user_manager.find_user(token).await?;
     Ok(password_manager.view_password(&user, id).await?)
 }
 
+#[conerror]
+#[method(name = "password.generate")]
+async fn generate_password(
+    #[inject] user_manager: &UserManager,
+    token: &str,
+    spec: GenerateSpec,
+) -> conerror::Result<Generated> {
+    user_manager.find_user(token).await?;
+    Ok(generator::generate(&spec)?)
+}
+
 #[conerror]
 #[method(name = "password.create")]
 async fn create_password<'a>(
@@ -84,18 +969,40 @@ async fn create_password<'a>(
     token: &str,
     name: Cow<'a, str>,
     username: Cow<'a, str>,
-    password: Cow<'a, str>,
+    password: Option<Cow<'a, str>>,
     attachment: Option<Cow<'a, str>>,
-) -> conerror::Result<()> {
+    totp: Option<Cow<'a, str>>,
+    fields: Option<Vec<CustomField>>,
+    payload: Option<EntryPayload>,
+    urls: Option<Vec<String>>,
+    generate: Option<GenerateSpec>,
+) -> conerror::Result<Option<Generated>> {
     let user = user_manager.find_user(token).await?;
+    if generate.is_some() && password.is_some() {
+        return Err(msg("参数错误"));
+    }
+    let generated = match generate {
+        Some(spec) => Some(generator::generate(&spec)?),
+        None => None,
+    };
+    let password = match (&generated, &password) {
+        (Some(v), _) => v.password(),
+        (None, Some(v)) => v,
+        (None, None) if payload.is_some() => "",
+        (None, None) => return Err(msg("参数错误")),
+    };
     let create = PasswordCreate {
         name: &name,
         username: &username,
-        password: &password,
+        password,
         attachment: attachment.as_ref().map(|v| &**v),
+        totp: totp.as_ref().map(|v| &**v),
+        fields: fields.as_deref().unwrap_or_default(),
+        payload: payload.as_ref(),
+        urls: urls.as_deref().unwrap_or_default(),
     };
     password_manager.create_password(&user, create).await?;
-    Ok(())
+    Ok(generated)
 }
 
 #[conerror]
@@ -109,18 +1016,66 @@ async fn update_password<'a>(
     username: Cow<'a, str>,
     password: Cow<'a, str>,
     attachment: Option<Cow<'a, str>>,
+    totp: Option<Cow<'a, str>>,
+    fields: Option<Vec<CustomField>>,
+    payload: Option<PayloadUpdate>,
+    urls: Option<Vec<String>>,
 ) -> conerror::Result<()> {
+    audit::set_target(TARGET_PASSWORD, id);
     let user = user_manager.find_user(token).await?;
     let update = PasswordUpdate {
         name: &name,
         username: &username,
         password: &password,
         attachment: attachment.as_ref().map(|v| &**v),
+        totp: totp.as_ref().map(|v| &**v),
+        fields: fields.as_deref(),
+        payload: payload.as_ref().map(PayloadUpdate::payload),
+        urls: urls.as_deref(),
     };
     password_manager.update_password(&user, id, update).await?;
     Ok(())
 }
 
+#[conerror]
+#[method(name = "password.history")]
+async fn password_history(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<Vec<PasswordVersion>> {
+    audit::set_target(TARGET_PASSWORD, id);
+    let user = user_manager.find_user(token).await?;
+    Ok(password_manager.list_history(&user, id).await?)
+}
+
+#[conerror]
+#[method(name = "password.restore")]
+async fn restore_password(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    version_id: i64,
+) -> conerror::Result<()> {
+    let user = user_manager.find_user(token).await?;
+    password_manager.restore_history(&user, version_id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "password.totp")]
+async fn password_totp(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<Option<TotpCode>> {
+    audit::set_target(TARGET_PASSWORD, id);
+    let user = user_manager.find_user(token).await?;
+    Ok(password_manager.totp(&user, id).await?)
+}
+
 #[conerror]
 #[method(name = "password.delete")]
 async fn delete_password(
@@ -129,20 +1084,186 @@ async fn delete_password(
     token: &str,
     id: i64,
 ) -> conerror::Result<()> {
+    audit::set_target(TARGET_PASSWORD, id);
     let user = user_manager.find_user(token).await?;
     password_manager.delete_password(&user, id).await?;
     Ok(())
 }
 
+#[conerror]
+#[method(name = "password.trash.list")]
+async fn list_trash(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+) -> conerror::Result<Vec<TrashItem>> {
+    let user = user_manager.find_user(token).await?;
+    Ok(password_manager.list_trash(&user).await?)
+}
+
+#[conerror]
+#[method(name = "password.trash.restore")]
+async fn restore_trash(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    id: i64,
+) -> conerror::Result<()> {
+    audit::set_target(TARGET_PASSWORD, id);
+    let user = user_manager.find_user(token).await?;
+    password_manager.restore_trash(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "password.trash.purge")]
+async fn purge_trash(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    id: Option<i64>,
+) -> conerror::Result<()> {
+    if let Some(id) = id {
+        audit::set_target(TARGET_PASSWORD, id);
+    }
+    let user = user_manager.find_user(token).await?;
+    password_manager.purge_trash(&user, id).await?;
+    Ok(())
+}
+
+#[conerror]
+#[method(name = "vault.export")]
+async fn export_vault<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] vault_manager: &VaultManager,
+    token: &str,
+    passphrase: Cow<'a, str>,
+) -> conerror::Result<String> {
+    let user = user_manager.find_user(token).await?;
+    Ok(vault_manager.export(&user, &passphrase).await?)
+}
+
+#[conerror]
+#[method(name = "vault.export_kdbx")]
+async fn export_kdbx<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] vault_manager: &VaultManager,
+    token: &str,
+    password: Cow<'a, str>,
+) -> conerror::Result<String> {
+    let user = user_manager.find_user(token).await?;
+    let data = vault_manager.export_kdbx(&user, &password).await?;
+    Ok(BASE64_STANDARD.encode(data))
+}
+
+#[conerror]
+#[method(name = "vault.import")]
+async fn import_vault<'a>(
+    #[inject] user_manager: &UserManager,
+    #[inject] vault_manager: &VaultManager,
+    token: &str,
+    passphrase: Cow<'a, str>,
+    data: Cow<'a, str>,
+    conflict: Option<ConflictPolicy>,
+) -> conerror::Result<ImportResult> {
+    let user = user_manager.find_user(token).await?;
+    let conflict = conflict.unwrap_or(ConflictPolicy::Skip);
+    Ok(vault_manager
+        .import(&user, &passphrase, &data, conflict)
+        .await?)
+}
+
+#[conerror]
+#[method(name = "password.import")]
+async fn import_password(
+    #[inject] user_manager: &UserManager,
+    #[inject] password_manager: &PasswordManager,
+    token: &str,
+    format: ImportFormat,
+    data: &str,
+) -> conerror::Result<ImportReport> {
+    let user = user_manager.find_user(token).await?;
+    let data = BASE64_STANDARD.decode(data)?;
+    Ok(importer::import(password_manager, &user, format, &data).await?)
+}
+
 pub const fn methods() -> &'static [Method] {
     methods!(
         login,
         create_user,
+        recover_user,
+        regenerate_recovery_key,
+        logout,
+        refresh_token,
+        list_session,
+        revoke_session,
+        enroll_two_factor,
+        confirm_two_factor,
+        disable_two_factor,
+        webauthn_login_options,
+        webauthn_register_options,
+        webauthn_register,
+        list_webauthn_credential,
+        webauthn_assertion_options,
+        remove_webauthn_credential,
         change_user_password,
+        rotate_key,
+        list_lockout,
+        unlock_user,
+        list_audit,
+        user_settings,
+        update_user_settings,
         list_password,
+        search_password,
+        lookup_password_by_url,
+        list_file,
+        delete_file,
+        set_password_folder,
+        set_password_tags,
+        list_folder,
+        create_folder,
+        rename_folder,
+        move_folder,
+        delete_folder,
+        list_tag,
+        rename_tag,
+        delete_tag,
+        share_password,
+        list_incoming_share,
+        list_outgoing_share,
+        accept_share,
+        revoke_share,
+        create_share_link,
+        list_emergency_contact,
+        add_emergency_contact,
+        remove_emergency_contact,
+        request_emergency_access,
+        approve_emergency_access,
+        reject_emergency_access,
+        view_emergency_vault,
+        list_shared_vault,
+        create_shared_vault,
+        invite_shared_vault_member,
+        accept_shared_vault_invite,
+        list_shared_vault_member,
+        revoke_shared_vault_member,
+        list_shared_entry,
+        create_shared_entry,
+        view_shared_entry,
         view_password,
+        generate_password,
         create_password,
         update_password,
-        delete_password
+        password_history,
+        restore_password,
+        password_totp,
+        delete_password,
+        list_trash,
+        restore_trash,
+        purge_trash,
+        export_vault,
+        export_kdbx,
+        import_vault,
+        import_password
     )
 }
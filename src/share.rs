@@ -0,0 +1,211 @@
+use conerror::conerror;
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+use x25519_dalek::StaticSecret;
+
+use crate::encryption::EncryptionManager;
+use crate::error::msg;
+use crate::password::PasswordManager;
+use crate::user::{User, UserManager};
+use crate::util::timestamp;
+use crate::vault::ExportEntry;
+
+#[derive(Serialize)]
+pub struct IncomingShare {
+    id: i64,
+    sender: String,
+    name: String,
+    created_at: i64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct OutgoingShare {
+    id: i64,
+    recipient: String,
+    accepted_at: Option<i64>,
+    created_at: i64,
+}
+
+#[derive(FromRow)]
+struct ShareRow {
+    id: i64,
+    sender: String,
+    ephemeral_key: Vec<u8>,
+    data: Vec<u8>,
+    created_at: i64,
+}
+
+/// Sends copies of entries to other users. A copy is encrypted with a key agreed between
+/// a fresh X25519 key and the recipient's public key, so only the recipient can read it.
+#[derive(Clone)]
+pub struct ShareManager {
+    db: SqlitePool,
+    encryption: EncryptionManager,
+    user_manager: UserManager,
+    password_manager: PasswordManager,
+}
+
+impl ShareManager {
+    pub fn new(
+        db: SqlitePool,
+        encryption: EncryptionManager,
+        user_manager: UserManager,
+        password_manager: PasswordManager,
+    ) -> Self {
+        Self {
+            db,
+            encryption,
+            user_manager,
+            password_manager,
+        }
+    }
+
+    #[conerror]
+    pub async fn share_password(
+        &self,
+        user: &User,
+        password_id: i64,
+        username: &str,
+    ) -> conerror::Result<i64> {
+        let (recipient_id, public_key) = match self.user_manager.public_key(username).await? {
+            Some(v) => v,
+            None => return Err(msg("用户不存在")),
+        };
+        if recipient_id == user.id() {
+            return Err(msg("参数错误"));
+        }
+        let entry = match self
+            .password_manager
+            .view_password(user, password_id)
+            .await?
+        {
+            Some(v) => ExportEntry::from(v),
+            None => return Err(msg("密码不存在")),
+        };
+
+        let (ephemeral_key, data) = self
+            .encryption
+            .seal(&serde_json::to_vec(&entry)?, &public_key)?;
+        let id = insert!("password_share", {
+            "sender_id": user.id(),
+            "recipient_id": recipient_id,
+            "ephemeral_key": ephemeral_key.as_slice(),
+            "data": &data,
+            "created_at": timestamp(),
+        })
+        .execute(&self.db)
+        .await?
+        .last_insert_rowid();
+        Ok(id)
+    }
+
+    /// Shares sent to the user that haven't been accepted yet.
+    #[conerror]
+    pub async fn list_incoming(&self, user: &User) -> conerror::Result<Vec<IncomingShare>> {
+        let rows = self.find_incoming(user, None).await?;
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let private_key = self.user_manager.private_key(user).await?;
+        let mut list = Vec::with_capacity(rows.len());
+        for row in rows {
+            let entry = self.decrypt(&private_key, &row)?;
+            list.push(IncomingShare {
+                id: row.id,
+                sender: row.sender,
+                name: entry.name().to_string(),
+                created_at: row.created_at,
+            });
+        }
+        Ok(list)
+    }
+
+    #[conerror]
+    pub async fn list_outgoing(&self, user: &User) -> conerror::Result<Vec<OutgoingShare>> {
+        let list = sqlx::query_as(
+            "SELECT `password_share`.`id`, `user`.`username` AS `recipient`, `password_share`.`accepted_at`, \
+            `password_share`.`created_at` FROM `password_share` \
+            JOIN `user` ON `user`.`id` = `password_share`.`recipient_id` \
+            WHERE `password_share`.`sender_id` = ? ORDER BY `password_share`.`id` DESC",
+        )
+        .bind(user.id())
+        .fetch_all(&self.db)
+        .await?;
+        Ok(list)
+    }
+
+    /// Copies a shared entry into the user's vault.
+    #[conerror]
+    pub async fn accept_share(&self, user: &User, id: i64) -> conerror::Result<()> {
+        let row = match self.find_incoming(user, Some(id)).await?.pop() {
+            Some(v) => v,
+            None => return Err(msg("分享不存在")),
+        };
+        let private_key = self.user_manager.private_key(user).await?;
+        let entry = self.decrypt(&private_key, &row)?;
+
+        // claims the share first so a concurrent revoke or accept can't succeed as well
+        let result = update!(
+            "password_share",
+            {"accepted_at": timestamp()},
+            {"id" = id, "accepted_at" is None::<i64>}
+        )
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("分享不存在"));
+        }
+        if let Err(err) = self
+            .password_manager
+            .create_password(user, entry.to_create())
+            .await
+        {
+            update!("password_share", {"accepted_at": None::<i64>}, {"id" = id})
+                .execute(&self.db)
+                .await?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Withdraws a share the recipient hasn't accepted yet.
+    #[conerror]
+    pub async fn revoke_share(&self, user: &User, id: i64) -> conerror::Result<()> {
+        let result = delete!("password_share", {
+            "id" = id,
+            "sender_id" = user.id(),
+            "accepted_at" is None::<i64>
+        })
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("分享不存在"));
+        }
+        Ok(())
+    }
+
+    #[conerror]
+    async fn find_incoming(&self, user: &User, id: Option<i64>) -> conerror::Result<Vec<ShareRow>> {
+        let rows = sqlx::query_as(
+            "SELECT `password_share`.`id`, `user`.`username` AS `sender`, `password_share`.`ephemeral_key`, \
+            `password_share`.`data`, `password_share`.`created_at` FROM `password_share` \
+            JOIN `user` ON `user`.`id` = `password_share`.`sender_id` \
+            WHERE `password_share`.`recipient_id` = ? AND `password_share`.`accepted_at` IS NULL \
+            AND (? IS NULL OR `password_share`.`id` = ?) ORDER BY `password_share`.`id` DESC",
+        )
+        .bind(user.id())
+        .bind(id)
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows)
+    }
+
+    #[conerror]
+    fn decrypt(&self, private_key: &StaticSecret, row: &ShareRow) -> conerror::Result<ExportEntry> {
+        let data = self
+            .encryption
+            .open(&row.data, &row.ephemeral_key, private_key)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+}
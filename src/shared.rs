@@ -0,0 +1,665 @@
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use conerror::conerror;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
+
+use crate::encryption::EncryptionManager;
+use crate::error::msg;
+use crate::user::{lock_credential, User, UserManager};
+use crate::util::{fill_bytes, timestamp};
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberRole {
+    Owner,
+    Member,
+}
+
+impl MemberRole {
+    fn as_str(self) -> &'static str {
+        match self {
+            MemberRole::Owner => "owner",
+            MemberRole::Member => "member",
+        }
+    }
+
+    fn from_str(s: &str) -> Self {
+        match s {
+            "owner" => MemberRole::Owner,
+            _ => MemberRole::Member,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SharedVault {
+    id: i64,
+    name: String,
+    role: MemberRole,
+}
+
+#[derive(Serialize)]
+pub struct SharedVaultMember {
+    user_id: i64,
+    username: String,
+    role: MemberRole,
+    /// The member was invited but hasn't accepted yet.
+    pending: bool,
+}
+
+/// A member who was invited again when the vault key was rotated, see `revoke_member`.
+#[derive(Serialize)]
+pub struct MemberInvite {
+    user_id: i64,
+    username: String,
+    code: String,
+}
+
+pub struct SharedEntryCreate<'a> {
+    pub name: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+    pub attachment: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+pub struct SharedEntryListItem {
+    id: i64,
+    name: String,
+    updated_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct SharedEntry {
+    id: i64,
+    name: String,
+    username: String,
+    password: String,
+    attachment: Option<String>,
+    created_by: i64,
+    updated_at: i64,
+    created_at: i64,
+}
+
+#[derive(FromRow)]
+struct SharedEntryRow {
+    id: i64,
+    name: Vec<u8>,
+    username: Vec<u8>,
+    password: Vec<u8>,
+    attachment: Option<Vec<u8>>,
+    created_by: i64,
+    updated_at: i64,
+    created_at: i64,
+}
+
+#[derive(FromRow)]
+struct MemberRow {
+    role: String,
+    key: Option<Vec<u8>>,
+    sealed_key: Option<Vec<u8>>,
+    ephemeral_key: Option<Vec<u8>>,
+}
+
+/// Random key of a shared vault, used like a `Credential`.
+struct VaultKey(Vec<u8>);
+
+impl VaultKey {
+    fn generate() -> Self {
+        let mut v = vec![0; 64];
+        fill_bytes(&mut v);
+        Self(v)
+    }
+
+    fn password(&self) -> &[u8] {
+        &self.0[..32]
+    }
+
+    fn salt(&self) -> &[u8] {
+        &self.0[32..]
+    }
+}
+
+/// Vaults shared between users. Entries are encrypted with the vault key, which is
+/// stored wrapped with the credential of each member.
+///
+/// Members can't wrap the key for each other, so invitations carry the key wrapped with
+/// a one-time code, and after a member is revoked the new key is sealed to the public key
+/// of each remaining member. Members wrap the new key with their credential the next time
+/// they open the vault.
+#[derive(Clone)]
+pub struct SharedVaultManager {
+    db: SqlitePool,
+    encryption: EncryptionManager,
+    user_manager: UserManager,
+}
+
+impl SharedVaultManager {
+    pub fn new(db: SqlitePool, encryption: EncryptionManager, user_manager: UserManager) -> Self {
+        Self {
+            db,
+            encryption,
+            user_manager,
+        }
+    }
+
+    #[conerror]
+    pub async fn create_vault(&self, user: &User, name: &str) -> conerror::Result<i64> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        let key = VaultKey::generate();
+        let now = timestamp();
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
+        let id = insert!("shared_vault", {
+            "name": &self.encrypt(&key, name.as_bytes())?,
+            "key_version": 0,
+            "created_at": now,
+        })
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        insert!("shared_vault_member", {
+            "vault_id": id,
+            "user_id": user.id(),
+            "role": MemberRole::Owner.as_str(),
+            "key": &self.wrap(user, &key)?,
+            "key_version": 0,
+            "created_at": now,
+        })
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(id)
+    }
+
+    #[conerror]
+    pub async fn list_vault(&self, user: &User) -> conerror::Result<Vec<SharedVault>> {
+        let ids: Vec<(i64,)> = sqlx::query_as(
+            "SELECT `vault_id` FROM `shared_vault_member` WHERE `user_id` = ? AND `key` IS NOT NULL \
+            ORDER BY `vault_id`",
+        )
+        .bind(user.id())
+        .fetch_all(&self.db)
+        .await?;
+        let mut list = Vec::with_capacity(ids.len());
+        for (id,) in ids {
+            let (key, role) = self.vault_key(user, id).await?;
+            let (name,): (Vec<u8>,) = select!("shared_vault", ["name"], { "id" = id })
+                .fetch_one(&self.db)
+                .await?;
+            list.push(SharedVault {
+                id,
+                name: String::from_utf8(self.decrypt(&key, &name)?)?,
+                role,
+            });
+        }
+        Ok(list)
+    }
+
+    /// Invites a user to the vault and returns the code they need to accept the invitation.
+    #[conerror]
+    pub async fn invite_member(
+        &self,
+        user: &User,
+        vault_id: i64,
+        username: &str,
+    ) -> conerror::Result<String> {
+        let key_version = self.require_owner(user, vault_id).await?;
+        let (key, _) = self.vault_key(user, vault_id).await?;
+        let member: Option<(i64,)> = select!("user", ["id"], { "username" = username })
+            .fetch_optional(&self.db)
+            .await?;
+        let member_id = match member {
+            Some((v,)) => v,
+            None => return Err(msg("用户不存在")),
+        };
+
+        let code = VaultKey::generate();
+        let result = insert_ignore!("shared_vault_member", {
+            "vault_id": vault_id,
+            "user_id": member_id,
+            "role": MemberRole::Member.as_str(),
+            "invite": &self.encrypt(&code, &key.0)?,
+            "key_version": key_version,
+            "created_at": timestamp(),
+        })
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("用户已是成员"));
+        }
+        Ok(BASE64_URL_SAFE_NO_PAD.encode(code.0))
+    }
+
+    #[conerror]
+    pub async fn accept_invite(
+        &self,
+        user: &User,
+        vault_id: i64,
+        code: &str,
+    ) -> conerror::Result<()> {
+        let invite: Option<(Option<Vec<u8>>,)> = select!(
+            "shared_vault_member",
+            ["invite"],
+            {"vault_id" = vault_id, "user_id" = user.id()}
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        let invite = match invite {
+            Some((Some(v),)) => v,
+            _ => return Err(msg("邀请不存在")),
+        };
+        let code = BASE64_URL_SAFE_NO_PAD
+            .decode(code.as_bytes())
+            .ok()
+            .filter(|v| v.len() == 64)
+            .map(VaultKey);
+        let key = match code.and_then(|code| self.decrypt(&code, &invite).ok()) {
+            Some(v) => VaultKey(v),
+            None => return Err(msg("邀请码错误")),
+        };
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
+        update!(
+            "shared_vault_member",
+            {"key": &self.wrap(user, &key)?, "invite": None::<Vec<u8>>},
+            {"vault_id" = vault_id, "user_id" = user.id()}
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn list_member(
+        &self,
+        user: &User,
+        vault_id: i64,
+    ) -> conerror::Result<Vec<SharedVaultMember>> {
+        self.vault_key(user, vault_id).await?;
+        let rows: Vec<(i64, String, String, bool)> = sqlx::query_as(
+            "SELECT `shared_vault_member`.`user_id`, `user`.`username`, `shared_vault_member`.`role`, \
+            `shared_vault_member`.`key` IS NULL FROM `shared_vault_member` \
+            JOIN `user` ON `user`.`id` = `shared_vault_member`.`user_id` \
+            WHERE `shared_vault_member`.`vault_id` = ? ORDER BY `shared_vault_member`.`created_at`",
+        )
+        .bind(vault_id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(user_id, username, role, pending)| SharedVaultMember {
+                user_id,
+                username,
+                role: MemberRole::from_str(&role),
+                pending,
+            })
+            .collect())
+    }
+
+    /// Removes a member or invitation and rotates the vault key, the entries are
+    /// re-encrypted with the new key. Members without a keypair can't receive the new key,
+    /// they are invited again and the codes of their invitations are returned.
+    #[conerror]
+    pub async fn revoke_member(
+        &self,
+        user: &User,
+        vault_id: i64,
+        user_id: i64,
+    ) -> conerror::Result<Vec<MemberInvite>> {
+        let key_version = self.require_owner(user, vault_id).await?;
+        if user_id == user.id() {
+            return Err(msg("参数错误"));
+        }
+        let (old_key, _) = self.vault_key(user, vault_id).await?;
+        let new_key = VaultKey::generate();
+
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
+        let result = delete!("shared_vault_member", {"vault_id" = vault_id, "user_id" = user_id})
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("成员不存在"));
+        }
+        // guards against a concurrent rotation, which would seal different keys
+        let result = update!(
+            "shared_vault",
+            {"key_version": key_version + 1},
+            {"id" = vault_id, "key_version" = key_version}
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("请稍后再试"));
+        }
+        update!(
+            "shared_vault_member",
+            {"key": &self.wrap(user, &new_key)?, "key_version": key_version + 1, "sealed_key": None::<Vec<u8>>, "ephemeral_key": None::<Vec<u8>>},
+            {"vault_id" = vault_id, "user_id" = user.id()}
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let members: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT `shared_vault_member`.`user_id`, `user`.`username` FROM `shared_vault_member` \
+            JOIN `user` ON `user`.`id` = `shared_vault_member`.`user_id` \
+            WHERE `shared_vault_member`.`vault_id` = ? AND `shared_vault_member`.`user_id` != ?",
+        )
+        .bind(vault_id)
+        .bind(user.id())
+        .fetch_all(&mut *tx)
+        .await?;
+        let mut invites = Vec::new();
+        for (member_id, username) in members {
+            match self.user_manager.public_key(&username).await? {
+                Some((_, public_key)) => {
+                    let (ephemeral_key, sealed_key) =
+                        self.encryption.seal(&new_key.0, &public_key)?;
+                    update!(
+                        "shared_vault_member",
+                        {"sealed_key": &sealed_key, "ephemeral_key": ephemeral_key.as_slice(), "key_version": key_version + 1},
+                        {"vault_id" = vault_id, "user_id" = member_id}
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                None => {
+                    let code = VaultKey::generate();
+                    update!(
+                        "shared_vault_member",
+                        {"key": None::<Vec<u8>>, "invite": &self.encrypt(&code, &new_key.0)?, "key_version": key_version + 1, "sealed_key": None::<Vec<u8>>, "ephemeral_key": None::<Vec<u8>>},
+                        {"vault_id" = vault_id, "user_id" = member_id}
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    invites.push(MemberInvite {
+                        user_id: member_id,
+                        username,
+                        code: BASE64_URL_SAFE_NO_PAD.encode(code.0),
+                    });
+                }
+            }
+        }
+        self.reencrypt(&mut tx, vault_id, &old_key, &new_key)
+            .await?;
+        tx.commit().await?;
+        Ok(invites)
+    }
+
+    #[conerror]
+    pub async fn create_entry(
+        &self,
+        user: &User,
+        vault_id: i64,
+        create: SharedEntryCreate<'_>,
+    ) -> conerror::Result<i64> {
+        let name = create.name.trim();
+        if name.is_empty() || create.password.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        let (key, _) = self.vault_key(user, vault_id).await?;
+        let attachment = match create.attachment.filter(|v| !v.is_empty()) {
+            Some(v) => Some(self.encrypt(&key, v.as_bytes())?),
+            None => None,
+        };
+        let now = timestamp();
+        let id = insert!("shared_vault_entry", {
+            "vault_id": vault_id,
+            "name": &self.encrypt(&key, name.as_bytes())?,
+            "username": &self.encrypt(&key, create.username.as_bytes())?,
+            "password": &self.encrypt(&key, create.password.as_bytes())?,
+            "attachment": attachment,
+            "created_by": user.id(),
+            "updated_at": now,
+            "created_at": now,
+        })
+        .execute(&self.db)
+        .await?
+        .last_insert_rowid();
+        Ok(id)
+    }
+
+    #[conerror]
+    pub async fn list_entry(
+        &self,
+        user: &User,
+        vault_id: i64,
+    ) -> conerror::Result<Vec<SharedEntryListItem>> {
+        let (key, _) = self.vault_key(user, vault_id).await?;
+        let rows: Vec<(i64, Vec<u8>, i64)> = select!(
+            "shared_vault_entry",
+            ["id", "name", "updated_at"],
+            { "vault_id" = vault_id },
+            "ORDER BY updated_at DESC"
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let mut list = Vec::with_capacity(rows.len());
+        for (id, name, updated_at) in rows {
+            list.push(SharedEntryListItem {
+                id,
+                name: String::from_utf8(self.decrypt(&key, &name)?)?,
+                updated_at,
+            });
+        }
+        Ok(list)
+    }
+
+    #[conerror]
+    pub async fn view_entry(
+        &self,
+        user: &User,
+        vault_id: i64,
+        id: i64,
+    ) -> conerror::Result<Option<SharedEntry>> {
+        let (key, _) = self.vault_key(user, vault_id).await?;
+        let row: Option<SharedEntryRow> = select!(
+            "shared_vault_entry",
+            ["id", "name", "username", "password", "attachment", "created_by", "updated_at", "created_at"],
+            {"id" = id, "vault_id" = vault_id}
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        let row = match row {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        Ok(Some(SharedEntry {
+            id: row.id,
+            name: String::from_utf8(self.decrypt(&key, &row.name)?)?,
+            username: String::from_utf8(self.decrypt(&key, &row.username)?)?,
+            password: String::from_utf8(self.decrypt(&key, &row.password)?)?,
+            attachment: match row.attachment {
+                Some(v) => Some(String::from_utf8(self.decrypt(&key, &v)?)?),
+                None => None,
+            },
+            created_by: row.created_by,
+            updated_at: row.updated_at,
+            created_at: row.created_at,
+        }))
+    }
+
+    /// Returns the current key of a vault the user is a member of, wrapping the key with
+    /// the credential if it was sealed to the user by a rotation.
+    #[conerror]
+    async fn vault_key(
+        &self,
+        user: &User,
+        vault_id: i64,
+    ) -> conerror::Result<(VaultKey, MemberRole)> {
+        let member: Option<MemberRow> = select!(
+            "shared_vault_member",
+            ["role", "key", "sealed_key", "ephemeral_key"],
+            {"vault_id" = vault_id, "user_id" = user.id()}
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        let member = match member {
+            Some(v) if v.key.is_some() => v,
+            _ => return Err(msg("共享库不存在")),
+        };
+        let role = MemberRole::from_str(&member.role);
+        if let (Some(sealed_key), Some(ephemeral_key)) = (member.sealed_key, member.ephemeral_key) {
+            let private_key = self.user_manager.private_key(user).await?;
+            let key = VaultKey(
+                self.encryption
+                    .open(&sealed_key, &ephemeral_key, &private_key)?,
+            );
+            let mut tx = self.db.begin().await?;
+            lock_credential(&mut tx, user).await?;
+            // a rotation meanwhile sealed a newer key, which is used next time
+            update!(
+                "shared_vault_member",
+                {"key": &self.wrap(user, &key)?, "sealed_key": None::<Vec<u8>>, "ephemeral_key": None::<Vec<u8>>},
+                {"vault_id" = vault_id, "user_id" = user.id(), "sealed_key" = &sealed_key}
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            return Ok((key, role));
+        }
+
+        let key = VaultKey(self.encryption.decrypt(
+            member.key.as_deref().unwrap_or_default(),
+            user.credential().password(),
+            user.credential().salt(),
+        )?);
+        Ok((key, role))
+    }
+
+    /// Returns the current key version if the user owns the vault.
+    #[conerror]
+    async fn require_owner(&self, user: &User, vault_id: i64) -> conerror::Result<i64> {
+        let row: Option<(String, i64)> = sqlx::query_as(
+            "SELECT `shared_vault_member`.`role`, `shared_vault`.`key_version` FROM `shared_vault_member` \
+            JOIN `shared_vault` ON `shared_vault`.`id` = `shared_vault_member`.`vault_id` \
+            WHERE `shared_vault_member`.`vault_id` = ? AND `shared_vault_member`.`user_id` = ? \
+            AND `shared_vault_member`.`key` IS NOT NULL",
+        )
+        .bind(vault_id)
+        .bind(user.id())
+        .fetch_optional(&self.db)
+        .await?;
+        match row {
+            Some((role, version)) if MemberRole::from_str(&role) == MemberRole::Owner => {
+                Ok(version)
+            }
+            Some(_) => Err(msg("无权限")),
+            None => Err(msg("共享库不存在")),
+        }
+    }
+
+    #[conerror]
+    async fn reencrypt(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        vault_id: i64,
+        old_key: &VaultKey,
+        new_key: &VaultKey,
+    ) -> conerror::Result<()> {
+        let (name,): (Vec<u8>,) = select!("shared_vault", ["name"], { "id" = vault_id })
+            .fetch_one(&mut **tx)
+            .await?;
+        let name = self.encrypt(new_key, &self.decrypt(old_key, &name)?)?;
+        update!("shared_vault", {"name": &name}, {"id" = vault_id})
+            .execute(&mut **tx)
+            .await?;
+
+        let rows: Vec<SharedEntryRow> = select!(
+            "shared_vault_entry",
+            [
+                "id",
+                "name",
+                "username",
+                "password",
+                "attachment",
+                "created_by",
+                "updated_at",
+                "created_at"
+            ],
+            { "vault_id" = vault_id }
+        )
+        .fetch_all(&mut **tx)
+        .await?;
+        for row in rows {
+            let rewrap = |data: &[u8]| self.encrypt(new_key, &self.decrypt(old_key, data)?);
+            let attachment = match &row.attachment {
+                Some(v) => Some(rewrap(v)?),
+                None => None,
+            };
+            update!("shared_vault_entry", {
+                "name": &rewrap(&row.name)?,
+                "username": &rewrap(&row.username)?,
+                "password": &rewrap(&row.password)?,
+                "attachment": attachment,
+            }, {"id" = row.id})
+            .execute(&mut **tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Re-wraps the vault keys of `from` for `to` with a new credential.
+    #[conerror]
+    pub async fn rekey(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        from: &User,
+        to: &User,
+    ) -> conerror::Result<()> {
+        let rows: Vec<(i64, Option<Vec<u8>>)> =
+            select!("shared_vault_member", ["vault_id", "key"], {
+                "user_id" = from.id()
+            })
+            .fetch_all(&mut **tx)
+            .await?;
+        // members who haven't accepted their invite have no key yet
+        for (vault_id, key) in rows {
+            let key = match key {
+                Some(v) => v,
+                None => continue,
+            };
+            let key = VaultKey(self.encryption.decrypt(
+                &key,
+                from.credential().password(),
+                from.credential().salt(),
+            )?);
+            update!(
+                "shared_vault_member",
+                {"key": &self.wrap(to, &key)?},
+                {"vault_id" = vault_id, "user_id" = to.id()}
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[conerror]
+    fn wrap(&self, user: &User, key: &VaultKey) -> conerror::Result<Vec<u8>> {
+        let data = self.encryption.encrypt(
+            &key.0,
+            user.credential().password(),
+            user.credential().salt(),
+        )?;
+        Ok(data)
+    }
+
+    #[conerror]
+    fn encrypt(&self, key: &VaultKey, data: &[u8]) -> conerror::Result<Vec<u8>> {
+        let data = self.encryption.encrypt(data, key.password(), key.salt())?;
+        Ok(data)
+    }
+
+    #[conerror]
+    fn decrypt(&self, key: &VaultKey, data: &[u8]) -> conerror::Result<Vec<u8>> {
+        let data = self.encryption.decrypt(data, key.password(), key.salt())?;
+        Ok(data)
+    }
+}
@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+
+use url::Url;
+
+/// Public suffixes with more than one label that are common enough to matter,
+/// everything else is assumed to be a single label suffix like `com`.
+const MULTI_LABEL_SUFFIXES: &[&str] = &[
+    "ac.jp",
+    "ac.uk",
+    "co.in",
+    "co.jp",
+    "co.kr",
+    "co.nz",
+    "co.uk",
+    "co.za",
+    "com.au",
+    "com.br",
+    "com.cn",
+    "com.hk",
+    "com.mx",
+    "com.sg",
+    "com.tr",
+    "com.tw",
+    "edu.au",
+    "edu.cn",
+    "gov.cn",
+    "gov.uk",
+    "ne.jp",
+    "net.au",
+    "net.cn",
+    "or.jp",
+    "org.au",
+    "org.cn",
+    "org.uk",
+    "github.io",
+    "gitlab.io",
+    "herokuapp.com",
+    "pages.dev",
+    "vercel.app",
+];
+
+/// Parses an entry or page URL, a bare host like `example.com/login` is taken as https.
+pub fn parse_url(s: &str) -> Option<Url> {
+    let s = s.trim();
+    let url = match Url::parse(s) {
+        Ok(v) if v.has_host() => v,
+        _ => Url::parse(&format!("https://{}", s)).ok()?,
+    };
+    match url.host_str() {
+        Some(host) if !host.is_empty() => Some(url),
+        _ => None,
+    }
+}
+
+/// The domain a user registered, e.g. `corp.example` for `git.corp.example`
+/// and `example.co.uk` for `www.example.co.uk`. IP addresses are returned unchanged.
+pub fn registrable_domain(url: &Url) -> String {
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_end_matches('.')
+        .to_lowercase();
+    if host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+        .is_ok()
+    {
+        return host;
+    }
+    let labels: Vec<&str> = host.split('.').collect();
+    let suffix_labels = match MULTI_LABEL_SUFFIXES
+        .iter()
+        .find(|v| host.ends_with(&format!(".{}", v)))
+    {
+        Some(v) => v.split('.').count(),
+        None => 1,
+    };
+    labels[labels.len().saturating_sub(suffix_labels + 1)..].join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::site::{parse_url, registrable_domain};
+
+    #[test]
+    fn test_registrable_domain() {
+        let cases = [
+            ("https://www.example.co.uk/login", "example.co.uk"),
+            ("https://git.corp.example", "corp.example"),
+            ("https://example.com", "example.com"),
+            ("https://WWW.Example.COM.", "example.com"),
+            ("https://user.github.io/page", "user.github.io"),
+            ("https://a.b.user.github.io", "user.github.io"),
+            ("https://localhost:8080", "localhost"),
+            ("http://192.168.1.1:8080/admin", "192.168.1.1"),
+            ("http://[::1]/admin", "[::1]"),
+            ("http://[2001:db8::1]:8443", "[2001:db8::1]"),
+        ];
+        for (url, domain) in cases {
+            assert_eq!(
+                domain,
+                registrable_domain(&parse_url(url).unwrap()),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = parse_url(" example.com/login ").unwrap();
+        assert_eq!("https", url.scheme());
+        assert_eq!(Some("example.com"), url.host_str());
+        assert_eq!("/login", url.path());
+        let url = parse_url("http://example.com:8080").unwrap();
+        assert_eq!("http", url.scheme());
+        assert_eq!(Some(8080), url.port());
+        assert!(parse_url("").is_none());
+        assert!(parse_url("  ").is_none());
+    }
+}
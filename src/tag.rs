@@ -0,0 +1,118 @@
+use conerror::conerror;
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::error::msg;
+use crate::user::User;
+use crate::util::timestamp;
+
+#[derive(FromRow, Serialize)]
+pub struct Tag {
+    id: i64,
+    name: String,
+    count: i64,
+}
+
+#[derive(Clone)]
+pub struct TagManager {
+    db: SqlitePool,
+}
+
+impl TagManager {
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+
+    #[conerror]
+    pub async fn list_tag(&self, user: &User) -> conerror::Result<Vec<Tag>> {
+        let list = sqlx::query_as(
+            "SELECT `tag`.`id`, `tag`.`name`, COUNT(`password`.`id`) AS `count` FROM `tag` \
+            LEFT JOIN `password_tag` ON `password_tag`.`tag_id` = `tag`.`id` \
+            LEFT JOIN `password` ON `password`.`id` = `password_tag`.`password_id` AND `password`.`deleted_at` IS NULL \
+            WHERE `tag`.`user_id` = ? GROUP BY `tag`.`id` ORDER BY `tag`.`name`",
+        )
+        .bind(user.id())
+        .fetch_all(&self.db)
+        .await?;
+        Ok(list)
+    }
+
+    /// Replaces the tags of an entry, tags that don't exist yet are created.
+    #[conerror]
+    pub async fn set_password_tags(
+        &self,
+        user: &User,
+        password_id: i64,
+        tags: &[String],
+    ) -> conerror::Result<()> {
+        let exists: Option<(i64,)> =
+            select!("password", ["id"], {"id" = password_id, "user_id" = user.id()})
+                .fetch_optional(&self.db)
+                .await?;
+        if exists.is_none() {
+            return Err(msg("密码不存在"));
+        }
+
+        let mut tx = self.db.begin().await?;
+        delete!("password_tag", { "password_id" = password_id })
+            .execute(&mut *tx)
+            .await?;
+        for tag in tags {
+            let tag = tag.trim();
+            if tag.is_empty() {
+                continue;
+            }
+            insert_ignore!("tag", {"user_id": user.id(), "name": tag, "created_at": timestamp()})
+                .execute(&mut *tx)
+                .await?;
+            let (tag_id,): (i64,) = select!("tag", ["id"], {"user_id" = user.id(), "name" = tag})
+                .fetch_one(&mut *tx)
+                .await?;
+            insert_ignore!("password_tag", {"password_id": password_id, "tag_id": tag_id})
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn rename_tag(&self, user: &User, id: i64, name: &str) -> conerror::Result<()> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        let exists: Option<(i64,)> = select!("tag", ["id"], {"id" = id, "user_id" = user.id()})
+            .fetch_optional(&self.db)
+            .await?;
+        if exists.is_none() {
+            return Err(msg("标签不存在"));
+        }
+        let result =
+            sqlx::query("UPDATE OR IGNORE `tag` SET `name` = ? WHERE `id` = ? AND `user_id` = ?")
+                .bind(name)
+                .bind(id)
+                .bind(user.id())
+                .execute(&self.db)
+                .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("标签已存在"));
+        }
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn delete_tag(&self, user: &User, id: i64) -> conerror::Result<()> {
+        let mut tx = self.db.begin().await?;
+        let result = delete!("tag", {"id" = id, "user_id" = user.id()})
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() > 0 {
+            delete!("password_tag", { "tag_id" = id })
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
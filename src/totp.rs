@@ -0,0 +1,228 @@
+use conerror::conerror;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use url::Url;
+
+use crate::error::msg;
+
+#[derive(Copy, Clone)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+pub struct Totp {
+    secret: Vec<u8>,
+    algorithm: Algorithm,
+    digits: u32,
+    period: i64,
+}
+
+#[derive(Serialize)]
+pub struct TotpCode {
+    code: String,
+    remaining: i64,
+}
+
+impl Totp {
+    /// Default parameters of authenticator apps: SHA1, 6 digits and 30 seconds.
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            algorithm: Algorithm::Sha1,
+            digits: 6,
+            period: 30,
+        }
+    }
+
+    #[conerror]
+    pub fn from_uri(uri: &str) -> conerror::Result<Self> {
+        let url = Url::parse(uri).map_err(|_| invalid_uri())?;
+        if url.scheme() != "otpauth" || url.host_str() != Some("totp") {
+            return Err(invalid_uri());
+        }
+
+        let mut secret = None;
+        let mut algorithm = Algorithm::Sha1;
+        let mut digits = 6;
+        let mut period = 30;
+        for (k, v) in url.query_pairs() {
+            match &*k {
+                "secret" => secret = base32_decode(&v),
+                "algorithm" => {
+                    algorithm = match &*v.to_ascii_uppercase() {
+                        "SHA1" => Algorithm::Sha1,
+                        "SHA256" => Algorithm::Sha256,
+                        "SHA512" => Algorithm::Sha512,
+                        _ => return Err(invalid_uri()),
+                    }
+                }
+                "digits" => digits = v.parse().map_err(|_| invalid_uri())?,
+                "period" => period = v.parse().map_err(|_| invalid_uri())?,
+                _ => {}
+            }
+        }
+
+        match secret {
+            Some(secret) if !secret.is_empty() && (6..=9).contains(&digits) && period > 0 => {
+                Ok(Self {
+                    secret,
+                    algorithm,
+                    digits,
+                    period,
+                })
+            }
+            _ => Err(invalid_uri()),
+        }
+    }
+
+    pub fn code(&self, time: i64) -> TotpCode {
+        TotpCode {
+            code: self.generate(time),
+            remaining: self.period - time.rem_euclid(self.period),
+        }
+    }
+
+    /// Checks a code against the time steps around `time` to allow for clock drift,
+    /// returns the matching time step.
+    pub fn verify(&self, code: &str, time: i64) -> Option<i64> {
+        let step = time.div_euclid(self.period);
+        (step - 1..=step + 1).find(|step| self.generate(step * self.period) == code)
+    }
+
+    pub fn generate(&self, time: i64) -> String {
+        let counter = (time.div_euclid(self.period) as u64).to_be_bytes();
+        let hash = match self.algorithm {
+            Algorithm::Sha1 => hmac::<Hmac<Sha1>>(&self.secret, &counter),
+            Algorithm::Sha256 => hmac::<Hmac<Sha256>>(&self.secret, &counter),
+            Algorithm::Sha512 => hmac::<Hmac<Sha512>>(&self.secret, &counter),
+        };
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            binary as u64 % 10u64.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+}
+
+fn hmac<M: Mac + hmac::digest::KeyInit>(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn base32_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for b in data {
+        buffer = (buffer << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            b'=' | b' ' | b'-' => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn invalid_uri() -> conerror::Error {
+    msg("无效的 otpauth 链接")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::totp::{base32_decode, base32_encode, Totp};
+
+    #[test]
+    fn test_generate() {
+        let cases = [
+            ("SHA1", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", 59, "94287082"),
+            ("SHA1", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", 1111111109, "07081804"),
+            ("SHA1", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", 2000000000, "69279037"),
+            (
+                "SHA256",
+                "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZA",
+                59,
+                "46119246",
+            ),
+            (
+                "SHA512",
+                "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNA",
+                59,
+                "90693936",
+            ),
+        ];
+        for (algorithm, secret, time, code) in cases {
+            let uri = format!(
+                "otpauth://totp/passman:alice?secret={}&algorithm={}&digits=8",
+                secret, algorithm
+            );
+            let totp = Totp::from_uri(&uri).unwrap();
+            assert_eq!(code, totp.generate(time));
+        }
+    }
+
+    #[test]
+    fn test_from_uri() {
+        assert!(Totp::from_uri("otpauth://hotp/alice?secret=GEZDGNBV").is_err());
+        assert!(Totp::from_uri("otpauth://totp/alice").is_err());
+        let totp = Totp::from_uri("otpauth://totp/alice?secret=gezd%20gnbv").unwrap();
+        assert_eq!(6, totp.generate(59).len());
+        assert_eq!(1, totp.code(59).remaining);
+    }
+
+    #[test]
+    fn test_base32_encode() {
+        assert_eq!("GEZDGNBVGY3TQOJQ", base32_encode(b"1234567890"));
+        assert_eq!("MZXW6YQ", base32_encode(b"foob"));
+        let data = b"passman two factor";
+        assert_eq!(
+            data.as_slice(),
+            base32_decode(&base32_encode(data)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let totp = Totp::new(b"12345678901234567890".to_vec());
+        assert_eq!(Some(1), totp.verify("287082", 59));
+        assert_eq!(Some(1), totp.verify("287082", 89));
+        assert_eq!(None, totp.verify("287082", 120));
+    }
+}
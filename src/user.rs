@@ -1,28 +1,55 @@
 use base64::prelude::BASE64_URL_SAFE_NO_PAD;
 use base64::Engine;
 use conerror::conerror;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
-use sqlx::{FromRow, SqlitePool};
+use sqlx::{FromRow, Sqlite, SqlitePool, Transaction};
+use x25519_dalek::{PublicKey, StaticSecret};
 
+use crate::audit;
+use crate::client::ClientInfo;
 use crate::encryption::EncryptionManager;
-use crate::error::{invalid_token, msg};
+use crate::error::{invalid_token, msg, two_factor_required};
+use crate::password::VERSIONED_TABLES;
+use crate::ratelimit::{LoginAttempt, LoginLimiter};
+use crate::totp::{base32_encode, Totp};
 use crate::util::{fill_bytes, timestamp};
+use crate::webauthn::{AssertionOptions, AssertionResponse, RegistrationResponse, WebauthnManager};
 
+#[derive(Clone)]
 pub struct User {
     id: i64,
+    /// Version of the credential when the user was loaded, see `lock_credential`.
+    credential_version: i64,
     credential: Credential,
 }
 
 impl User {
+    /// A user acting with a credential that was obtained without logging in,
+    /// e.g. through emergency access.
+    pub fn with_credential(id: i64, credential_version: i64, credential: Vec<u8>) -> Self {
+        Self {
+            id,
+            credential_version,
+            credential: Credential(credential),
+        }
+    }
+
     pub fn id(&self) -> i64 {
         self.id
     }
 
+    pub fn credential_version(&self) -> i64 {
+        self.credential_version
+    }
+
     pub fn credential(&self) -> &Credential {
         &self.credential
     }
 }
 
+#[derive(Clone)]
 pub struct Credential(Vec<u8>);
 
 impl Credential {
@@ -39,36 +66,182 @@ impl Credential {
     pub fn salt(&self) -> &[u8] {
         &self.0[32..]
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
 }
 
-const MAX_LOGIN_ATTEMPT: i64 = 5;
+#[derive(FromRow, Serialize)]
+pub struct UserSettings {
+    history_retention: i64,
+    /// `None` uses the server's limit, which also caps the user's own.
+    token_idle_timeout: Option<i64>,
+    token_max_age: Option<i64>,
+}
+
+pub struct UserSettingsUpdate {
+    pub history_retention: Option<i64>,
+    /// 0 resets to the server's limit.
+    pub token_idle_timeout: Option<i64>,
+    /// 0 resets to the server's limit.
+    pub token_max_age: Option<i64>,
+}
+
+/// Server wide limits of how long a token stays valid, in seconds.
+#[derive(Copy, Clone)]
+pub struct TokenLifetime {
+    /// A token expires when it isn't used for this long.
+    pub idle_timeout: i64,
+    /// A token expires this long after login, even if it's refreshed.
+    pub max_age: i64,
+}
+
+impl TokenLifetime {
+    /// Applies the user's own limits, which can only be shorter.
+    fn with_settings(self, idle_timeout: Option<i64>, max_age: Option<i64>) -> Self {
+        Self {
+            idle_timeout: idle_timeout.map_or(self.idle_timeout, |v| v.min(self.idle_timeout)),
+            max_age: max_age.map_or(self.max_age, |v| v.min(self.max_age)),
+        }
+    }
+}
+
+/// Lower bound of the token limits a user can set, in seconds.
+const MIN_TOKEN_LIFETIME: i64 = 60;
+
+/// Secret(64), id(8) and SHA-256 of both and the encrypted credential(32).
+const TOKEN_LENGTH: usize = 104;
 
-const LOGIN_SUSPEND_DURATION: i64 = 300;
+const MAX_HISTORY_RETENTION: i64 = 100;
 
-const TOKEN_IDLE_DURATION: i64 = 300;
+/// Symbols of recovery keys, without the easily confused 0, O, 1 and I.
+const RECOVERY_KEY_ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+const RECOVERY_KEY_LENGTH: usize = 32;
+
+const BACKUP_CODE_COUNT: usize = 10;
+
+const BACKUP_CODE_LENGTH: usize = 10;
+
+#[derive(Serialize)]
+pub struct TwoFactorEnrollment {
+    /// Base32 encoded, for entering into an authenticator app by hand.
+    secret: String,
+    uri: String,
+    /// One-time codes for logging in without the authenticator app, only shown now.
+    backup_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct WebauthnRegistration {
+    id: i64,
+    /// Codes for logging in without the security key, generated with the first key
+    /// if the user has none and only shown now.
+    backup_codes: Option<Vec<String>>,
+}
+
+/// Proof of the second factor given along with the password.
+pub enum SecondFactor<'a> {
+    /// A TOTP or backup code.
+    Code(&'a str),
+    Webauthn(&'a AssertionResponse),
+}
 
 #[derive(Clone)]
 pub struct UserManager {
     db: SqlitePool,
     encryption: EncryptionManager,
+    webauthn: WebauthnManager,
+    token_lifetime: TokenLifetime,
+    login_limiter: LoginLimiter,
 }
 
 impl UserManager {
-    pub fn new(db: SqlitePool, encryption: EncryptionManager) -> Self {
-        Self { db, encryption }
+    pub fn new(
+        db: SqlitePool,
+        encryption: EncryptionManager,
+        webauthn: WebauthnManager,
+        token_lifetime: TokenLifetime,
+        login_limiter: LoginLimiter,
+    ) -> Self {
+        Self {
+            db,
+            encryption,
+            webauthn,
+            token_lifetime,
+            login_limiter,
+        }
+    }
+
+    /// Logs in with the password, and a second factor if two-factor login is enabled.
+    #[conerror]
+    pub async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        second_factor: Option<SecondFactor<'_>>,
+    ) -> conerror::Result<User> {
+        let (u, user, attempt) = self.check_password(username, password).await?;
+        let webauthn_enabled = self.webauthn.has_credential(u.id).await?;
+        if u.totp_enabled || webauthn_enabled {
+            let (verified, error) = match second_factor {
+                Some(SecondFactor::Code(code)) if !code.trim().is_empty() => (
+                    self.verify_second_factor(&user, code.trim(), u.totp_enabled)
+                        .await?,
+                    "验证码错误",
+                ),
+                Some(SecondFactor::Webauthn(response)) if webauthn_enabled => (
+                    self.webauthn.verify_assertion(u.id, response).await?,
+                    "安全密钥验证失败",
+                ),
+                _ => return Err(two_factor_required()),
+            };
+            if !verified {
+                attempt.fail().await?;
+                return Err(msg(error));
+            }
+        }
+        attempt.succeed();
+        if u.public_key.is_none() {
+            self.private_key(&user).await?;
+        }
+        Ok(user)
+    }
+
+    /// Checks the password before a security key is asked for, returns the options
+    /// to pass to `navigator.credentials.get`.
+    #[conerror]
+    pub async fn webauthn_login_options(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> conerror::Result<AssertionOptions> {
+        let (u, _, _) = self.check_password(username, password).await?;
+        let options = self.webauthn.assertion_options(u.id).await?;
+        Ok(options)
     }
 
     #[conerror]
-    pub async fn login(&self, username: &str, password: &str) -> conerror::Result<User> {
+    async fn check_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> conerror::Result<(UserRow, User, LoginAttempt)> {
+        let attempt = self
+            .login_limiter
+            .begin(ClientInfo::current().ip.as_deref(), username)?;
         let u = match UserRow::find_by_username(&self.db, username).await? {
             Some(v) => v,
-            None => return Err(msg("用户名或密码错误")),
+            None => {
+                attempt.fail().await?;
+                return Err(msg("用户名或密码错误"));
+            }
         };
-        if u.suspend > timestamp() {
-            return Err(msg("请稍后再试"));
-        }
+        audit::set_user(u.id);
         let mut user = User {
             id: u.id,
+            credential_version: u.credential_version,
             credential: Credential(Vec::new()),
         };
         user.credential.0 =
@@ -76,43 +249,282 @@ impl UserManager {
                 .encryption
                 .decrypt(&u.credential, password.as_bytes(), &u.salt)
             {
-                Ok(v) => {
-                    update!("user", {"suspend": 0}, {"id" = u.id})
-                        .execute(&self.db)
-                        .await?;
-                    v
-                }
+                Ok(v) => v,
                 Err(_) => {
-                    let suspend = if u.suspend + 1 >= MAX_LOGIN_ATTEMPT {
-                        timestamp() + LOGIN_SUSPEND_DURATION
-                    } else {
-                        u.suspend + 1
-                    };
-                    update!("user", {"suspend": suspend}, {"id" = u.id})
-                        .execute(&self.db)
-                        .await?;
+                    attempt.fail().await?;
                     return Err(msg("用户名或密码错误"));
                 }
             };
-        Ok(user)
+        Ok((u, user, attempt))
+    }
+
+    /// Starts enrolling two-factor login, which is enabled once `confirm_two_factor`
+    /// succeeds with a code from the authenticator app.
+    #[conerror]
+    pub async fn enroll_two_factor(&self, user: &User) -> conerror::Result<TwoFactorEnrollment> {
+        let (username, enabled): (String, bool) =
+            select!("user", ["username", "totp_enabled"], { "id" = user.id })
+                .fetch_one(&self.db)
+                .await?;
+        if enabled {
+            return Err(msg("已启用两步验证"));
+        }
+        let mut secret = vec![0u8; 20];
+        fill_bytes(&mut secret);
+        let encrypted_secret =
+            self.encryption
+                .encrypt(&secret, user.credential.password(), user.credential.salt())?;
+
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
+        update!(
+            "user",
+            {"totp_secret": &encrypted_secret, "totp_last": None::<i64>},
+            {"id" = user.id}
+        )
+        .execute(&mut *tx)
+        .await?;
+        let backup_codes = replace_backup_codes(&mut tx, user).await?;
+        tx.commit().await?;
+
+        let secret = base32_encode(&secret);
+        let uri = format!(
+            "otpauth://totp/passman:{}?secret={}&issuer=passman",
+            url::form_urlencoded::byte_serialize(username.as_bytes()).collect::<String>(),
+            secret
+        );
+        Ok(TwoFactorEnrollment {
+            secret,
+            uri,
+            backup_codes,
+        })
+    }
+
+    #[conerror]
+    pub async fn confirm_two_factor(&self, user: &User, code: &str) -> conerror::Result<()> {
+        if !self.verify_totp(user, code.trim()).await? {
+            return Err(msg("验证码错误"));
+        }
+        update!("user", {"totp_enabled": true}, {"id" = user.id})
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Disables two-factor login, `code` is a TOTP or backup code.
+    #[conerror]
+    pub async fn disable_two_factor(&self, user: &User, code: &str) -> conerror::Result<()> {
+        let (enabled,): (bool,) = select!("user", ["totp_enabled"], { "id" = user.id })
+            .fetch_one(&self.db)
+            .await?;
+        if !enabled {
+            return Err(msg("未启用两步验证"));
+        }
+        if !self.verify_second_factor(user, code.trim(), true).await? {
+            return Err(msg("验证码错误"));
+        }
+        let mut tx = self.db.begin().await?;
+        update!(
+            "user",
+            {"totp_enabled": false, "totp_secret": None::<Vec<u8>>, "totp_last": None::<i64>},
+            {"id" = user.id}
+        )
+        .execute(&mut *tx)
+        .await?;
+        // backup codes still stand in for security keys
+        if !self.webauthn.has_credential(user.id).await? {
+            delete!("user_backup_code", { "user_id" = user.id })
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Registers a security key, generating backup codes with the first one.
+    #[conerror]
+    pub async fn register_webauthn(
+        &self,
+        user: &User,
+        name: &str,
+        response: &RegistrationResponse,
+    ) -> conerror::Result<WebauthnRegistration> {
+        let id = self.webauthn.register(user, name, response).await?;
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
+        let exists: Option<(i64,)> = select!(
+            "user_backup_code",
+            ["user_id"],
+            { "user_id" = user.id },
+            "LIMIT 1"
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let backup_codes = match exists {
+            Some(_) => None,
+            None => Some(replace_backup_codes(&mut tx, user).await?),
+        };
+        tx.commit().await?;
+        Ok(WebauthnRegistration { id, backup_codes })
+    }
+
+    /// Removes a security key, which needs a fresh second factor besides the session so
+    /// a stolen session can't weaken the login.
+    #[conerror]
+    pub async fn remove_webauthn(
+        &self,
+        user: &User,
+        id: i64,
+        second_factor: SecondFactor<'_>,
+    ) -> conerror::Result<()> {
+        let verified = match second_factor {
+            SecondFactor::Code(code) => {
+                let (totp_enabled,): (bool,) =
+                    select!("user", ["totp_enabled"], { "id" = user.id })
+                        .fetch_one(&self.db)
+                        .await?;
+                self.verify_second_factor(user, code.trim(), totp_enabled)
+                    .await?
+            }
+            SecondFactor::Webauthn(response) => {
+                self.webauthn.verify_assertion(user.id, response).await?
+            }
+        };
+        if !verified {
+            return Err(msg("验证失败"));
+        }
+        self.webauthn.remove_credential(user, id).await?;
+        Ok(())
+    }
+
+    /// Whether logging in needs a code, so the command line knows to prompt for it.
+    #[conerror]
+    pub async fn two_factor_enabled(&self, username: &str) -> conerror::Result<bool> {
+        let row: Option<(bool,)> = select!("user", ["totp_enabled"], { "username" = username })
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row.is_some_and(|(v,)| v))
+    }
+
+    /// Accepts a TOTP code, or uses up a backup code.
+    #[conerror]
+    async fn verify_second_factor(
+        &self,
+        user: &User,
+        code: &str,
+        totp_enabled: bool,
+    ) -> conerror::Result<bool> {
+        if code.bytes().all(|b| b.is_ascii_digit()) {
+            if !totp_enabled {
+                return Ok(false);
+            }
+            return self.verify_totp(user, code).await;
+        }
+        let result = sqlx::query(
+            "UPDATE `user_backup_code` SET `used_at` = ? \
+            WHERE `user_id` = ? AND `hash` = ? AND `used_at` IS NULL",
+        )
+        .bind(timestamp())
+        .bind(user.id)
+        .bind(backup_code_hash(user, code)?)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Accepts a TOTP code unless a code of the same or a later time step was used before,
+    /// so an observed code can't be replayed.
+    #[conerror]
+    async fn verify_totp(&self, user: &User, code: &str) -> conerror::Result<bool> {
+        let (secret,): (Option<Vec<u8>>,) = select!("user", ["totp_secret"], { "id" = user.id })
+            .fetch_one(&self.db)
+            .await?;
+        let secret = match secret {
+            Some(v) => {
+                self.encryption
+                    .decrypt(&v, user.credential.password(), user.credential.salt())?
+            }
+            None => return Err(msg("未启用两步验证")),
+        };
+        let step = match Totp::new(secret).verify(code, timestamp()) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let result = sqlx::query(
+            "UPDATE `user` SET `totp_last` = ? \
+            WHERE `id` = ? AND (`totp_last` IS NULL OR `totp_last` < ?)",
+        )
+        .bind(step)
+        .bind(user.id)
+        .bind(step)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() == 1)
     }
 
     #[conerror]
-    pub async fn create_token(&self, user: &User) -> conerror::Result<String> {
+    pub async fn create_token(&self, user: &User, client: &ClientInfo) -> conerror::Result<String> {
+        let token = self.insert_token(user, client, timestamp()).await?;
+        Ok(token)
+    }
+
+    /// Replaces a token with a new one of the same session, so a client can keep
+    /// its session without the master password. The session still ends at its max age.
+    #[conerror]
+    pub async fn refresh_token(
+        &self,
+        token: &str,
+        client: &ClientInfo,
+    ) -> conerror::Result<String> {
+        let user = self.find_user(token).await?;
+        let id = token_id(token).ok_or_else(invalid_token)?;
+        let (created_at,): (i64,) = match sqlx::query_as(
+            "DELETE FROM `token` WHERE `id` = ? AND `user_id` = ? RETURNING `created_at`",
+        )
+        .bind(id)
+        .bind(user.id)
+        .fetch_optional(&self.db)
+        .await?
+        {
+            Some(v) => v,
+            // refreshed or revoked by a concurrent request
+            None => return Err(invalid_token()),
+        };
+        let token = self.insert_token(&user, client, created_at).await?;
+        Ok(token)
+    }
+
+    /// Stores a new token of a session that started at `created_at`.
+    #[conerror]
+    async fn insert_token(
+        &self,
+        user: &User,
+        client: &ClientInfo,
+        created_at: i64,
+    ) -> conerror::Result<String> {
         let token = Credential::generate();
         let credential =
             self.encryption
                 .encrypt(&to_vec(user.credential()), token.password(), token.salt())?;
-        let now = timestamp();
-        let id = insert!("token", {
-            "user_id": user.id,
-            "credential": &credential,
-            "last_active": now,
-            "created_at": now,
-        })
+        // a token must not outlive a rotation of the credential it holds
+        let result = sqlx::query(
+            "INSERT INTO `token`(`user_id`,`credential`,`ip`,`user_agent`,`last_active`,`created_at`) \
+            SELECT ?,?,?,?,?,? FROM `user` WHERE `id` = ? AND `credential_version` = ?",
+        )
+        .bind(user.id)
+        .bind(&credential)
+        .bind(client.ip.as_deref())
+        .bind(client.user_agent.as_deref())
+        .bind(timestamp())
+        .bind(created_at)
+        .bind(user.id)
+        .bind(user.credential_version)
         .execute(&self.db)
-        .await?
-        .last_insert_rowid();
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(invalid_token());
+        }
+        let id = result.last_insert_rowid();
 
         let mut token = token.0;
         token.extend_from_slice(&id.to_le_bytes());
@@ -124,12 +536,15 @@ impl UserManager {
         Ok(BASE64_URL_SAFE_NO_PAD.encode(token))
     }
 
+    /// Changes the password, which only re-encrypts the credential. When `current_token`
+    /// is given, every other session of the user is ended.
     #[conerror]
     pub async fn change_password(
         &self,
         user: &User,
         old_password: &str,
         new_password: &str,
+        current_token: Option<&str>,
     ) -> conerror::Result<()> {
         if new_password.is_empty() {
             return Err(msg("参数错误"));
@@ -141,9 +556,167 @@ impl UserManager {
         let credential =
             self.encryption
                 .encrypt(&to_vec(&user.credential), new_password.as_bytes(), &salt)?;
+        let mut tx = self.db.begin().await?;
+        lock_credential(&mut tx, user).await?;
         update!("user", {"salt": &salt,"credential": &credential}, {"id" = user.id})
-            .execute(&self.db)
+            .execute(&mut *tx)
+            .await?;
+        if let Some(token) = current_token {
+            sqlx::query("DELETE FROM `token` WHERE `user_id` = ? AND `id` IS NOT ?")
+                .bind(user.id)
+                .bind(token_id(token))
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Replaces the credential of the user with a new one as part of `tx`, the password
+    /// is unchanged. Returns the user with the new credential, the new recovery key and
+    /// new backup codes if two-factor login is enabled, which depend on the credential.
+    /// All tokens are deleted since they hold the old credential.
+    #[conerror]
+    pub async fn rekey(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        from: &User,
+        password: &str,
+    ) -> conerror::Result<(User, String, Option<Vec<String>>)> {
+        self.verify_password(from.id, password).await?;
+        // fences writes that were started with the old credential, see `lock_credential`
+        let result = sqlx::query(
+            "UPDATE `user` SET `credential_version` = `credential_version` + 1 \
+            WHERE `id` = ? AND `credential_version` = ?",
+        )
+        .bind(from.id)
+        .bind(from.credential_version)
+        .execute(&mut **tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(invalid_token());
+        }
+        let to = User {
+            id: from.id,
+            credential_version: from.credential_version + 1,
+            credential: Credential::generate(),
+        };
+        let rewrap = |data: Option<Vec<u8>>| match data {
+            Some(v) => {
+                let data = self.encryption.decrypt(
+                    &v,
+                    from.credential.password(),
+                    from.credential.salt(),
+                )?;
+                self.encryption
+                    .encrypt(&data, to.credential.password(), to.credential.salt())
+                    .map(Some)
+            }
+            None => Ok(None),
+        };
+        let (private_key, totp_secret): (Option<Vec<u8>>, Option<Vec<u8>>) =
+            select!("user", ["private_key", "totp_secret"], { "id" = from.id })
+                .fetch_one(&mut **tx)
+                .await?;
+
+        let mut salt = vec![0u8; 32];
+        fill_bytes(&mut salt);
+        let credential =
+            self.encryption
+                .encrypt(&to_vec(&to.credential), password.as_bytes(), &salt)?;
+        let recovery_key = generate_recovery_key();
+        let (recovery_salt, recovery_credential) =
+            self.wrap_recovery(&to.credential, &recovery_key)?;
+        update!("user", {
+            "salt": &salt,
+            "credential": &credential,
+            "private_key": rewrap(private_key)?,
+            "totp_secret": rewrap(totp_secret)?,
+            "recovery_salt": &recovery_salt,
+            "recovery_credential": &recovery_credential,
+        }, {"id" = from.id})
+        .execute(&mut **tx)
+        .await?;
+
+        let backup_codes: Option<(i64,)> = select!(
+            "user_backup_code",
+            ["user_id"],
+            { "user_id" = from.id },
+            "LIMIT 1"
+        )
+        .fetch_optional(&mut **tx)
+        .await?;
+        let backup_codes = match backup_codes {
+            Some(_) => Some(replace_backup_codes(tx, &to).await?),
+            None => None,
+        };
+        delete!("token", { "user_id" = from.id })
+            .execute(&mut **tx)
             .await?;
+        Ok((to, recovery_key, backup_codes))
+    }
+
+    #[conerror]
+    pub async fn settings(&self, user: &User) -> conerror::Result<UserSettings> {
+        let settings = select!(
+            "user",
+            ["history_retention", "token_idle_timeout", "token_max_age"],
+            { "id" = user.id }
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(settings)
+    }
+
+    #[conerror]
+    pub async fn update_settings(
+        &self,
+        user: &User,
+        update: UserSettingsUpdate,
+    ) -> conerror::Result<()> {
+        if let Some(retention) = update.history_retention {
+            if !(0..=MAX_HISTORY_RETENTION).contains(&retention) {
+                return Err(msg("参数错误"));
+            }
+            let mut tx = self.db.begin().await?;
+            update!("user", {"history_retention": retention}, {"id" = user.id})
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(
+                "DELETE FROM `password_history` WHERE `id` IN (SELECT `id` FROM \
+                (SELECT `id`, ROW_NUMBER() OVER (PARTITION BY `password_id` ORDER BY `id` DESC) AS `n` \
+                FROM `password_history` WHERE `user_id` = ?) WHERE `n` > ?)",
+            )
+            .bind(user.id)
+            .bind(retention)
+            .execute(&mut *tx)
+            .await?;
+            for (table, _) in VERSIONED_TABLES {
+                let sql = format!(
+                    "DELETE FROM `{}` WHERE `user_id` = ? AND `history_id` IS NOT NULL \
+                    AND `history_id` NOT IN (SELECT `id` FROM `password_history` WHERE `user_id` = ?)",
+                    table
+                );
+                sqlx::query(&sql)
+                    .bind(user.id)
+                    .bind(user.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        }
+        if let Some(timeout) = update.token_idle_timeout {
+            let timeout = token_setting(timeout, self.token_lifetime.idle_timeout)?;
+            update!("user", {"token_idle_timeout": timeout}, {"id" = user.id})
+                .execute(&self.db)
+                .await?;
+        }
+        if let Some(max_age) = update.token_max_age {
+            let max_age = token_setting(max_age, self.token_lifetime.max_age)?;
+            update!("user", {"token_max_age": max_age}, {"id" = user.id})
+                .execute(&self.db)
+                .await?;
+        }
         Ok(())
     }
 
@@ -160,14 +733,138 @@ impl UserManager {
         }
     }
 
+    /// Sets a new password with the recovery key, for users who forgot their password.
+    /// Every session of the user is ended.
+    #[conerror]
+    pub async fn recover(
+        &self,
+        username: &str,
+        recovery_key: &str,
+        new_password: &str,
+    ) -> conerror::Result<()> {
+        if new_password.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        let attempt = self
+            .login_limiter
+            .begin(ClientInfo::current().ip.as_deref(), username)?;
+        let row: Option<RecoveryRow> = select!(
+            "user",
+            [
+                "id",
+                "credential_version",
+                "recovery_salt",
+                "recovery_credential"
+            ],
+            { "username" = username }
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        let (id, credential_version, salt, recovery_credential) = match row {
+            Some(RecoveryRow {
+                id,
+                credential_version,
+                recovery_salt: Some(salt),
+                recovery_credential: Some(credential),
+            }) => (id, credential_version, salt, credential),
+            _ => {
+                attempt.fail().await?;
+                return Err(msg("恢复密钥错误"));
+            }
+        };
+        let recovery_key = normalize_recovery_key(recovery_key);
+        let credential =
+            match self
+                .encryption
+                .decrypt(&recovery_credential, recovery_key.as_bytes(), &salt)
+            {
+                Ok(v) => Credential(v),
+                Err(_) => {
+                    attempt.fail().await?;
+                    return Err(msg("恢复密钥错误"));
+                }
+            };
+
+        let mut salt = vec![0u8; 32];
+        fill_bytes(&mut salt);
+        let credential =
+            self.encryption
+                .encrypt(&to_vec(&credential), new_password.as_bytes(), &salt)?;
+        let mut tx = self.db.begin().await?;
+        // a rotation meanwhile replaced the recovery key as well
+        let result = update!(
+            "user",
+            {"salt": &salt, "credential": &credential},
+            {"id" = id, "credential_version" = credential_version}
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("恢复密钥错误"));
+        }
+        // whoever knew the old password may still be logged in
+        delete!("token", { "user_id" = id })
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        attempt.succeed();
+        Ok(())
+    }
+
+    /// Replaces the recovery key, the previous one stops working.
     #[conerror]
-    pub async fn create_user(&self, username: &str, password: &str) -> conerror::Result<User> {
+    pub async fn regenerate_recovery_key(
+        &self,
+        user: &User,
+        password: &str,
+    ) -> conerror::Result<String> {
+        self.verify_password(user.id, password).await?;
+        let recovery_key = generate_recovery_key();
+        let (salt, credential) = self.wrap_recovery(&user.credential, &recovery_key)?;
+        let result = update!(
+            "user",
+            {"recovery_salt": &salt, "recovery_credential": &credential},
+            {"id" = user.id, "credential_version" = user.credential_version}
+        )
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(invalid_token());
+        }
+        Ok(recovery_key)
+    }
+
+    /// Encrypts the credential with a recovery key, returns the salt and the ciphertext.
+    #[conerror]
+    fn wrap_recovery(
+        &self,
+        credential: &Credential,
+        recovery_key: &str,
+    ) -> conerror::Result<(Vec<u8>, Vec<u8>)> {
+        let mut salt = vec![0u8; 32];
+        fill_bytes(&mut salt);
+        let credential = self.encryption.encrypt(
+            &to_vec(credential),
+            normalize_recovery_key(recovery_key).as_bytes(),
+            &salt,
+        )?;
+        Ok((salt, credential))
+    }
+
+    /// Creates a user and returns it with its recovery key, which is only available now.
+    #[conerror]
+    pub async fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> conerror::Result<(User, String)> {
         if username.is_empty() || password.is_empty() {
             return Err(msg("参数错误"));
         }
 
         let mut user = User {
             id: 0,
+            credential_version: 0,
             credential: Credential::generate(),
         };
 
@@ -176,11 +873,19 @@ impl UserManager {
         let credential =
             self.encryption
                 .encrypt(&to_vec(&user.credential), password.as_bytes(), &salt)?;
+        let (public_key, private_key) = self.generate_keypair(&user.credential)?;
+        let recovery_key = generate_recovery_key();
+        let (recovery_salt, recovery_credential) =
+            self.wrap_recovery(&user.credential, &recovery_key)?;
 
         let result = insert_ignore!("user", {
             "username": username,
             "salt": &salt,
             "credential": &credential,
+            "public_key": &public_key,
+            "private_key": &private_key,
+            "recovery_salt": &recovery_salt,
+            "recovery_credential": &recovery_credential,
             "created_at": timestamp(),
         })
         .execute(&self.db)
@@ -189,7 +894,135 @@ impl UserManager {
             return Err(msg("用户已存在"));
         }
         user.id = result.last_insert_rowid();
-        Ok(user)
+        Ok((user, recovery_key))
+    }
+
+    #[conerror]
+    pub async fn username(&self, user: &User) -> conerror::Result<String> {
+        let (username,): (String,) = select!("user", ["username"], { "id" = user.id })
+            .fetch_one(&self.db)
+            .await?;
+        Ok(username)
+    }
+
+    /// Returns the id and public key of a user, `None` if the user doesn't exist
+    /// or hasn't logged in since keypairs were introduced.
+    #[conerror]
+    pub async fn public_key(&self, username: &str) -> conerror::Result<Option<(i64, PublicKey)>> {
+        let row: Option<(i64, Option<Vec<u8>>)> =
+            select!("user", ["id", "public_key"], { "username" = username })
+                .fetch_optional(&self.db)
+                .await?;
+        Ok(match row {
+            Some((id, Some(key))) => Some((id, PublicKey::from(to_array(&key)?))),
+            _ => None,
+        })
+    }
+
+    /// Returns the private key of the user, generating a keypair for users created before
+    /// keypairs were introduced.
+    #[conerror]
+    pub async fn private_key(&self, user: &User) -> conerror::Result<StaticSecret> {
+        loop {
+            let (private_key, credential_version): (Option<Vec<u8>>, i64) =
+                select!("user", ["private_key", "credential_version"], {
+                    "id" = user.id
+                })
+                .fetch_one(&self.db)
+                .await?;
+            if let Some(private_key) = private_key {
+                let private_key = self.encryption.decrypt(
+                    &private_key,
+                    user.credential.password(),
+                    user.credential.salt(),
+                )?;
+                return Ok(StaticSecret::from(to_array(&private_key)?));
+            }
+            if credential_version != user.credential_version {
+                return Err(invalid_token());
+            }
+            let (public_key, private_key) = self.generate_keypair(&user.credential)?;
+            // another request may have generated a keypair meanwhile, use that one instead
+            update!(
+                "user",
+                {"public_key": &public_key, "private_key": &private_key},
+                {"id" = user.id, "private_key" is None::<Vec<u8>>, "credential_version" = user.credential_version}
+            )
+            .execute(&self.db)
+            .await?;
+        }
+    }
+
+    /// Generates an X25519 keypair, the private key is encrypted with `credential`.
+    #[conerror]
+    fn generate_keypair(&self, credential: &Credential) -> conerror::Result<(Vec<u8>, Vec<u8>)> {
+        let mut secret = [0u8; 32];
+        fill_bytes(&mut secret);
+        let secret = StaticSecret::from(secret);
+        let public_key = PublicKey::from(&secret);
+        let private_key =
+            self.encryption
+                .encrypt(secret.as_bytes(), credential.password(), credential.salt())?;
+        Ok((public_key.as_bytes().to_vec(), private_key))
+    }
+
+    /// Tokens of the user that haven't expired, `token` is marked as the current session.
+    #[conerror]
+    pub async fn list_session(&self, user: &User, token: &str) -> conerror::Result<Vec<Session>> {
+        let lifetime = match UserRow::find(&self.db, user.id).await? {
+            Some(u) => u.token_lifetime(self.token_lifetime),
+            None => return Ok(Vec::new()),
+        };
+        let now = timestamp();
+        let list = sqlx::query_as(
+            "SELECT `id`, `ip`, `user_agent`, `created_at`, `last_active`, `id` = ? AS `current` \
+            FROM `token` WHERE `user_id` = ? AND `last_active` > ? AND `created_at` > ? \
+            ORDER BY `last_active` DESC",
+        )
+        .bind(token_id(token))
+        .bind(user.id)
+        .bind(now - lifetime.idle_timeout)
+        .bind(now - lifetime.max_age)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(list)
+    }
+
+    #[conerror]
+    pub async fn revoke_session(&self, user: &User, id: i64) -> conerror::Result<()> {
+        delete!("token", { "id" = id, "user_id" = user.id })
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn logout(&self, token: &str) -> conerror::Result<()> {
+        let user = self.find_user(token).await?;
+        if let Some(id) = token_id(token) {
+            self.revoke_session(&user, id).await?;
+        }
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn delete_expired_tokens(&self) -> conerror::Result<u64> {
+        let now = timestamp();
+        let result = sqlx::query(
+            "DELETE FROM `token` WHERE `id` IN (SELECT `token`.`id` FROM `token` \
+            LEFT JOIN `user` ON `user`.`id` = `token`.`user_id` \
+            WHERE `token`.`last_active` + MIN(COALESCE(`user`.`token_idle_timeout`, ?), ?) <= ? \
+            OR `token`.`created_at` + MIN(COALESCE(`user`.`token_max_age`, ?), ?) <= ?)",
+        )
+        .bind(self.token_lifetime.idle_timeout)
+        .bind(self.token_lifetime.idle_timeout)
+        .bind(now)
+        .bind(self.token_lifetime.max_age)
+        .bind(self.token_lifetime.max_age)
+        .bind(now)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected())
     }
 
     #[conerror]
@@ -203,12 +1036,10 @@ impl UserManager {
     #[conerror]
     pub async fn find_user_optional(&self, token: &str) -> conerror::Result<Option<User>> {
         let token = BASE64_URL_SAFE_NO_PAD.decode(token.as_bytes())?;
-        if token.len() != 104 {
+        if token.len() != TOKEN_LENGTH {
             return Ok(None);
         }
-        let id = i64::from_le_bytes([
-            token[64], token[65], token[66], token[67], token[68], token[69], token[70], token[71],
-        ]);
+        let id = i64::from_le_bytes(token[64..72].try_into()?);
         let t = match TokenRow::find(&self.db, id).await? {
             Some(v) => v,
             None => return Ok(None),
@@ -221,42 +1052,101 @@ impl UserManager {
             return Ok(None);
         }
 
-        if t.last_active + TOKEN_IDLE_DURATION <= timestamp() {
-            return Ok(None);
-        }
-
         let user = match UserRow::find(&self.db, t.user_id).await? {
             Some(v) => v,
             None => return Ok(None),
         };
+        let lifetime = user.token_lifetime(self.token_lifetime);
+        let now = timestamp();
+        if t.last_active + lifetime.idle_timeout <= now || t.created_at + lifetime.max_age <= now {
+            return Ok(None);
+        }
         let credential = self
             .encryption
             .decrypt(&t.credential, &token[..32], &token[32..64])?;
 
-        update!("token", {"last_active": timestamp()}, {"id" = t.id})
+        // a rotation deletes the tokens, so once the token is still there the version
+        // read above is the one of its credential
+        let result = update!("token", {"last_active": timestamp()}, {"id" = t.id})
             .execute(&self.db)
             .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        audit::set_user(user.id);
         Ok(Some(User {
             id: user.id,
+            credential_version: user.credential_version,
             credential: Credential(credential),
         }))
     }
 }
 
+/// Validates a token limit a user sets, 0 resets it to the server's limit.
+#[conerror]
+fn token_setting(value: i64, server: i64) -> conerror::Result<Option<i64>> {
+    if value == 0 {
+        return Ok(None);
+    }
+    if !(MIN_TOKEN_LIFETIME..=server).contains(&value) {
+        return Err(msg("参数错误"));
+    }
+    Ok(Some(value))
+}
+
+/// The id of a token, which is stored after the 64 byte secret.
+fn token_id(token: &str) -> Option<i64> {
+    let token = BASE64_URL_SAFE_NO_PAD.decode(token.as_bytes()).ok()?;
+    if token.len() != TOKEN_LENGTH {
+        return None;
+    }
+    Some(i64::from_le_bytes(token[64..72].try_into().ok()?))
+}
+
+#[derive(FromRow, Serialize)]
+pub struct Session {
+    id: i64,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created_at: i64,
+    last_active: i64,
+    /// Whether this is the session making the request.
+    current: bool,
+}
+
 #[derive(FromRow)]
 struct UserRow {
     id: i64,
+    credential_version: i64,
     salt: Vec<u8>,
     credential: Vec<u8>,
-    suspend: i64,
+    public_key: Option<Vec<u8>>,
+    totp_enabled: bool,
+    token_idle_timeout: Option<i64>,
+    token_max_age: Option<i64>,
 }
 
 impl UserRow {
+    fn token_lifetime(&self, server: TokenLifetime) -> TokenLifetime {
+        server.with_settings(self.token_idle_timeout, self.token_max_age)
+    }
+
     #[conerror]
     async fn find(db: &SqlitePool, id: i64) -> conerror::Result<Option<UserRow>> {
-        let row = select!("user", ["id", "salt", "credential", "suspend"], {
-            "id" = id
-        })
+        let row = select!(
+            "user",
+            [
+                "id",
+                "credential_version",
+                "salt",
+                "credential",
+                "public_key",
+                "totp_enabled",
+                "token_idle_timeout",
+                "token_max_age"
+            ],
+            { "id" = id }
+        )
         .fetch_optional(db)
         .await?;
         Ok(row)
@@ -267,35 +1157,143 @@ impl UserRow {
         db: &SqlitePool,
         username: &str,
     ) -> conerror::Result<Option<UserRow>> {
-        let row = select!("user", ["id", "salt", "credential", "suspend"], {
-            "username" = username
-        })
+        let row = select!(
+            "user",
+            [
+                "id",
+                "credential_version",
+                "salt",
+                "credential",
+                "public_key",
+                "totp_enabled",
+                "token_idle_timeout",
+                "token_max_age"
+            ],
+            { "username" = username }
+        )
         .fetch_optional(db)
         .await?;
         Ok(row)
     }
 }
 
+#[derive(FromRow)]
+struct RecoveryRow {
+    id: i64,
+    credential_version: i64,
+    recovery_salt: Option<Vec<u8>>,
+    recovery_credential: Option<Vec<u8>>,
+}
+
 #[derive(FromRow)]
 struct TokenRow {
     id: i32,
     user_id: i64,
     credential: Vec<u8>,
     last_active: i64,
+    created_at: i64,
 }
 
 impl TokenRow {
     #[conerror]
     async fn find(db: &SqlitePool, id: i64) -> conerror::Result<Option<Self>> {
-        let row = select!("token", ["id", "user_id", "credential", "last_active"], {
-            "id" = id
-        })
+        let row = select!(
+            "token",
+            ["id", "user_id", "credential", "last_active", "created_at"],
+            { "id" = id }
+        )
         .fetch_optional(db)
         .await?;
         Ok(row)
     }
 }
 
+/// A random key like `ABCD-EFGH-...` that is easy to write down.
+fn generate_recovery_key() -> String {
+    random_code(RECOVERY_KEY_LENGTH, 4)
+}
+
+/// Random characters from `RECOVERY_KEY_ALPHABET`, separated by '-' every `group` characters.
+fn random_code(length: usize, group: usize) -> String {
+    let mut bytes = vec![0u8; length];
+    fill_bytes(&mut bytes);
+    let mut code = String::with_capacity(length + length / group);
+    for (i, b) in bytes.iter().enumerate() {
+        if i > 0 && i % group == 0 {
+            code.push('-');
+        }
+        code.push(RECOVERY_KEY_ALPHABET[(*b & 31) as usize] as char);
+    }
+    code
+}
+
+/// Takes the write lock for `tx` and fails if the credential of `user` was rotated since
+/// the user was loaded, so nothing encrypted with an old credential is committed.
+/// Every write encrypted with the credential calls this first.
+#[conerror]
+pub async fn lock_credential(
+    tx: &mut Transaction<'_, Sqlite>,
+    user: &User,
+) -> conerror::Result<()> {
+    let result = sqlx::query(
+        "UPDATE `user` SET `credential_version` = `credential_version` \
+        WHERE `id` = ? AND `credential_version` = ?",
+    )
+    .bind(user.id)
+    .bind(user.credential_version)
+    .execute(&mut **tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(invalid_token());
+    }
+    Ok(())
+}
+
+/// Generates new backup codes in place of the user's current ones.
+#[conerror]
+async fn replace_backup_codes(
+    tx: &mut Transaction<'_, Sqlite>,
+    user: &User,
+) -> conerror::Result<Vec<String>> {
+    let backup_codes: Vec<String> = (0..BACKUP_CODE_COUNT)
+        .map(|_| random_code(BACKUP_CODE_LENGTH, 5))
+        .collect();
+    delete!("user_backup_code", { "user_id" = user.id })
+        .execute(&mut **tx)
+        .await?;
+    for code in &backup_codes {
+        insert!("user_backup_code", {
+            "user_id": user.id,
+            "hash": &backup_code_hash(user, code)?,
+        })
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(backup_codes)
+}
+
+/// Backup codes are stored as an HMAC keyed with the credential,
+/// so they can't be guessed offline from the database alone.
+#[conerror]
+fn backup_code_hash(user: &User, code: &str) -> conerror::Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(user.credential.password())?;
+    mac.update(normalize_recovery_key(code).as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Ignores case, dashes and spaces so the key can be typed as printed or not.
+fn normalize_recovery_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[conerror]
+fn to_array(key: &[u8]) -> conerror::Result<[u8; 32]> {
+    Ok(key.try_into()?)
+}
+
 fn to_vec(credential: &Credential) -> Vec<u8> {
     let mut v = Vec::with_capacity(credential.password().len() + credential.salt().len());
     v.extend_from_slice(credential.password());
@@ -0,0 +1,378 @@
+use std::str::FromStr;
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use conerror::conerror;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::encryption::EncryptionManager;
+use crate::entry::EntryPayload;
+use crate::error::msg;
+use crate::field::CustomField;
+use crate::kdbx::write_kdbx;
+use crate::password::{Password, PasswordCreate, PasswordManager};
+use crate::user::User;
+use crate::util::{fill_bytes, timestamp};
+
+const EXPORT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct ExportFile {
+    version: u32,
+    salt: String,
+    data: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportBundle {
+    exported_at: i64,
+    entries: Vec<ExportEntry>,
+}
+
+/// Portable copy of an entry, also used to send entries between users.
+#[derive(Serialize, Deserialize)]
+pub struct ExportEntry {
+    name: String,
+    username: String,
+    password: String,
+    attachment: Option<String>,
+    totp: Option<String>,
+    #[serde(default)]
+    fields: Vec<CustomField>,
+    #[serde(default)]
+    payload: Option<EntryPayload>,
+    #[serde(default)]
+    urls: Vec<String>,
+    updated_at: i64,
+    created_at: i64,
+}
+
+impl ExportEntry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn to_create(&self) -> PasswordCreate<'_> {
+        PasswordCreate {
+            name: &self.name,
+            username: &self.username,
+            password: &self.password,
+            attachment: self.attachment.as_deref(),
+            totp: self.totp.as_deref(),
+            fields: &self.fields,
+            payload: self.payload.as_ref(),
+            urls: &self.urls,
+        }
+    }
+}
+
+impl From<Password> for ExportEntry {
+    fn from(v: Password) -> Self {
+        Self {
+            name: v.name,
+            username: v.username,
+            password: v.password,
+            attachment: v.attachment,
+            totp: v.totp,
+            fields: v.fields,
+            payload: v.payload,
+            urls: v.urls,
+            updated_at: v.updated_at,
+            created_at: v.created_at,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    Skip,
+    Overwrite,
+    KeepBoth,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "keep_both" => Ok(Self::KeepBoth),
+            _ => Err(format!("unknown conflict policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+pub struct ImportResult {
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+}
+
+#[derive(Clone)]
+pub struct VaultManager {
+    db: SqlitePool,
+    password_manager: PasswordManager,
+    encryption: EncryptionManager,
+}
+
+impl VaultManager {
+    pub fn new(
+        db: SqlitePool,
+        password_manager: PasswordManager,
+        encryption: EncryptionManager,
+    ) -> Self {
+        Self {
+            db,
+            password_manager,
+            encryption,
+        }
+    }
+
+    #[conerror]
+    pub async fn export(&self, user: &User, passphrase: &str) -> conerror::Result<String> {
+        if passphrase.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        let entries = self.password_manager.all_password(user).await?;
+        let bundle = ExportBundle {
+            exported_at: timestamp(),
+            entries: entries.into_iter().map(ExportEntry::from).collect(),
+        };
+        let data = encrypt_bundle(&self.encryption, &bundle, passphrase)?;
+        Ok(data)
+    }
+
+    #[conerror]
+    pub async fn export_kdbx(&self, user: &User, password: &str) -> conerror::Result<Vec<u8>> {
+        if password.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        let entries = self.password_manager.all_password(user).await?;
+        // the key derivation takes 64 MiB and most of a second, keep it off the runtime
+        let password = password.to_string();
+        let data = tokio::task::spawn_blocking(move || write_kdbx(&entries, &password)).await??;
+        Ok(data)
+    }
+
+    #[conerror]
+    pub async fn import(
+        &self,
+        user: &User,
+        passphrase: &str,
+        data: &str,
+        conflict: ConflictPolicy,
+    ) -> conerror::Result<ImportResult> {
+        let bundle = decrypt_bundle(&self.encryption, data, passphrase)?;
+
+        // all or nothing, a failure must not leave a partly imported vault
+        let mut tx = self.db.begin().await?;
+        let mut result = ImportResult::default();
+        for entry in &bundle.entries {
+            let create = entry.to_create();
+            let existing = match conflict {
+                ConflictPolicy::KeepBoth => None,
+                _ => {
+                    self.password_manager
+                        .find_password_id(&mut tx, user, &entry.name)
+                        .await?
+                }
+            };
+            match (existing, conflict) {
+                (Some(_), ConflictPolicy::Skip) => result.skipped += 1,
+                (Some(id), _) => {
+                    self.password_manager
+                        .write_update(&mut tx, user, id, create.into())
+                        .await?;
+                    result.updated += 1;
+                }
+                (None, _) => {
+                    self.password_manager
+                        .insert_password(&mut tx, user, create)
+                        .await?;
+                    result.created += 1;
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(result)
+    }
+}
+
+#[conerror]
+fn encrypt_bundle(
+    encryption: &EncryptionManager,
+    bundle: &ExportBundle,
+    passphrase: &str,
+) -> conerror::Result<String> {
+    let mut salt = vec![0u8; 32];
+    fill_bytes(&mut salt);
+    let data = encryption.encrypt(&serde_json::to_vec(bundle)?, passphrase.as_bytes(), &salt)?;
+    let file = ExportFile {
+        version: EXPORT_VERSION,
+        salt: BASE64_STANDARD.encode(salt),
+        data: BASE64_STANDARD.encode(data),
+    };
+    Ok(serde_json::to_string(&file)?)
+}
+
+#[conerror]
+fn decrypt_bundle(
+    encryption: &EncryptionManager,
+    data: &str,
+    passphrase: &str,
+) -> conerror::Result<ExportBundle> {
+    let file: ExportFile = serde_json::from_str(data).map_err(|_| msg("文件格式错误"))?;
+    if file.version > EXPORT_VERSION {
+        return Err(msg("不支持的文件版本"));
+    }
+    let data = encryption
+        .decrypt(
+            &BASE64_STANDARD.decode(file.data)?,
+            passphrase.as_bytes(),
+            &BASE64_STANDARD.decode(file.salt)?,
+        )
+        .map_err(|_| msg("密码错误"))?;
+    let bundle = serde_json::from_slice(&data)?;
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::setup_db;
+    use crate::encryption::{Aes256GcmEncryptor, EncryptionManager};
+    use crate::password::{PasswordCreate, PasswordManager};
+    use crate::user::User;
+    use crate::util::fill_bytes;
+    use crate::vault::{
+        decrypt_bundle, encrypt_bundle, ConflictPolicy, ExportBundle, ExportEntry, VaultManager,
+    };
+
+    fn entry(name: &str, password: &str) -> ExportEntry {
+        ExportEntry {
+            name: name.to_string(),
+            username: "alice".to_string(),
+            password: password.to_string(),
+            attachment: None,
+            totp: None,
+            fields: Vec::new(),
+            payload: None,
+            urls: vec!["https://example.com".to_string()],
+            updated_at: 1,
+            created_at: 1,
+        }
+    }
+
+    fn create<'a>(name: &'a str, password: &'a str) -> PasswordCreate<'a> {
+        PasswordCreate {
+            name,
+            username: "alice",
+            password,
+            attachment: None,
+            totp: None,
+            fields: &[],
+            payload: None,
+            urls: &[],
+        }
+    }
+
+    /// Names and passwords of the user's entries, sorted.
+    async fn passwords(password_manager: &PasswordManager, user: &User) -> Vec<(String, String)> {
+        let mut list: Vec<_> = password_manager
+            .all_password(user)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.name, v.password))
+            .collect();
+        list.sort();
+        list
+    }
+
+    #[test]
+    fn test_bundle() {
+        let encryption = EncryptionManager::new(vec![Box::new(Aes256GcmEncryptor)]);
+        let bundle = ExportBundle {
+            exported_at: 1,
+            entries: vec![entry("a", "foo"), entry("b", "bar")],
+        };
+        let data = encrypt_bundle(&encryption, &bundle, "passphrase").unwrap();
+        assert!(!data.contains("foo"));
+        let bundle = decrypt_bundle(&encryption, &data, "passphrase").unwrap();
+        assert_eq!(2, bundle.entries.len());
+        assert_eq!("a", bundle.entries[0].name);
+        assert_eq!("foo", bundle.entries[0].password);
+        assert_eq!(vec!["https://example.com"], bundle.entries[1].urls);
+
+        let err = decrypt_bundle(&encryption, &data, "wrong").err().unwrap();
+        assert_eq!("密码错误", err.to_string());
+        assert!(decrypt_bundle(&encryption, "foo", "passphrase").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        let dir = std::env::temp_dir().join(format!("passman-vault-{}", std::process::id()));
+        let db = setup_db(dir.to_str().unwrap()).await.unwrap();
+        let id = sqlx::query(
+            "INSERT INTO `user`(`username`,`salt`,`credential`,`created_at`) VALUES ('alice',x'',x'',0)",
+        )
+        .execute(&db)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        let mut credential = vec![0u8; 64];
+        fill_bytes(&mut credential);
+        let user = User::with_credential(id, 0, credential);
+        let encryption = EncryptionManager::new(vec![Box::new(Aes256GcmEncryptor)]);
+        let password_manager = PasswordManager::new(db.clone(), encryption.clone());
+        let vault_manager = VaultManager::new(db.clone(), password_manager.clone(), encryption);
+
+        for (name, password) in [("a", "foo"), ("b", "bar")] {
+            password_manager
+                .create_password(&user, create(name, password))
+                .await
+                .unwrap();
+        }
+        let data = vault_manager.export(&user, "passphrase").await.unwrap();
+        assert!(vault_manager
+            .import(&user, "wrong", &data, ConflictPolicy::Skip)
+            .await
+            .is_err());
+        let (a,): (i64,) = sqlx::query_as("SELECT `id` FROM `password` WHERE `name` = 'a'")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        password_manager
+            .update_password(&user, a, create("a", "changed").into())
+            .await
+            .unwrap();
+        let result = vault_manager
+            .import(&user, "passphrase", &data, ConflictPolicy::Skip)
+            .await
+            .unwrap();
+        assert_eq!((0, 0, 2), (result.created, result.updated, result.skipped));
+        assert_eq!("changed", passwords(&password_manager, &user).await[0].1);
+
+        let result = vault_manager
+            .import(&user, "passphrase", &data, ConflictPolicy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!((0, 2, 0), (result.created, result.updated, result.skipped));
+        assert_eq!("foo", passwords(&password_manager, &user).await[0].1);
+
+        let result = vault_manager
+            .import(&user, "passphrase", &data, ConflictPolicy::KeepBoth)
+            .await
+            .unwrap();
+        assert_eq!((2, 0, 0), (result.created, result.updated, result.skipped));
+        let list = passwords(&password_manager, &user).await;
+        assert_eq!(4, list.len());
+        assert_eq!(("b".to_string(), "bar".to_string()), list[3]);
+
+        db.close().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
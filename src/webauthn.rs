@@ -0,0 +1,595 @@
+use std::collections::BTreeMap;
+
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::Value;
+use conerror::conerror;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+use url::Url;
+
+use crate::error::msg;
+use crate::user::User;
+use crate::util::{fill_bytes, timestamp};
+
+/// How long a challenge can be answered, in seconds.
+const CHALLENGE_LIFETIME: i64 = 300;
+
+/// COSE algorithm identifier of ECDSA with P-256 and SHA-256.
+const ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// The site credentials are scoped to, derived from the public URL of the server.
+#[derive(Clone)]
+pub struct RelyingParty {
+    id: String,
+    origin: String,
+}
+
+impl RelyingParty {
+    pub fn from_url(url: &str) -> Option<Self> {
+        let url = Url::parse(url).ok()?;
+        Some(Self {
+            id: url.host_str()?.to_string(),
+            origin: url.origin().ascii_serialization(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct RegistrationOptions {
+    challenge: String,
+    rp_id: String,
+    /// Base64 encoded user handle.
+    user_id: String,
+    username: String,
+    algorithms: Vec<i64>,
+    /// Credentials the user already registered, so the same authenticator isn't added twice.
+    exclude_credentials: Vec<String>,
+    timeout: i64,
+}
+
+#[derive(Serialize)]
+pub struct AssertionOptions {
+    challenge: String,
+    rp_id: String,
+    allow_credentials: Vec<String>,
+    timeout: i64,
+}
+
+/// Response of `navigator.credentials.create`, fields are base64url encoded.
+#[derive(Deserialize)]
+pub struct RegistrationResponse {
+    client_data_json: String,
+    attestation_object: String,
+}
+
+/// Response of `navigator.credentials.get`, fields are base64url encoded.
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    credential_id: String,
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct WebauthnCredential {
+    id: i64,
+    name: String,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// A credential created by an authenticator, the public key is SEC1 encoded.
+struct AttestedCredential {
+    credential_id: Vec<u8>,
+    public_key: Vec<u8>,
+    sign_count: i64,
+}
+
+#[derive(Copy, Clone)]
+enum Ceremony {
+    Registration,
+    Assertion,
+}
+
+impl Ceremony {
+    fn as_str(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "webauthn.create",
+            Ceremony::Assertion => "webauthn.get",
+        }
+    }
+}
+
+/// Security keys and platform authenticators used as a second factor for login.
+///
+/// Only ES256 credentials are accepted, which every authenticator supports, and
+/// attestation statements are not checked since any authenticator may be registered.
+#[derive(Clone)]
+pub struct WebauthnManager {
+    db: SqlitePool,
+    relying_party: Option<RelyingParty>,
+}
+
+impl WebauthnManager {
+    pub fn new(db: SqlitePool, relying_party: Option<RelyingParty>) -> Self {
+        Self { db, relying_party }
+    }
+
+    #[conerror]
+    pub async fn registration_options(&self, user: &User) -> conerror::Result<RegistrationOptions> {
+        let relying_party = self.relying_party()?;
+        let (username,): (String,) = select!("user", ["username"], { "id" = user.id() })
+            .fetch_one(&self.db)
+            .await?;
+        let challenge = self
+            .create_challenge(user.id(), Ceremony::Registration)
+            .await?;
+        Ok(RegistrationOptions {
+            challenge,
+            rp_id: relying_party.id.clone(),
+            user_id: BASE64_URL_SAFE_NO_PAD.encode(user.id().to_le_bytes()),
+            username,
+            algorithms: vec![ES256],
+            exclude_credentials: self.credential_ids(user.id()).await?,
+            timeout: CHALLENGE_LIFETIME * 1000,
+        })
+    }
+
+    #[conerror]
+    pub async fn register(
+        &self,
+        user: &User,
+        name: &str,
+        response: &RegistrationResponse,
+    ) -> conerror::Result<i64> {
+        let relying_party = self.relying_party()?;
+        if name.is_empty() {
+            return Err(msg("参数错误"));
+        }
+        let client_data = decode(&response.client_data_json)?;
+        let challenge = verify_client_data(relying_party, &client_data, Ceremony::Registration)?;
+        self.use_challenge(user.id(), &challenge, Ceremony::Registration)
+            .await?;
+        let credential = parse_attestation(relying_party, &decode(&response.attestation_object)?)?;
+        let result = insert_ignore!("webauthn_credential", {
+            "user_id": user.id(),
+            "credential_id": &credential.credential_id,
+            "public_key": &credential.public_key,
+            "sign_count": credential.sign_count,
+            "name": name,
+            "created_at": timestamp(),
+        })
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("安全密钥已注册"));
+        }
+        Ok(result.last_insert_rowid())
+    }
+
+    #[conerror]
+    pub async fn list_credential(&self, user: &User) -> conerror::Result<Vec<WebauthnCredential>> {
+        let list = select!(
+            "webauthn_credential",
+            ["id", "name", "created_at", "last_used_at"],
+            { "user_id" = user.id() }
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(list)
+    }
+
+    #[conerror]
+    pub async fn remove_credential(&self, user: &User, id: i64) -> conerror::Result<()> {
+        delete!("webauthn_credential", { "id" = id, "user_id" = user.id() })
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    #[conerror]
+    pub async fn has_credential(&self, user_id: i64) -> conerror::Result<bool> {
+        let row: Option<(i64,)> = select!(
+            "webauthn_credential",
+            ["id"],
+            { "user_id" = user_id },
+            "LIMIT 1"
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(row.is_some())
+    }
+
+    /// Options for signing in with a registered credential, `user_id` must be
+    /// checked with the password first.
+    #[conerror]
+    pub async fn assertion_options(&self, user_id: i64) -> conerror::Result<AssertionOptions> {
+        let relying_party = self.relying_party()?;
+        let allow_credentials = self.credential_ids(user_id).await?;
+        if allow_credentials.is_empty() {
+            return Err(msg("未注册安全密钥"));
+        }
+        let challenge = self.create_challenge(user_id, Ceremony::Assertion).await?;
+        Ok(AssertionOptions {
+            challenge,
+            rp_id: relying_party.id.clone(),
+            allow_credentials,
+            timeout: CHALLENGE_LIFETIME * 1000,
+        })
+    }
+
+    /// Checks an assertion made with one of the user's credentials
+    /// over a challenge from `assertion_options`.
+    #[conerror]
+    pub async fn verify_assertion(
+        &self,
+        user_id: i64,
+        response: &AssertionResponse,
+    ) -> conerror::Result<bool> {
+        let relying_party = self.relying_party()?;
+        let credential_id = decode(&response.credential_id)?;
+        let row: Option<(i64, Vec<u8>, i64)> = select!(
+            "webauthn_credential",
+            ["id", "public_key", "sign_count"],
+            {"user_id" = user_id, "credential_id" = &credential_id}
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        let (id, public_key, sign_count) = match row {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let client_data = decode(&response.client_data_json)?;
+        let challenge = verify_client_data(relying_party, &client_data, Ceremony::Assertion)?;
+        self.use_challenge(user_id, &challenge, Ceremony::Assertion)
+            .await?;
+        let new_sign_count = match verify_assertion(
+            relying_party,
+            &public_key,
+            &decode(&response.authenticator_data)?,
+            &client_data,
+            &decode(&response.signature)?,
+        ) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        // a counter that doesn't grow means the credential may have been cloned,
+        // authenticators that don't count always report 0
+        if (new_sign_count != 0 || sign_count != 0) && new_sign_count <= sign_count {
+            return Ok(false);
+        }
+        update!(
+            "webauthn_credential",
+            {"sign_count": new_sign_count, "last_used_at": timestamp()},
+            {"id" = id}
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(true)
+    }
+
+    #[conerror]
+    fn relying_party(&self) -> conerror::Result<&RelyingParty> {
+        match &self.relying_party {
+            Some(v) => Ok(v),
+            None => Err(msg("未配置公开地址")),
+        }
+    }
+
+    #[conerror]
+    async fn credential_ids(&self, user_id: i64) -> conerror::Result<Vec<String>> {
+        let rows: Vec<(Vec<u8>,)> = select!("webauthn_credential", ["credential_id"], {
+            "user_id" = user_id
+        })
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(v,)| BASE64_URL_SAFE_NO_PAD.encode(v))
+            .collect())
+    }
+
+    #[conerror]
+    async fn create_challenge(&self, user_id: i64, ceremony: Ceremony) -> conerror::Result<String> {
+        let now = timestamp();
+        sqlx::query("DELETE FROM `webauthn_challenge` WHERE `user_id` = ? AND `expires_at` <= ?")
+            .bind(user_id)
+            .bind(now)
+            .execute(&self.db)
+            .await?;
+        let mut challenge = vec![0u8; 32];
+        fill_bytes(&mut challenge);
+        insert!("webauthn_challenge", {
+            "user_id": user_id,
+            "challenge": &challenge,
+            "type": ceremony.as_str(),
+            "expires_at": now + CHALLENGE_LIFETIME,
+        })
+        .execute(&self.db)
+        .await?;
+        Ok(BASE64_URL_SAFE_NO_PAD.encode(challenge))
+    }
+
+    /// Deletes the challenge so a response can't be replayed, fails if it wasn't issued
+    /// to the user or has expired.
+    #[conerror]
+    async fn use_challenge(
+        &self,
+        user_id: i64,
+        challenge: &[u8],
+        ceremony: Ceremony,
+    ) -> conerror::Result<()> {
+        let result = sqlx::query(
+            "DELETE FROM `webauthn_challenge` \
+            WHERE `user_id` = ? AND `challenge` = ? AND `type` = ? AND `expires_at` > ?",
+        )
+        .bind(user_id)
+        .bind(challenge)
+        .bind(ceremony.as_str())
+        .bind(timestamp())
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(msg("安全密钥验证失败"));
+        }
+        Ok(())
+    }
+}
+
+#[conerror]
+fn decode(s: &str) -> conerror::Result<Vec<u8>> {
+    Ok(BASE64_URL_SAFE_NO_PAD.decode(s.trim_end_matches('=').as_bytes())?)
+}
+
+/// Checks the type and origin of the client data, returns the challenge.
+#[conerror]
+fn verify_client_data(
+    relying_party: &RelyingParty,
+    data: &[u8],
+    ceremony: Ceremony,
+) -> conerror::Result<Vec<u8>> {
+    let data: ClientData = serde_json::from_slice(data)?;
+    if data.kind != ceremony.as_str() || data.origin != relying_party.origin {
+        return Err(msg("安全密钥验证失败"));
+    }
+    decode(&data.challenge)
+}
+
+/// Parses the authenticator data in an attestation object. The attestation statement
+/// is ignored, so `none` attestation is enough.
+#[conerror]
+fn parse_attestation(
+    relying_party: &RelyingParty,
+    data: &[u8],
+) -> conerror::Result<AttestedCredential> {
+    let object: Value = ciborium::de::from_reader(data)?;
+    let auth_data = object
+        .as_map()
+        .and_then(|map| {
+            map.iter()
+                .find(|(k, _)| k.as_text() == Some("authData"))
+                .and_then(|(_, v)| v.as_bytes())
+        })
+        .ok_or_else(invalid_credential)?;
+
+    let sign_count =
+        verify_authenticator_data(relying_party, auth_data).ok_or_else(invalid_credential)?;
+    if auth_data[32] & FLAG_ATTESTED_CREDENTIAL == 0 || auth_data.len() < 55 {
+        return Err(invalid_credential());
+    }
+    // aaguid(16) and the length of the credential id(2) follow the header
+    let len = u16::from_be_bytes([auth_data[53], auth_data[54]]) as usize;
+    let credential_id = auth_data
+        .get(55..55 + len)
+        .ok_or_else(invalid_credential)?
+        .to_vec();
+    let key: Value = ciborium::de::from_reader(&auth_data[55 + len..])?;
+    let public_key = parse_cose_key(&key).ok_or_else(invalid_credential)?;
+    Ok(AttestedCredential {
+        credential_id,
+        public_key,
+        sign_count,
+    })
+}
+
+/// Converts an ES256 COSE key to SEC1 encoding.
+fn parse_cose_key(key: &Value) -> Option<Vec<u8>> {
+    let map: BTreeMap<i128, &Value> = key
+        .as_map()?
+        .iter()
+        .filter_map(|(k, v)| Some((k.as_integer()?.into(), v)))
+        .collect();
+    let int = |label: i128| -> Option<i128> { map.get(&label)?.as_integer().map(Into::into) };
+    // kty EC2, alg ES256, crv P-256
+    if int(1)? != 2 || int(3)? != ES256 as i128 || int(-1)? != 1 {
+        return None;
+    }
+    let x = map.get(&-2)?.as_bytes()?;
+    let y = map.get(&-3)?.as_bytes()?;
+    if x.len() != 32 || y.len() != 32 {
+        return None;
+    }
+    let mut public_key = Vec::with_capacity(65);
+    public_key.push(4);
+    public_key.extend_from_slice(x);
+    public_key.extend_from_slice(y);
+    VerifyingKey::from_sec1_bytes(&public_key).ok()?;
+    Some(public_key)
+}
+
+/// Checks the relying party and user presence, returns the signature counter.
+fn verify_authenticator_data(relying_party: &RelyingParty, data: &[u8]) -> Option<i64> {
+    if data.len() < 37 || data[..32] != Sha256::digest(relying_party.id.as_bytes())[..] {
+        return None;
+    }
+    if data[32] & FLAG_USER_PRESENT == 0 {
+        return None;
+    }
+    Some(u32::from_be_bytes([data[33], data[34], data[35], data[36]]) as i64)
+}
+
+/// Verifies the signature over the authenticator data and the hash of the client data,
+/// returns the signature counter.
+fn verify_assertion(
+    relying_party: &RelyingParty,
+    public_key: &[u8],
+    authenticator_data: &[u8],
+    client_data: &[u8],
+    signature: &[u8],
+) -> Option<i64> {
+    let sign_count = verify_authenticator_data(relying_party, authenticator_data)?;
+    let key = VerifyingKey::from_sec1_bytes(public_key).ok()?;
+    let signature = Signature::from_der(signature).ok()?;
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&Sha256::digest(client_data));
+    key.verify(&message, &signature).ok()?;
+    Some(sign_count)
+}
+
+fn invalid_credential() -> conerror::Error {
+    msg("无效的安全密钥")
+}
+
+#[cfg(test)]
+mod tests {
+    use ciborium::Value;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::{Signature, SigningKey};
+    use sha2::{Digest, Sha256};
+
+    use crate::webauthn::{
+        parse_attestation, verify_assertion, verify_client_data, Ceremony, RelyingParty,
+    };
+
+    /// A software authenticator holding a single credential.
+    struct Authenticator {
+        key: SigningKey,
+        credential_id: Vec<u8>,
+        sign_count: u32,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            Self {
+                key: SigningKey::from_slice(&[7u8; 32]).unwrap(),
+                credential_id: b"software authenticator".to_vec(),
+                sign_count: 0,
+            }
+        }
+
+        fn authenticator_data(&self, rp_id: &str, flags: u8) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            data
+        }
+
+        fn make_credential(&self, rp_id: &str) -> Vec<u8> {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let key = Value::Map(vec![
+                (Value::from(1), Value::from(2)),
+                (Value::from(3), Value::from(-7)),
+                (Value::from(-1), Value::from(1)),
+                (Value::from(-2), Value::from(point.x().unwrap().to_vec())),
+                (Value::from(-3), Value::from(point.y().unwrap().to_vec())),
+            ]);
+            let mut auth_data = self.authenticator_data(rp_id, 0x41);
+            auth_data.extend_from_slice(&[0u8; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            ciborium::ser::into_writer(&key, &mut auth_data).unwrap();
+
+            let object = Value::Map(vec![
+                (Value::from("fmt"), Value::from("none")),
+                (Value::from("attStmt"), Value::Map(Vec::new())),
+                (Value::from("authData"), Value::from(auth_data)),
+            ]);
+            let mut data = Vec::new();
+            ciborium::ser::into_writer(&object, &mut data).unwrap();
+            data
+        }
+
+        fn get_assertion(&mut self, rp_id: &str, client_data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+            self.sign_count += 1;
+            let auth_data = self.authenticator_data(rp_id, 0x01);
+            let mut message = auth_data.clone();
+            message.extend_from_slice(&Sha256::digest(client_data));
+            let signature: Signature = self.key.sign(&message);
+            (auth_data, signature.to_der().as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn test_registration() {
+        let rp = RelyingParty::from_url("https://passman.example:8443/app").unwrap();
+        assert_eq!("passman.example", rp.id);
+        assert_eq!("https://passman.example:8443", rp.origin);
+
+        let authenticator = Authenticator::new();
+        let credential = parse_attestation(&rp, &authenticator.make_credential(&rp.id)).unwrap();
+        assert_eq!(authenticator.credential_id, credential.credential_id);
+        assert_eq!(65, credential.public_key.len());
+        assert!(parse_attestation(&rp, &authenticator.make_credential("evil.example")).is_err());
+
+        let client_data = br#"{"type":"webauthn.create","challenge":"AQID","origin":"https://passman.example:8443"}"#;
+        assert_eq!(
+            vec![1, 2, 3],
+            verify_client_data(&rp, client_data, Ceremony::Registration).unwrap()
+        );
+        assert!(verify_client_data(&rp, client_data, Ceremony::Assertion).is_err());
+        let client_data =
+            br#"{"type":"webauthn.create","challenge":"AQID","origin":"https://evil.example"}"#;
+        assert!(verify_client_data(&rp, client_data, Ceremony::Registration).is_err());
+    }
+
+    #[test]
+    fn test_assertion() {
+        let rp = RelyingParty::from_url("https://passman.example").unwrap();
+        let mut authenticator = Authenticator::new();
+        let public_key = parse_attestation(&rp, &authenticator.make_credential(&rp.id))
+            .unwrap()
+            .public_key;
+        let client_data =
+            br#"{"type":"webauthn.get","challenge":"AQID","origin":"https://passman.example"}"#;
+
+        let (auth_data, signature) = authenticator.get_assertion(&rp.id, client_data);
+        assert_eq!(
+            Some(1),
+            verify_assertion(&rp, &public_key, &auth_data, client_data, &signature)
+        );
+        let tampered = String::from_utf8_lossy(client_data).replace("AQID", "AQIE");
+        assert_eq!(
+            None,
+            verify_assertion(
+                &rp,
+                &public_key,
+                &auth_data,
+                tampered.as_bytes(),
+                &signature
+            )
+        );
+
+        let (auth_data, signature) = authenticator.get_assertion("evil.example", client_data);
+        assert_eq!(
+            None,
+            verify_assertion(&rp, &public_key, &auth_data, client_data, &signature)
+        );
+    }
+}